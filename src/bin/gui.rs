@@ -1,19 +1,45 @@
+use anyhow::ensure;
 use idek::prelude::*;
 use idek::winit;
 use idek_basics::{
-    draw_array2d::draw_grid,
     idek::{self, simple_ortho_cam_ctx},
-    GraphicsBuilder,
+    Array2D,
 };
+use nalgebra::{Vector2, Vector3};
+use rand::{rngs::StdRng, SeedableRng};
 use slime::{
-    record::{record_frame, RecordFile},
+    expr::Oscillation,
+    gamepad::{Gamepad, GamepadAction, GamepadBinding},
+    image::{gradient_field, load_grayscale, load_rgb, noise_image, resample, Blend},
+    init::InitParticles,
+    logging::{self, Verbosity},
+    lut::Lut,
+    palette::{age_color, group_color},
+    profile::Profiler,
+    record::{record_frame, Overflow, RecordBudget, RecordFile, RecordMeta},
     sim::*,
 };
-use std::path::PathBuf;
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
 use structopt::StructOpt;
+use tracing::{debug, info, info_span, warn};
 
 fn main() -> Result<()> {
-    let args = SlimeArgs::from_args();
+    let matches = SlimeArgs::clap().get_matches();
+    let mut args = SlimeArgs::from_clap(&matches);
+    if let Some(path) = &args.compare {
+        args.compare_cfg = Some(SlimeConfig::load(path)?.with_overrides(&args.cfg, &matches)?);
+    }
+    if let Some(path) = &args.config {
+        args.cfg = SlimeConfig::load(path)?.with_overrides(&args.cfg, &matches)?;
+    }
+    if let Some(path) = &args.save_config {
+        args.cfg.save(path)?;
+    }
+
     launch::<SlimeArgs, SlimeApp>(Settings::default().vr(args.vr).args(args))
 }
 
@@ -40,70 +66,545 @@ struct SlimeArgs {
     #[structopt(long, default_value = "1")]
     steps_per_frame: usize,
 
+    /// Adjust --steps-per-frame continually to hold this frame rate
+    #[structopt(long)]
+    target_fps: Option<f32>,
+
+    /// When --target-fps can't be held even at one step per frame, draw fewer of the particles
+    /// shown by --show-slime
+    #[structopt(long)]
+    adaptive_detail: bool,
+
+    /// Seed the random number generator, so that runs can be reproduced (see `slime
+    /// verify`). Unseeded runs are different every time
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Also store the medium in every Nth recorded frame
+    #[structopt(long)]
+    record_medium_every: Option<usize>,
+
+    /// Save the record so far every this many recorded frames, instead of only on exit
+    #[structopt(long)]
+    autosave_every: Option<usize>,
+
+    /// Limit the memory used by the record, in megabytes
+    #[structopt(long)]
+    record_max_mb: Option<f32>,
+
+    /// What to do when the record reaches --record-max-mb: stop recording, or thin out the
+    /// frames and record less often
+    #[structopt(long, default_value = "thin")]
+    record_overflow: Overflow,
+
+    /// Draw the particles on top of the medium
+    #[structopt(long)]
+    show_slime: bool,
+
+    /// Color the medium by how recently each cell was deposited into, fading from warm to cool
+    /// over about this much sim time. Recolors the live sim only, not replays
+    #[structopt(long)]
+    color_by_age: Option<f32>,
+
+    /// Color the particles shown by --show-slime by group instead of by state
+    #[structopt(long)]
+    color_by_group: bool,
+
+    /// Image whose gradient is used as a force field on the particles
+    #[structopt(long)]
+    force_image: Option<PathBuf>,
+
+    /// Strength of the image-derived force field
+    #[structopt(long, default_value = "10.0")]
+    force_strength: f32,
+
+    /// Circle around bright regions of the force image instead of moving towards them
+    #[structopt(long)]
+    force_swirl: bool,
+
+    /// Image mapped to a temperature field, which scales local move speed and deposit rate
+    #[structopt(long)]
+    temperature_image: Option<PathBuf>,
+
+    /// Temperature of black pixels in the temperature image
+    #[structopt(long, default_value = "0.25")]
+    temperature_min: f32,
+
+    /// Temperature of white pixels in the temperature image
+    #[structopt(long, default_value = "2.0")]
+    temperature_max: f32,
+
+    /// Image mapped to a cost field, where bright pixels are slow going (see --cost-speed and
+    /// --cost-turn)
+    #[structopt(long)]
+    cost_image: Option<PathBuf>,
+
+    /// Generate the cost field from noise with features about this many cells across. Ignored
+    /// if --cost-image is given
+    #[structopt(long)]
+    cost_noise: Option<f32>,
+
+    /// Cost of white pixels in the cost image, or of the noise peaks
+    #[structopt(long, default_value = "4.0")]
+    cost_max: f32,
+
+    /// Swing a parameter between two values over a period of sim time, e.g. "decay=0.02..0.2/500"
+    #[structopt(long)]
+    oscillate: Vec<Oscillation>,
+
+    /// Image whose bright pixels are walls
+    #[structopt(long)]
+    obstacle_image: Option<PathBuf>,
+
+    /// Image whose gray levels (0-255) mark the zones of the config with the same index
+    #[structopt(long)]
+    zone_image: Option<PathBuf>,
+
+    /// Image used as the initial (and maximum) amount of food in each cell
+    #[structopt(long)]
+    food_image: Option<PathBuf>,
+
+    /// Densities above this glow softly into their surroundings
+    #[structopt(long)]
+    bloom_threshold: Option<f32>,
+
+    /// Brightness of the glow added by --bloom-threshold
+    #[structopt(long, default_value = "1.0")]
+    bloom_intensity: f32,
+
+    /// Blur radius of the glow, in cells
+    #[structopt(long, default_value = "4")]
+    bloom_radius: usize,
+
+    /// Start with the accumulation buffer enabled. Toggle it at runtime with A
+    #[structopt(long)]
+    accumulate: bool,
+
+    /// Fraction of the accumulation buffer kept each frame
+    #[structopt(long, default_value = "0.95")]
+    accumulate_decay: f32,
+
+    /// How much of each frame is added to the accumulation buffer
+    #[structopt(long, default_value = "0.1")]
+    accumulate_gain: f32,
+
+    /// In VR, the medium is drawn as a heightfield this tall at full density
+    #[structopt(long, default_value = "0.25")]
+    height_scale: f32,
+
+    /// Compiled SPIR-V fragment shader for the grid, reloaded whenever the file changes. Density
+    /// arrives in every channel of the vertex color
+    #[structopt(long)]
+    fragment_shader: Option<PathBuf>,
+
+    /// Image to composite the medium over
+    #[structopt(long)]
+    background: Option<PathBuf>,
+
+    /// How the medium is composited over --background: add, screen, multiply or lighten
+    #[structopt(long, default_value = "screen")]
+    blend: Blend,
+
+    /// Color lookup table for the medium, as a .cube file or a gradient PNG
+    #[structopt(long)]
+    lut: Option<PathBuf>,
+
+    /// Write a Chrome trace of the time spent in each phase of the sim and renderer to this
+    /// file. Needs the profile feature
+    #[structopt(long)]
+    profile: Option<PathBuf>,
+
+    /// Decay and diffuse the medium on the GPU, for big grids. Needs the gpu feature
+    #[structopt(long)]
+    gpu: bool,
+
+    /// Read the sim parameters from this TOML file. Flags given on the command line override it
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Write the sim parameters in use to this TOML file, for use with --config
+    #[structopt(long)]
+    save_config: Option<PathBuf>,
+
+    /// Control the sim with a gamepad: Select resets it, Start pauses it, and South (A or
+    /// Cross) pauses and resumes the recording. Needs the gamepad feature
+    #[structopt(long)]
+    gamepad: bool,
+
+    /// Bind a stick or trigger to a parameter with --gamepad, e.g. "left_x=turn_speed:0.5..2"
+    #[structopt(long)]
+    gamepad_axis: Vec<GamepadBinding>,
+
+    /// Run a second sim with the parameters from this TOML file next to the first, from the
+    /// same seed and in lockstep. Flags given on the command line override it too. Only the
+    /// first sim is recorded
+    #[structopt(long)]
+    compare: Option<PathBuf>,
+
+    /// The parameters loaded from --compare
+    #[structopt(skip)]
+    compare_cfg: Option<SlimeConfig>,
+
+    #[structopt(flatten)]
+    init: InitParticles,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+
     #[structopt(flatten)]
     cfg: SlimeConfig,
 }
 
+/// Steps between progress messages in the log
+const LOG_INTERVAL: usize = 1000;
+
+/// Fraction of the particle trace kept from one replayed frame to the next, for records without
+/// a stored medium
+const REPLAY_FADE: f32 = 0.9;
+
+/// Brightness each particle adds to the replayed trace
+const REPLAY_DEPOSIT: f32 = 0.25;
+
+/// Frames replayed to rebuild the particle trace after jumping to a frame
+const REPLAY_TRACE: usize = 32;
+
+/// Bounds on the replay speed, in recorded frames per rendered frame
+const MIN_REPLAY_SPEED: f32 = 1. / 64.;
+const MAX_REPLAY_SPEED: f32 = 1024.;
+
+/// Height of the replay timeline in the view, and half the length of its playhead
+const TIMELINE_Y: f32 = -0.97;
+const TIMELINE_HEAD: f32 = 0.03;
+
+/// Weight of the newest frame in the timings behind --target-fps
+const TUNER_SMOOTHING: f32 = 0.1;
+
+/// Fraction of each frame at the target rate which the sim and the view update may use. The
+/// rest is left for the GPU and the event loop
+const TUNER_HEADROOM: f32 = 0.8;
+
+/// Upper bound on the steps per frame chosen for --target-fps
+const MAX_TUNED_STEPS: usize = 256;
+
+/// Frames to wait after changing the particle detail before judging the result
+const DETAIL_COOLDOWN: usize = 60;
+
 struct SlimeApp {
     verts: VertexBuffer,
     indices: IndexBuffer,
     args: SlimeArgs,
     sim: SlimeSim,
-    gb: GraphicsBuilder,
+    /// Grid mesh, of which only the colors change from frame to frame
+    grid_verts: Vec<Vertex>,
     record: Option<RecordFile>,
+    record_budget: Option<RecordBudget>,
+    /// One point per particle, only used with --show-slime
+    particle_verts: VertexBuffer,
+    point_shader: Shader,
+    /// Screen-space long exposure, present while accumulating
+    accumulation: Option<Array2D<f32>>,
+    /// User fragment shader for the grid, and the modification time it was loaded at
+    grid_shader: Option<(Shader, SystemTime)>,
+    /// --background, resampled to the grid size
+    background: Option<Array2D<[f32; 3]>>,
+    lut: Option<Lut>,
+    rng: StdRng,
+    profiler: Option<Profiler>,
+    /// Record dropped onto the window, shown instead of the sim until Escape is pressed
+    replay: Option<Replay>,
+    /// Present with --target-fps
+    tuner: Option<FrameTuner>,
+    /// Line segments of the replay timeline
+    timeline_verts: VertexBuffer,
+    line_shader: Shader,
+    /// Where the mouse is across the window, from 0 to 1
+    cursor_x: f32,
+    /// Present with --gamepad
+    gamepad: Option<Gamepad>,
+    /// The sim isn't stepped while paused
+    paused: bool,
+    /// Whether frames go into the record, which the gamepad can pause
+    recording: bool,
+    /// Present with --compare
+    comparison: Option<Comparison>,
+}
+
+/// The second sim of --compare, drawn to the right of the first
+struct Comparison {
+    sim: SlimeSim,
+    cfg: SlimeConfig,
+    rng: StdRng,
+    /// Shared by both sims, so that a reset starts them together again
+    seed: u64,
 }
 
 impl App<SlimeArgs> for SlimeApp {
     fn init(ctx: &mut Context, _: &mut Platform, args: SlimeArgs) -> Result<Self> {
-        let sim = SlimeSim::new(
-            args.width,
-            args.height,
-            args.n_particles,
-            &mut rand::thread_rng(),
+        let profiler = logging::init(&args.verbosity, args.profile.as_deref())?;
+
+        let mut rng = match args.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let comparison = match &args.compare_cfg {
+            Some(cfg) => {
+                let seed = match args.seed {
+                    Some(seed) => seed,
+                    None => {
+                        let seed = rand::random();
+                        info!("Comparing from seed {}", seed);
+                        seed
+                    }
+                };
+                rng = StdRng::seed_from_u64(seed);
+                let mut cmp_rng = StdRng::seed_from_u64(seed);
+                Some(Comparison {
+                    sim: new_sim(&args, &mut cmp_rng)?,
+                    cfg: cfg.clone(),
+                    rng: cmp_rng,
+                    seed,
+                })
+            }
+            None => None,
+        };
+
+        let sim = new_sim(&args, &mut rng)?;
+
+        let record = args.record.is_some().then(|| RecordFile {
+            meta: Some(RecordMeta {
+                config: args.cfg.clone(),
+                dt: args.dt,
+                thumbnail: None,
+            }),
+            ..RecordFile::new(args.width, args.height)
+        });
+
+        // Catch typos in parameter names before the window opens
+        for o in &args.oscillate {
+            args.cfg.clone().set_param(&o.param, 0.)?;
+        }
+        for b in &args.gamepad_axis {
+            args.cfg.clone().set_param(&b.param, 0.)?;
+        }
+        ensure!(
+            args.gamepad || args.gamepad_axis.is_empty(),
+            "--gamepad-axis needs --gamepad"
         );
+        let gamepad = if args.gamepad {
+            Some(Gamepad::new()?)
+        } else {
+            None
+        };
+
+        let background = match &args.background {
+            Some(path) => {
+                let image = resample(&load_rgb(path)?, args.width, args.height);
+                Some(match comparison {
+                    Some(_) => side_by_side(&image, &image),
+                    None => image,
+                })
+            }
+            None => None,
+        };
+
+        let lut = match &args.lut {
+            Some(path) => Some(Lut::load(path)?),
+            None => None,
+        };
+
+        let view_width = args.width * if comparison.is_some() { 2 } else { 1 };
+        let (mut grid_verts, grid_indices) = grid_mesh(view_width, args.height);
+        let palette = Palette {
+            lut: lut.as_ref(),
+            freshness: None,
+            background: background.as_ref().map(|bg| (bg, args.blend)),
+        };
+        let medium = match &comparison {
+            Some(cmp) => side_by_side(sim.frame().medium(), cmp.sim.frame().medium()),
+            None => sim.frame().medium().clone(),
+        };
+        color_grid(&mut grid_verts, &medium, &palette);
 
-        let record = args
-            .record
-            .is_some()
-            .then(|| RecordFile::new(args.width, args.height));
+        let verts = ctx.vertices(&grid_verts, true)?;
+        let indices = ctx.indices(&grid_indices, false)?;
 
-        let mut gb = GraphicsBuilder::new();
+        let particle_verts = ctx.vertices(
+            &particle_vertices(
+                &sim.frame().slime,
+                args.width,
+                args.height,
+                args.color_by_group,
+                1,
+            ),
+            true,
+        )?;
+        let point_shader = ctx.shader(
+            DEFAULT_VERTEX_SHADER,
+            DEFAULT_FRAGMENT_SHADER,
+            Primitive::Points,
+        )?;
 
-        draw_sim(&mut gb, &sim);
+        let blank: Vec<Vertex> = (0..6).map(|_| Vertex::new([0.; 3], [0.; 3])).collect();
+        let timeline_verts = ctx.vertices(&blank, true)?;
+        let line_shader = ctx.shader(
+            DEFAULT_VERTEX_SHADER,
+            DEFAULT_FRAGMENT_SHADER,
+            Primitive::Lines,
+        )?;
 
-        let verts = ctx.vertices(&gb.vertices, true)?;
-        let indices = ctx.indices(&gb.indices, false)?;
+        let accumulation = args
+            .accumulate
+            .then(|| Array2D::new(view_width, args.height));
+
+        let grid_shader = match &args.fragment_shader {
+            Some(path) => Some((load_grid_shader(ctx, path)?, modified(path)?)),
+            None => None,
+        };
 
         Ok(Self {
+            rng,
+            profiler,
+            replay: None,
+            timeline_verts,
+            line_shader,
+            cursor_x: 0.,
+            gamepad,
+            paused: false,
+            recording: true,
+            comparison,
+            tuner: args
+                .target_fps
+                .map(|fps| FrameTuner::new(fps, args.adaptive_detail)),
+            lut,
+            background,
+            grid_shader,
+            accumulation,
+            particle_verts,
+            point_shader,
             record,
+            record_budget: args
+                .record_max_mb
+                .map(|mb| RecordBudget::new(mb, args.record_overflow)),
             verts,
             indices,
-            gb,
+            grid_verts,
             sim,
             args,
         })
     }
 
     fn frame(&mut self, ctx: &mut Context, platform: &mut Platform) -> Result<Vec<DrawCmd>> {
-        // Timing
-        for _ in 0..self.args.steps_per_frame {
-            if let Some(record) = &mut self.record {
-                record_frame(record, &mut self.sim);
-            }
+        let _span = info_span!("frame").entered();
 
-            self.sim
-                .step(&self.args.cfg, self.args.dt, &mut rand::thread_rng());
+        // Timing
+        self.poll_gamepad()?;
+        let started = Instant::now();
+        match &mut self.replay {
+            Some(replay) => replay.advance(),
+            None if self.paused => (),
+            None => self.step()?,
         }
+        let stepping = started.elapsed();
 
         // Update view
-        self.gb.clear();
-        draw_sim(&mut self.gb, &self.sim);
-        ctx.update_vertices(self.verts, &self.gb.vertices)?;
+        let _span = info_span!("draw").entered();
+        let mut image = match &self.replay {
+            Some(replay) => replay.image.clone(),
+            None => self.live_view(|sim| sim.frame().medium().clone()),
+        };
+        if let Some(threshold) = self.args.bloom_threshold {
+            let (intensity, radius) = (self.args.bloom_intensity, self.args.bloom_radius);
+            add_bloom(&mut image, threshold, intensity, radius);
+        }
+
+        if let Some(accum) = &mut self.accumulation {
+            let (decay, gain) = (self.args.accumulate_decay, self.args.accumulate_gain);
+            for (a, v) in accum.data_mut().iter_mut().zip(image.data_mut()) {
+                *a = *a * decay + *v * gain;
+                *v = *a;
+            }
+        }
+
+        let freshness = match (&self.replay, self.args.color_by_age) {
+            (None, Some(fade)) => Some(self.live_view(|sim| sim.freshness(fade))),
+            _ => None,
+        };
+        let palette = Palette {
+            lut: self.lut.as_ref(),
+            freshness: freshness.as_ref(),
+            background: self.background.as_ref().map(|bg| (bg, self.args.blend)),
+        };
+        if self.args.vr {
+            heightfield_grid(
+                &mut self.grid_verts,
+                &image,
+                &palette,
+                self.args.height_scale,
+            );
+        } else {
+            color_grid(&mut self.grid_verts, &image, &palette);
+        }
+        ctx.update_vertices(self.verts, &self.grid_verts)?;
 
         // Camera and drawing
         simple_ortho_cam_ctx(ctx, platform);
 
-        Ok(vec![DrawCmd::new(self.verts).indices(self.indices)])
+        self.reload_grid_shader(ctx);
+
+        let mut grid_cmd = DrawCmd::new(self.verts).indices(self.indices);
+        if let Some((shader, _)) = self.grid_shader {
+            grid_cmd = grid_cmd.shader(shader);
+        }
+        let mut cmds = vec![grid_cmd];
+
+        if self.args.show_slime {
+            let (slime, width, height) = match (&self.replay, &self.comparison) {
+                (Some(replay), _) => (
+                    Cow::Borrowed(&replay.record.frames[replay.frame].slime),
+                    replay.record.width,
+                    replay.record.height,
+                ),
+                (None, Some(cmp)) => {
+                    // The second sim's particles, moved over to its half of the view
+                    let shift = Vector2::new(self.args.width as f32, 0.);
+                    let mut slime = self.sim.frame().slime.clone();
+                    slime.extend(cmp.sim.frame().slime.iter().map(|part| SlimeParticle {
+                        position: part.position + shift,
+                        ..*part
+                    }));
+                    (Cow::Owned(slime), self.args.width * 2, self.args.height)
+                }
+                (None, None) => (
+                    Cow::Borrowed(&self.sim.frame().slime),
+                    self.args.width,
+                    self.args.height,
+                ),
+            };
+            ctx.update_vertices(
+                self.particle_verts,
+                &particle_vertices(
+                    &slime,
+                    width,
+                    height,
+                    self.args.color_by_group,
+                    self.tuner.as_ref().map_or(1, |t| t.stride),
+                ),
+            )?;
+            cmds.push(DrawCmd::new(self.particle_verts).shader(self.point_shader));
+        }
+
+        if let Some(replay) = &self.replay {
+            ctx.update_vertices(self.timeline_verts, &replay.timeline())?;
+            cmds.push(DrawCmd::new(self.timeline_verts).shader(self.line_shader));
+        }
+
+        // Replayed frames cost next to nothing, so they would throw off the timings
+        if let (Some(tuner), None, false) = (&mut self.tuner, &self.replay, self.paused) {
+            self.args.steps_per_frame = tuner.update(started, stepping, self.args.steps_per_frame);
+        }
+
+        Ok(cmds)
     }
 
     /// Called once per event
@@ -119,6 +620,100 @@ impl App<SlimeArgs> for SlimeApp {
                 **control_flow = winit::event_loop::ControlFlow::Exit;
                 self.exit();
             }
+            (
+                Event::Winit(winit::event::Event::WindowEvent {
+                    event:
+                        winit::event::WindowEvent::KeyboardInput {
+                            input:
+                                winit::event::KeyboardInput {
+                                    state: winit::event::ElementState::Pressed,
+                                    virtual_keycode: Some(winit::event::VirtualKeyCode::A),
+                                    ..
+                                },
+                            ..
+                        },
+                    ..
+                }),
+                _,
+            ) => self.toggle_accumulation(),
+            (
+                Event::Winit(winit::event::Event::WindowEvent {
+                    event:
+                        winit::event::WindowEvent::KeyboardInput {
+                            input:
+                                winit::event::KeyboardInput {
+                                    state: winit::event::ElementState::Pressed,
+                                    virtual_keycode: Some(winit::event::VirtualKeyCode::Escape),
+                                    ..
+                                },
+                            ..
+                        },
+                    ..
+                }),
+                _,
+            ) => {
+                if self.replay.take().is_some() {
+                    info!("Back to the live sim");
+                }
+            }
+            (
+                Event::Winit(winit::event::Event::WindowEvent {
+                    event: winit::event::WindowEvent::DroppedFile(path),
+                    ..
+                }),
+                _,
+            ) => self.open_replay(&path),
+            (
+                Event::Winit(winit::event::Event::WindowEvent {
+                    event:
+                        winit::event::WindowEvent::KeyboardInput {
+                            input:
+                                winit::event::KeyboardInput {
+                                    state: winit::event::ElementState::Pressed,
+                                    virtual_keycode: Some(key),
+                                    ..
+                                },
+                            ..
+                        },
+                    ..
+                }),
+                _,
+            ) => {
+                if let Some(replay) = &mut self.replay {
+                    replay.key(key);
+                }
+            }
+            (
+                Event::Winit(winit::event::Event::WindowEvent {
+                    event: winit::event::WindowEvent::CursorMoved { position, .. },
+                    ..
+                }),
+                Platform::Winit { window, .. },
+            ) => {
+                self.cursor_x = position.x as f32 / window.inner_size().width.max(1) as f32;
+                if let Some(replay) = self.replay.as_mut().filter(|r| r.scrubbing) {
+                    replay.scrub(self.cursor_x);
+                }
+            }
+            (
+                Event::Winit(winit::event::Event::WindowEvent {
+                    event:
+                        winit::event::WindowEvent::MouseInput {
+                            state,
+                            button: winit::event::MouseButton::Left,
+                            ..
+                        },
+                    ..
+                }),
+                _,
+            ) => {
+                if let Some(replay) = &mut self.replay {
+                    replay.scrubbing = state == winit::event::ElementState::Pressed;
+                    if replay.scrubbing {
+                        replay.scrub(self.cursor_x);
+                    }
+                }
+            }
             _ => (),
         }
         Ok(())
@@ -126,13 +721,686 @@ impl App<SlimeArgs> for SlimeApp {
 }
 
 impl SlimeApp {
+    /// Advance the sim by --steps-per-frame steps, recording as it goes
+    fn step(&mut self) -> Result<()> {
+        for _ in 0..self.args.steps_per_frame {
+            let record_span = info_span!("record").entered();
+            let wanted = self.recording
+                && self
+                    .record_budget
+                    .as_mut()
+                    .map_or(true, |b| b.wants_frame());
+            if let Some(record) = self.record.as_mut().filter(|_| wanted) {
+                let with_medium = self
+                    .args
+                    .record_medium_every
+                    .map_or(false, |n| n > 0 && record.frames.len() % n == 0);
+                record_frame(record, &mut self.sim, with_medium);
+                if let Some(budget) = &mut self.record_budget {
+                    budget.enforce(record);
+                }
+
+                let n = record.frames.len();
+                if let Some(path) = &self.args.record {
+                    if self
+                        .args
+                        .autosave_every
+                        .map_or(false, |every| every > 0 && n % every == 0)
+                    {
+                        record.autosave(path)?;
+                        info!("Autosaved {} frames to {}", n, path.display());
+                    }
+                }
+            }
+            drop(record_span);
+
+            let cfg = self.live_config(&self.args.cfg)?;
+            let cmp_cfg = match &self.comparison {
+                Some(cmp) => Some(self.live_config(&cmp.cfg)?),
+                None => None,
+            };
+
+            self.sim.step(&cfg, self.args.dt, &mut self.rng);
+            if let Some((cmp, cfg)) = self.comparison.as_mut().zip(cmp_cfg) {
+                cmp.sim.step(&cfg, self.args.dt, &mut cmp.rng);
+            }
+            if self.sim.steps() % LOG_INTERVAL == 0 {
+                debug!(
+                    "Step {}, {} particles",
+                    self.sim.steps(),
+                    self.sim.frame().slime.len()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// `base` with the oscillations and gamepad applied for the current step. The sim applies
+    /// the config's own modulations itself
+    fn live_config(&self, base: &SlimeConfig) -> Result<SlimeConfig> {
+        let mut cfg = base.clone();
+        for o in &self.args.oscillate {
+            cfg.set_param(&o.param, o.value(self.sim.time()))?;
+        }
+        if let Some(gamepad) = &self.gamepad {
+            gamepad.apply(&self.args.gamepad_axis, &mut cfg)?;
+        }
+        Ok(cfg)
+    }
+
+    /// An image of the live sim, or of both sims side by side with --compare
+    fn live_view<T: Clone>(&self, image: impl Fn(&SlimeSim) -> Array2D<T>) -> Array2D<T> {
+        match &self.comparison {
+            Some(cmp) => side_by_side(&image(&self.sim), &image(&cmp.sim)),
+            None => image(&self.sim),
+        }
+    }
+
+    /// Act on the gamepad's buttons, and pick up where its sticks are for the next steps
+    fn poll_gamepad(&mut self) -> Result<()> {
+        let actions = match &mut self.gamepad {
+            Some(gamepad) => gamepad.poll(),
+            None => return Ok(()),
+        };
+        for action in actions {
+            match action {
+                GamepadAction::Reset => {
+                    if let Some(cmp) = &mut self.comparison {
+                        self.rng = StdRng::seed_from_u64(cmp.seed);
+                        cmp.rng = StdRng::seed_from_u64(cmp.seed);
+                        cmp.sim = new_sim(&self.args, &mut cmp.rng)?;
+                    }
+                    self.sim = new_sim(&self.args, &mut self.rng)?;
+                    info!("Reset the sim");
+                }
+                GamepadAction::Pause => {
+                    self.paused = !self.paused;
+                    info!("{}", if self.paused { "Paused" } else { "Resumed" });
+                }
+                GamepadAction::Record if self.record.is_none() => {
+                    warn!("Not recording; pass --record to record from the gamepad")
+                }
+                GamepadAction::Record => {
+                    self.recording = !self.recording;
+                    info!(
+                        "{} recording",
+                        if self.recording { "Resumed" } else { "Paused" }
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay a record dropped onto the window. Errors are logged rather than returned, so that
+    /// dropping the wrong file doesn't close the window
+    fn open_replay(&mut self, path: &Path) {
+        if self.comparison.is_some() {
+            warn!("Records can't be replayed while comparing sims");
+            return;
+        }
+        let speed = self.args.steps_per_frame as f32;
+        let replay = RecordFile::load(path)
+            .and_then(|record| Replay::new(record, self.args.width, self.args.height, speed));
+        match replay {
+            Ok(replay) => {
+                info!(
+                    "Replaying {} frames from {}; Space pauses, the arrow keys step and change \
+                     speed, I and O set the loop, dragging scrubs, and Escape returns to the sim",
+                    replay.record.frames.len(),
+                    path.display()
+                );
+                self.replay = Some(replay);
+            }
+            Err(e) => warn!("Failed to open {}: {:?}", path.display(), e),
+        }
+    }
+
+    /// Reload the user's fragment shader if it changed on disk. Errors are printed rather than
+    /// returned, so that a typo while editing doesn't close the window
+    fn reload_grid_shader(&mut self, ctx: &mut Context) {
+        let (path, (_, loaded)) = match self.args.fragment_shader.as_ref().zip(self.grid_shader) {
+            Some(shader) => shader,
+            None => return,
+        };
+
+        match modified(path) {
+            Ok(time) if time != loaded => match load_grid_shader(ctx, path) {
+                Ok(shader) => self.grid_shader = Some((shader, time)),
+                Err(e) => {
+                    warn!("Failed to reload {}: {:?}", path.display(), e);
+                    // Don't retry until the file changes again
+                    self.grid_shader = self.grid_shader.map(|(shader, _)| (shader, time));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Start from a black buffer when enabled, so old frames never linger
+    fn toggle_accumulation(&mut self) {
+        self.accumulation = match self.accumulation {
+            Some(_) => None,
+            None => {
+                let width = self.args.width * if self.comparison.is_some() { 2 } else { 1 };
+                Some(Array2D::new(width, self.args.height))
+            }
+        };
+    }
+
     fn exit(&self) {
         if let Some((record, path)) = self.record.as_ref().zip(self.args.record.as_ref()) {
             record.save(&path).expect("Failed to save");
+            info!("Saved {} frames to {}", record.frames.len(), path.display());
+        }
+        if let Some(profiler) = &self.profiler {
+            profiler.finish();
         }
     }
 }
 
-fn draw_sim(gb: &mut GraphicsBuilder, sim: &SlimeSim) {
-    draw_grid(gb, &sim.frame().medium, |&v| [v; 3], 0.);
+/// Picks the steps per frame for --target-fps from smoothed timings of the previous frames
+struct FrameTuner {
+    /// Seconds per frame at the target rate
+    period: f32,
+    adaptive_detail: bool,
+    /// Only every Nth particle is drawn
+    stride: usize,
+    /// Frames left until the detail may change again
+    cooldown: usize,
+    last_frame: Option<Instant>,
+    /// Smoothed seconds between frames, per sim step, and spent on the rest of `frame`
+    frame_time: Option<f32>,
+    step_time: Option<f32>,
+    draw_time: Option<f32>,
+}
+
+impl FrameTuner {
+    fn new(fps: f32, adaptive_detail: bool) -> Self {
+        Self {
+            period: 1. / fps.max(1.),
+            adaptive_detail,
+            stride: 1,
+            cooldown: 0,
+            last_frame: None,
+            frame_time: None,
+            step_time: None,
+            draw_time: None,
+        }
+    }
+
+    /// Account for a frame which began at `start` and spent `stepping` of its time on `steps`
+    /// steps, and return the number of steps to take next frame
+    fn update(&mut self, start: Instant, stepping: Duration, steps: usize) -> usize {
+        let now = Instant::now();
+        let blend = |avg: &mut Option<f32>, x: f32| {
+            let avg = avg.get_or_insert(x);
+            *avg += (x - *avg) * TUNER_SMOOTHING;
+            *avg
+        };
+
+        let step_time = blend(
+            &mut self.step_time,
+            stepping.as_secs_f32() / steps.max(1) as f32,
+        );
+        let draw_time = blend(&mut self.draw_time, (now - start - stepping).as_secs_f32());
+        let budget = self.period * TUNER_HEADROOM - draw_time;
+        let next = ((budget / step_time.max(1e-6)) as usize).clamp(1, MAX_TUNED_STEPS);
+
+        // The time between frames also covers the GPU, which fewer steps won't speed up
+        let frame_time = match self.last_frame.replace(now) {
+            Some(last) => blend(&mut self.frame_time, (now - last).as_secs_f32()),
+            None => return next,
+        };
+        self.cooldown = self.cooldown.saturating_sub(1);
+        if self.adaptive_detail && self.cooldown == 0 {
+            if next == 1 && frame_time > self.period * 1.2 {
+                self.stride *= 2;
+                self.cooldown = DETAIL_COOLDOWN;
+                debug!("Drawing one particle in {}", self.stride);
+            } else if self.stride > 1 && frame_time < self.period * 1.05 {
+                self.stride /= 2;
+                self.cooldown = DETAIL_COOLDOWN;
+                debug!("Drawing one particle in {}", self.stride);
+            }
+        }
+
+        next
+    }
+}
+
+/// Playback of a record, looping between its in and out points
+struct Replay {
+    record: RecordFile,
+    frame: usize,
+    /// Grid-sized image of the current frame: the recorded medium where there is one, or else a
+    /// fading trace of the particles
+    image: Array2D<f32>,
+    /// Recorded frames shown per rendered frame
+    speed: f32,
+    /// Fraction of a frame left over from the last advance
+    carry: f32,
+    paused: bool,
+    /// First and last frames of the loop
+    loop_in: usize,
+    loop_out: usize,
+    /// The left mouse button is held, dragging the playhead
+    scrubbing: bool,
+}
+
+impl Replay {
+    fn new(record: RecordFile, width: usize, height: usize, speed: f32) -> Result<Self> {
+        ensure!(!record.frames.is_empty(), "The record has no frames");
+        let mut replay = Self {
+            loop_out: record.frames.len() - 1,
+            record,
+            frame: 0,
+            image: Array2D::new(width, height),
+            speed,
+            carry: 0.,
+            paused: false,
+            loop_in: 0,
+            scrubbing: false,
+        };
+        replay.show_frame();
+        Ok(replay)
+    }
+
+    fn advance(&mut self) {
+        if self.paused || self.scrubbing {
+            return;
+        }
+        self.carry += self.speed;
+        let frames = self.carry.floor() as usize;
+        self.carry -= frames as f32;
+        if frames == 0 {
+            return;
+        }
+
+        let length = self.loop_out - self.loop_in + 1;
+        let offset = if (self.loop_in..=self.loop_out).contains(&self.frame) {
+            self.frame - self.loop_in + frames
+        } else {
+            frames - 1
+        };
+        self.seek(self.loop_in + offset % length);
+    }
+
+    /// Show another frame. Without a recorded medium, the trace is rebuilt from the frames just
+    /// before it
+    fn seek(&mut self, frame: usize) {
+        let frame = frame.min(self.record.frames.len() - 1);
+        if frame == self.frame + 1 {
+            self.frame = frame;
+            self.show_frame();
+            return;
+        }
+
+        let start = match self.record.frames[frame].medium {
+            Some(_) => frame,
+            None => {
+                self.image.data_mut().iter_mut().for_each(|v| *v = 0.);
+                frame.saturating_sub(REPLAY_TRACE)
+            }
+        };
+        for idx in start..=frame {
+            self.frame = idx;
+            self.show_frame();
+        }
+    }
+
+    /// Move the playhead to this fraction of the way through the record
+    fn scrub(&mut self, fraction: f32) {
+        let last = self.record.frames.len() - 1;
+        let frame = (fraction.clamp(0., 1.) * last as f32).round() as usize;
+        if frame != self.frame {
+            self.seek(frame);
+        }
+    }
+
+    /// Playback controls: Space pauses, Left and Right step a frame, Up and Down double and halve
+    /// the speed, I and O set the loop in and out points at the current frame, L clears them,
+    /// and Home and End jump to them
+    fn key(&mut self, key: winit::event::VirtualKeyCode) {
+        use winit::event::VirtualKeyCode as Key;
+        let last = self.record.frames.len() - 1;
+        match key {
+            Key::Space => {
+                self.paused = !self.paused;
+                info!(
+                    "{} at frame {}",
+                    if self.paused { "Paused" } else { "Playing" },
+                    self.frame
+                );
+            }
+            Key::Left | Key::Right => {
+                self.paused = true;
+                let frame = match key {
+                    Key::Left => self.frame.saturating_sub(1),
+                    _ => self.frame + 1,
+                };
+                self.seek(frame);
+                info!("Frame {} of {}", self.frame, last);
+            }
+            Key::Up | Key::Down => {
+                self.speed = match key {
+                    Key::Up => self.speed * 2.,
+                    _ => self.speed / 2.,
+                }
+                .clamp(MIN_REPLAY_SPEED, MAX_REPLAY_SPEED);
+                info!("Replaying {} frames per frame", self.speed);
+            }
+            Key::I => {
+                self.loop_in = self.frame;
+                self.loop_out = self.loop_out.max(self.frame);
+                info!("Looping frames {} to {}", self.loop_in, self.loop_out);
+            }
+            Key::O => {
+                self.loop_out = self.frame;
+                self.loop_in = self.loop_in.min(self.frame);
+                info!("Looping frames {} to {}", self.loop_in, self.loop_out);
+            }
+            Key::L => {
+                self.loop_in = 0;
+                self.loop_out = last;
+                info!("Looping the whole record");
+            }
+            Key::Home => self.seek(self.loop_in),
+            Key::End => self.seek(self.loop_out),
+            _ => (),
+        }
+    }
+
+    /// Timeline along the bottom edge of the view: the record, the loop within it and the
+    /// playhead, as line segments
+    fn timeline(&self) -> Vec<Vertex> {
+        let last = (self.record.frames.len() - 1).max(1) as f32;
+        let x = |frame: usize| frame as f32 * 2. / last - 1.;
+        let y = TIMELINE_Y;
+        let (dim, bright, head) = ([0.3; 3], [0.8; 3], [1.0, 0.81, 0.0]);
+        vec![
+            Vertex::new([-1., y, 0.], dim),
+            Vertex::new([1., y, 0.], dim),
+            Vertex::new([x(self.loop_in), y, 0.], bright),
+            Vertex::new([x(self.loop_out), y, 0.], bright),
+            Vertex::new([x(self.frame), y - TIMELINE_HEAD, 0.], head),
+            Vertex::new([x(self.frame), y + TIMELINE_HEAD, 0.], head),
+        ]
+    }
+
+    fn show_frame(&mut self) {
+        let (w, h) = (self.image.width(), self.image.height());
+        let frame = &self.record.frames[self.frame];
+        match &frame.medium {
+            Some(medium) => {
+                let medium = Array2D::from_array(self.record.width, medium.clone());
+                self.image = resample(&medium, w, h);
+            }
+            None => {
+                self.image
+                    .data_mut()
+                    .iter_mut()
+                    .for_each(|v| *v *= REPLAY_FADE);
+                let scale = Vector2::new(
+                    w as f32 / self.record.width as f32,
+                    h as f32 / self.record.height as f32,
+                );
+                for part in &frame.slime {
+                    let pos = part.position.component_mul(&scale);
+                    let (x, y) = (pos.x.floor() as isize, pos.y.floor() as isize);
+                    if (0..w as isize).contains(&x) && (0..h as isize).contains(&y) {
+                        self.image[(x as usize, y as usize)] += REPLAY_DEPOSIT;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The sim at its start, with all the fields and images given on the command line
+fn new_sim(args: &SlimeArgs, rng: &mut StdRng) -> Result<SlimeSim> {
+    let mut sim = SlimeSim::new(args.width, args.height, args.n_particles, &mut *rng);
+    args.init.apply(&mut sim, &mut *rng)?;
+    if args.gpu {
+        sim.use_gpu()?;
+    }
+
+    if let Some(path) = &args.force_image {
+        let image = resample(&load_grayscale(path)?, args.width, args.height);
+        let field = gradient_field(&image, args.force_strength, args.force_swirl);
+        sim.set_force_field(Some(field));
+    }
+
+    if let Some(path) = &args.temperature_image {
+        let mut image = resample(&load_grayscale(path)?, args.width, args.height);
+        image.data_mut().iter_mut().for_each(|v| {
+            *v = args.temperature_min + (args.temperature_max - args.temperature_min) * *v
+        });
+        sim.set_temperature(Some(image));
+    }
+
+    let cost = match (&args.cost_image, args.cost_noise) {
+        (Some(path), _) => Some(resample(&load_grayscale(path)?, args.width, args.height)),
+        (None, Some(scale)) => {
+            let seed = args.seed.unwrap_or(0) as u32;
+            Some(noise_image(args.width, args.height, scale, seed))
+        }
+        (None, None) => None,
+    };
+    if let Some(mut cost) = cost {
+        cost.data_mut().iter_mut().for_each(|v| *v *= args.cost_max);
+        sim.set_cost(Some(cost));
+    }
+
+    if let Some(path) = &args.obstacle_image {
+        let image = resample(&load_grayscale(path)?, args.width, args.height);
+        let mask = image.data().iter().map(|&v| v > 0.5).collect();
+        sim.set_obstacles(Some(Array2D::from_array(args.width, mask)));
+    }
+
+    if let Some(path) = &args.zone_image {
+        let image = resample(&load_grayscale(path)?, args.width, args.height);
+        let levels = image
+            .data()
+            .iter()
+            .map(|&v| (v * 255.).round() as u8)
+            .collect();
+        sim.set_zone_map(Some(Array2D::from_array(args.width, levels)));
+    }
+
+    if let Some(path) = &args.food_image {
+        let image = resample(&load_grayscale(path)?, args.width, args.height);
+        sim.set_food(Some(image));
+    }
+
+    Ok(sim)
+}
+
+/// Two images of the same size, with `right` to the right of `left`
+fn side_by_side<T: Clone>(left: &Array2D<T>, right: &Array2D<T>) -> Array2D<T> {
+    let data = left
+        .data()
+        .chunks_exact(left.width())
+        .zip(right.data().chunks_exact(right.width()))
+        .flat_map(|(l, r)| l.iter().chain(r).cloned())
+        .collect();
+    Array2D::from_array(left.width() + right.width(), data)
+}
+
+fn load_grid_shader(ctx: &mut Context, path: &Path) -> Result<Shader> {
+    let fragment = std::fs::read(path)?;
+    ctx.shader(DEFAULT_VERTEX_SHADER, &fragment, Primitive::Triangles)
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+/// Map a position in a `width` by `height` sim to view space, where the grid spans -1..1
+fn to_view(pos: Vector2<f32>, width: usize, height: usize) -> [f32; 3] {
+    [
+        pos.x * 2. / width as f32 - 1.,
+        pos.y * 2. / height as f32 - 1.,
+        0.,
+    ]
+}
+
+/// One point sprite for every `stride`th particle, colored by behavioral state or group
+fn particle_vertices(
+    slime: &[SlimeParticle],
+    width: usize,
+    height: usize,
+    by_group: bool,
+    stride: usize,
+) -> Vec<Vertex> {
+    slime
+        .iter()
+        .step_by(stride)
+        .map(|part| {
+            let color = if by_group {
+                group_color(part.group)
+            } else {
+                match part.state {
+                    AgentState::Exploring => [0.0, 0.66, 1.0],
+                    AgentState::Exploiting => [1.0, 0.81, 0.0],
+                    AgentState::Homing => [1.0, 0.0, 0.53],
+                }
+            };
+            Vertex::new(to_view(part.position, width, height), color)
+        })
+        .collect()
+}
+
+/// Static, double-sided grid mesh with four vertices per cell, in the same row-major order as
+/// the medium so that only the colors need updating each frame
+fn grid_mesh(width: usize, height: usize) -> (Vec<Vertex>, Vec<u32>) {
+    let mut vertices = Vec::with_capacity(width * height * 4);
+    let mut indices = Vec::with_capacity(width * height * 12);
+
+    let pos = |x: usize, y: usize| {
+        [
+            x as f32 * 2. / width as f32 - 1.,
+            y as f32 * 2. / height as f32 - 1.,
+            0.,
+        ]
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            let base = vertices.len() as u32;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                vertices.push(Vertex::new(pos(x + dx, y + dy), [0.; 3]));
+            }
+            let [tl, tr, bl, br] = [base, base + 1, base + 2, base + 3];
+            indices.extend_from_slice(&[tl, tr, bl, tr, br, bl]);
+            indices.extend_from_slice(&[tl, bl, tr, tr, bl, br]);
+        }
+    }
+
+    (vertices, indices)
+}
+
+/// How densities become grid colors
+struct Palette<'a> {
+    lut: Option<&'a Lut>,
+    /// How recently each cell was deposited into, which replaces the LUT (see --color-by-age)
+    freshness: Option<&'a Array2D<f32>>,
+    /// A grid-sized background image and how to composite over it
+    background: Option<(&'a Array2D<[f32; 3]>, Blend)>,
+}
+
+impl Palette<'_> {
+    /// Color of cell `idx` at density `v`, darkened by `shade`
+    fn color(&self, v: f32, shade: f32, idx: usize) -> [f32; 3] {
+        let color = match self.freshness {
+            Some(fresh) => age_color(fresh.data()[idx]).map(|c| c * v),
+            None => self.lut.map_or([v; 3], |lut| lut.sample(v)),
+        }
+        .map(|c| c * shade);
+        match self.background {
+            Some((bg, blend)) => blend.apply(bg.data()[idx], color),
+            None => color,
+        }
+    }
+}
+
+/// Write a grid-sized image into the colors of a mesh from `grid_mesh`
+fn color_grid(vertices: &mut [Vertex], image: &Array2D<f32>, palette: &Palette) {
+    let cells = vertices.chunks_exact_mut(4).zip(image.data()).enumerate();
+    for (idx, (quad, &v)) in cells {
+        let color = palette.color(v, 1., idx);
+        quad.iter_mut().for_each(|vert| vert.color = color);
+    }
+}
+
+/// Displace a mesh from `grid_mesh` so that the image becomes a continuous heightfield, shaded
+/// with a single directional light
+fn heightfield_grid(vertices: &mut [Vertex], image: &Array2D<f32>, palette: &Palette, scale: f32) {
+    let (w, h) = (image.width() as isize, image.height() as isize);
+    let sample =
+        |x: isize, y: isize| image[(x.clamp(0, w - 1) as usize, y.clamp(0, h - 1) as usize)];
+    let light = Vector3::new(0.3, 0.5, 1.).normalize();
+
+    for (idx, quad) in vertices.chunks_exact_mut(4).enumerate() {
+        let (x, y) = (idx as isize % w, idx as isize / w);
+        for (vert, (dx, dy)) in quad.iter_mut().zip([(0, 0), (1, 0), (0, 1), (1, 1)]) {
+            // Neighboring cells share corners, so sampling at the corner keeps the surface closed
+            let (cx, cy) = (x + dx, y + dy);
+            let v = sample(cx, cy);
+
+            // Central differences, in view units (the grid spans two units)
+            let gx = (sample(cx + 1, cy) - sample(cx - 1, cy)) * scale * w as f32 / 4.;
+            let gy = (sample(cx, cy + 1) - sample(cx, cy - 1)) * scale * h as f32 / 4.;
+            let normal = Vector3::new(-gx, -gy, 1.).normalize();
+            let shade = normal.dot(&light).max(0.2);
+
+            vert.pos[2] = v * scale;
+            vert.color = palette.color(v, shade, idx);
+        }
+    }
+}
+
+/// Add a blurred copy of everything above `threshold` back onto `image`, so that bright trail
+/// cores bleed into their surroundings
+fn add_bloom(image: &mut Array2D<f32>, threshold: f32, intensity: f32, radius: usize) {
+    let (w, h) = (image.width(), image.height());
+    let mut bright: Vec<f32> = image
+        .data()
+        .iter()
+        .map(|&v| (v - threshold).max(0.))
+        .collect();
+
+    // Two passes of a separable box blur look close enough to a gaussian
+    for _ in 0..2 {
+        box_blur(&mut bright, w, h, radius, 1, w);
+        box_blur(&mut bright, h, w, radius, w, 1);
+    }
+
+    for (v, b) in image.data_mut().iter_mut().zip(&bright) {
+        *v += b * intensity;
+    }
+}
+
+/// Box blur along `lines` lines of `len` samples each. `step` is the stride between neighboring
+/// samples along a line and `stride` the stride between lines, so that in a row-major `w` by `h`
+/// buffer `(w, h, .., 1, w)` blurs rows and `(h, w, .., w, 1)` blurs columns. Edges are clamped.
+fn box_blur(data: &mut [f32], len: usize, lines: usize, radius: usize, step: usize, stride: usize) {
+    let r = radius as isize;
+    let norm = 1. / (2 * radius + 1) as f32;
+    let mut line = vec![0.; len];
+
+    for l in 0..lines {
+        let at = |i: isize| l * stride + i.clamp(0, len as isize - 1) as usize * step;
+
+        let mut sum: f32 = (-r..=r).map(|i| data[at(i)]).sum();
+        for (i, out) in line.iter_mut().enumerate() {
+            *out = sum * norm;
+            let i = i as isize;
+            sum += data[at(i + r + 1)] - data[at(i - r)];
+        }
+
+        for (i, &v) in line.iter().enumerate() {
+            data[l * stride + i * step] = v;
+        }
+    }
 }
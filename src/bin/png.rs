@@ -1,21 +1,39 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use idek_basics::Array2D;
 use nalgebra::Vector2;
-use slime::{record::RecordFile, xiaolin::draw_line};
+use serde::{Deserialize, Serialize};
+use slime::{
+    font::{draw_text, text_width, GLYPH_HEIGHT},
+    image::{load_rgb, resample, Blend},
+    logging::{self, Verbosity},
+    lut::Lut,
+    palette::{age_color, group_color},
+    progress::Progress,
+    record::{RecordFile, RecordFrame},
+    sim::{wrap_delta, AgentState, SlimeParticle},
+    xiaolin::draw_line,
+};
+use std::collections::HashMap;
 use std::f32::consts::{PI, TAU};
+use std::ops::Range;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 use std::{
     fs::File,
-    io::BufWriter,
+    io::{BufReader, BufWriter},
     path::{Path, PathBuf},
 };
 use structopt::StructOpt;
+use tracing::{error, info};
 
 type Rgb = [f32; 3];
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+    /// Record to render. May be left out when only writing out saved buffers
     #[structopt()]
-    record: PathBuf,
+    record: Option<PathBuf>,
 
     #[structopt(short, long, default_value = "out.png")]
     outfile: PathBuf,
@@ -38,89 +56,1309 @@ struct Opt {
     /// Intensity of plotted points
     #[structopt(short, long, default_value = "0.05")]
     intensity: f32,
+
+    /// Color trails by the particle's behavioral state instead of its group
+    #[structopt(long)]
+    color_by_state: bool,
+
+    /// Color trails by direction of motion (hue) and speed (brightness)
+    #[structopt(long)]
+    color_by_velocity: bool,
+
+    /// Color trails by when they were drawn, from cool at the first frame of the range to warm
+    /// at the last, so new growth stands out from old structure. Works best with --blend max
+    #[structopt(long)]
+    color_by_age: bool,
+
+    /// Speed shown at full brightness by --color-by-velocity, in cells per recorded frame
+    #[structopt(long, default_value = "1.0")]
+    max_speed: f32,
+
+    /// Image to composite the render over
+    #[structopt(long)]
+    background: Option<PathBuf>,
+
+    /// How the render is composited over --background: add, screen, multiply or lighten
+    #[structopt(long, default_value = "screen")]
+    background_blend: Blend,
+
+    /// Write RGBA, with the accumulated intensity as alpha, instead of burning onto black
+    #[structopt(long)]
+    transparent: bool,
+
+    /// Write every Nth frame to a numbered PNG next to --outfile, instead of only the final
+    /// long exposure
+    #[structopt(long)]
+    sequence: Option<usize>,
+
+    /// Fraction of the trails kept each frame. Below 1, old trails fade out
+    #[structopt(long, default_value = "1.0")]
+    decay: f32,
+
+    /// Mark each particle's current position in sequence frames
+    #[structopt(long)]
+    draw_particles: bool,
+
+    /// Radius in output pixels of the antialiased dots drawn by --draw-particles, which keep
+    /// the particles' sub-pixel positions. By default each particle lights the one pixel it
+    /// falls in
+    #[structopt(long)]
+    particle_size: Option<f32>,
+
+    /// Wrap lines around the image edges so the output tiles seamlessly
+    #[structopt(long)]
+    tileable: bool,
+
+    /// How overlapping trails combine: add, max, screen or average
+    #[structopt(long, default_value = "add")]
+    blend: Accumulate,
+
+    /// Brightness adjustment in stops, applied before quantization
+    #[structopt(long, default_value = "0")]
+    exposure: f32,
+
+    /// Display gamma applied before quantization
+    #[structopt(long, default_value = "1.0")]
+    gamma: f32,
+
+    /// Equalize the histogram of lit pixels, spreading their brightness evenly
+    #[structopt(long)]
+    equalize: bool,
+
+    /// Draw the medium recorded with the trails behind them. Requires a record made with
+    /// --record-medium-every
+    #[structopt(long)]
+    medium_background: bool,
+
+    /// Recolor the render by brightness through a lookup table, as a .cube file or a gradient
+    /// PNG
+    #[structopt(long)]
+    lut: Option<PathBuf>,
+
+    /// Record frame whose medium is drawn; defaults to the last one that has it
+    #[structopt(long)]
+    medium_frame: Option<usize>,
+
+    /// Color of the medium at full density, as r,g,b
+    #[structopt(long, use_delimiter = true, default_value = "0.15,0.35,0.6")]
+    medium_color: Vec<f32>,
+
+    /// Remap particle positions before plotting: none, polar, log-polar or fisheye
+    #[structopt(long, default_value = "none")]
+    remap: Remap,
+
+    /// Save the float accumulation buffer here at the end of the render, so it can be resumed
+    /// or merged later
+    #[structopt(long)]
+    save_buffer: Option<PathBuf>,
+
+    /// Also write --save-buffer every N processed frames
+    #[structopt(long)]
+    checkpoint_every: Option<usize>,
+
+    /// Continue a render from a buffer written with --save-buffer
+    #[structopt(long)]
+    resume: Option<PathBuf>,
+
+    /// Add buffers written with --save-buffer (e.g. other frame ranges, rendered elsewhere)
+    #[structopt(long)]
+    merge: Vec<PathBuf>,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+
+    /// Keep running, and re-render whenever the record file changes
+    #[structopt(long)]
+    watch: bool,
+
+    /// How often --watch checks the record, in seconds
+    #[structopt(long, default_value = "2.0")]
+    watch_interval: f32,
+
+    /// Render several records side by side into a labeled grid instead, each tile being
+    /// --width by --height and sharing the same exposure settings
+    #[structopt(long)]
+    montage: Vec<PathBuf>,
+
+    /// Split the frame range into this many sections and render each into a tile of a grid,
+    /// labeled with its last frame and sim time, to find the interesting parts of a long record
+    #[structopt(long)]
+    contact_sheet: Option<usize>,
+
+    /// Second record to fade into over the frame range. Requires --sequence
+    #[structopt(long)]
+    crossfade: Option<PathBuf>,
+
+    /// Only render chunk i of N of the frame range, given as i/N, for splitting a render over
+    /// several machines. Save each with --save-buffer and combine them with --merge
+    #[structopt(long)]
+    frames_chunk: Option<Chunk>,
+
+    /// Split each plotted frame into this many extra sub-frames in --sequence output, for smooth
+    /// video from records with a large --frame-step
+    #[structopt(long, default_value = "0")]
+    interpolate: usize,
+
+    /// Interpolate along Catmull-Rom splines instead of straight lines
+    #[structopt(long)]
+    spline: bool,
+
+    /// Only render this region of the record, given as x,y,w,h in sim coordinates
+    #[structopt(long, use_delimiter = true, allow_hyphen_values = true)]
+    crop: Vec<f32>,
+
+    /// Magnify the center of the (cropped) view by this factor
+    #[structopt(long, default_value = "1.0")]
+    zoom: f32,
+
+    /// Stamp the frame number, sim time and particle count into the top left corner of each
+    /// output
+    #[structopt(long)]
+    overlay: bool,
+
+    /// Sim time between recorded frames, shown by --overlay. Defaults to the one stored in the
+    /// record, or 0.5 for older records
+    #[structopt(long)]
+    dt: Option<f32>,
+
+    /// Extra line for --overlay, such as the parameters of a sweep
+    #[structopt(long)]
+    overlay_text: Option<String>,
+}
+
+/// One of several equal parts of the frame range
+#[derive(Clone, Copy, Debug)]
+struct Chunk {
+    index: usize,
+    count: usize,
+}
+
+impl FromStr for Chunk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s.split_once('/').context("Expected a chunk like 2/8")?;
+        let (index, count) = (index.trim().parse()?, count.trim().parse()?);
+        ensure!(index < count, "Chunk index must be below the chunk count");
+        Ok(Self { index, count })
+    }
+}
+
+/// Coordinate transform applied to particle positions before plotting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Remap {
+    None,
+    /// x becomes the angle and y the radius, so the left and right edges meet
+    Polar,
+    /// Like polar, but with the radius growing exponentially along y
+    LogPolar,
+    /// Magnifies the center and compresses the edges
+    Fisheye,
+}
+
+impl FromStr for Remap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Remap::None,
+            "polar" => Remap::Polar,
+            "log-polar" => Remap::LogPolar,
+            "fisheye" => Remap::Fisheye,
+            _ => bail!(
+                "Unknown remapping \"{}\"; expected none, polar, log-polar or fisheye",
+                s
+            ),
+        })
+    }
+}
+
+impl Remap {
+    /// Map a position in 0..1 to a position in 0..1
+    fn apply(self, u: f32, v: f32) -> (f32, f32) {
+        let around =
+            |angle: f32, radius: f32| (0.5 + radius * angle.cos(), 0.5 + radius * angle.sin());
+
+        match self {
+            Remap::None => (u, v),
+            Remap::Polar => around(u * TAU, v * 0.5),
+            Remap::LogPolar => around(u * TAU, 0.5 * ((v - 1.) * 4.).exp()),
+            Remap::Fisheye => {
+                const STRENGTH: f32 = 2.;
+                let (dx, dy) = ((u - 0.5) * 2., (v - 0.5) * 2.);
+                let r = dx.hypot(dy);
+                if r == 0. {
+                    return (u, v);
+                }
+                let scale = (r * STRENGTH).atan() / STRENGTH.atan() / r;
+                (0.5 + dx * scale / 2., 0.5 + dy * scale / 2.)
+            }
+        }
+    }
+}
+
+/// How plotted points combine with what is already in the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Accumulate {
+    Add,
+    /// Keeps individual trajectories visible in crowded regions
+    Max,
+    Screen,
+    /// Coverage-weighted mean color of everything passing through a pixel
+    Average,
+}
+
+impl FromStr for Accumulate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "add" => Accumulate::Add,
+            "max" => Accumulate::Max,
+            "screen" => Accumulate::Screen,
+            "average" => Accumulate::Average,
+            _ => bail!(
+                "Unknown blend mode \"{}\"; expected add, max, screen or average",
+                s
+            ),
+        })
+    }
+}
+
+/// On-disk form of a `Canvas`, for resuming and merging renders
+#[derive(Serialize, Deserialize)]
+struct BufferFile {
+    width: usize,
+    height: usize,
+    mode: Accumulate,
+    /// Index of the next record frame to plot
+    next_frame: usize,
+    image: Vec<Rgb>,
+    weight: Vec<f32>,
+}
+
+/// Floating point accumulation buffer
+#[derive(Clone)]
+struct Canvas {
+    image: Array2D<Rgb>,
+    /// Total coverage plotted to each pixel, used by `Accumulate::Average`
+    weight: Array2D<f32>,
+    mode: Accumulate,
+    /// Wrap points around the edges instead of dropping them
+    wrap: bool,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize, mode: Accumulate, wrap: bool) -> Self {
+        Self {
+            image: Array2D::new(width, height),
+            weight: Array2D::new(width, height),
+            mode,
+            wrap,
+        }
+    }
+
+    /// Plot `color` with the given antialiasing coverage
+    fn plot(&mut self, mut x: i32, mut y: i32, color: Rgb, coverage: f32) {
+        let (w, h) = (self.image.width() as i32, self.image.height() as i32);
+        if self.wrap {
+            x = x.rem_euclid(w);
+            y = y.rem_euclid(h);
+        }
+
+        if x < 0 || y < 0 || x >= w || y >= h {
+            return;
+        }
+
+        let pos = (x as usize, y as usize);
+        self.weight[pos] += coverage;
+        for (o, i) in self.image[pos].iter_mut().zip(color) {
+            let i = i * coverage;
+            *o = match self.mode {
+                Accumulate::Add | Accumulate::Average => *o + i,
+                Accumulate::Max => o.max(i),
+                Accumulate::Screen => 1. - (1. - *o) * (1. - i),
+            };
+        }
+    }
+
+    /// Plot an antialiased disc of `color` centered on (x, y)
+    fn plot_disc(&mut self, x: f32, y: f32, radius: f32, color: Rgb) {
+        let reach = (radius + 0.5).ceil() as i32;
+        let (cx, cy) = (x.floor() as i32, y.floor() as i32);
+        for py in cy - reach..=cy + reach {
+            for px in cx - reach..=cx + reach {
+                let dist = (px as f32 + 0.5 - x).hypot(py as f32 + 0.5 - y);
+                let coverage = (radius + 0.5 - dist).clamp(0., 1.);
+                if coverage > 0. {
+                    self.plot(px, py, color, coverage);
+                }
+            }
+        }
+    }
+
+    fn decay(&mut self, factor: f32) {
+        for (px, w) in self.image.data_mut().iter_mut().zip(self.weight.data_mut()) {
+            *px = px.map(|v| v * factor);
+            *w *= factor;
+        }
+    }
+
+    /// Combine another canvas' contents into this one, as if they had been plotted here
+    fn merge(&mut self, other: &Canvas) {
+        for (px, o) in self.image.data_mut().iter_mut().zip(other.image.data()) {
+            for (a, b) in px.iter_mut().zip(o) {
+                *a = match self.mode {
+                    Accumulate::Add | Accumulate::Average => *a + b,
+                    Accumulate::Max => a.max(*b),
+                    Accumulate::Screen => 1. - (1. - *a) * (1. - b),
+                };
+            }
+        }
+
+        for (w, o) in self.weight.data_mut().iter_mut().zip(other.weight.data()) {
+            *w += o;
+        }
+    }
+
+    /// Write the buffer, along with the index of the next record frame to plot
+    fn save(&self, path: &Path, next_frame: usize) -> Result<()> {
+        let file = BufferFile {
+            width: self.image.width(),
+            height: self.image.height(),
+            mode: self.mode,
+            next_frame,
+            image: self.image.data().to_vec(),
+            weight: self.weight.data().to_vec(),
+        };
+        let writer = BufWriter::new(File::create(path)?);
+        Ok(bincode::serialize_into(writer, &file)?)
+    }
+
+    /// Read a buffer written by `save`, checking that it matches the requested output. Also
+    /// returns the next frame to plot
+    fn load(path: &Path, args: &Opt) -> Result<(Self, usize)> {
+        let reader = BufReader::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        );
+        let file: BufferFile = bincode::deserialize_from(reader)?;
+
+        ensure!(
+            (file.width, file.height) == (args.width, args.height),
+            "{} is {}x{}, but the output is {}x{}",
+            path.display(),
+            file.width,
+            file.height,
+            args.width,
+            args.height
+        );
+        ensure!(
+            file.mode == args.blend,
+            "{} was rendered with --blend {:?}",
+            path.display(),
+            file.mode
+        );
+
+        let canvas = Self {
+            image: Array2D::from_array(file.width, file.image),
+            weight: Array2D::from_array(file.width, file.weight),
+            mode: file.mode,
+            wrap: args.tileable,
+        };
+        Ok((canvas, file.next_frame))
+    }
+
+    /// The final image, with averages resolved
+    fn image(&self) -> Array2D<Rgb> {
+        let mut image = self.image.clone();
+        if self.mode == Accumulate::Average {
+            for (px, &w) in image.data_mut().iter_mut().zip(self.weight.data()) {
+                *px = px.map(|v| if w > 0. { v / w } else { 0. });
+            }
+        }
+        image
+    }
 }
 
 fn main() -> Result<()> {
     let args = Opt::from_args();
+    logging::init(&args.verbosity, None)?;
+    ensure!(
+        !(args.transparent && (args.background.is_some() || args.medium_background)),
+        "--transparent can't be combined with a background"
+    );
+    ensure!(args.medium_color.len() == 3, "--medium-color takes r,g,b");
+    ensure!(
+        args.crop.is_empty() || args.crop.len() == 4,
+        "--crop takes x,y,w,h"
+    );
+    ensure!(args.zoom > 0., "--zoom must be positive");
+    ensure!(
+        args.particle_size.map_or(true, |r| r > 0.),
+        "--particle-size must be positive"
+    );
+    ensure!(
+        [
+            args.color_by_state,
+            args.color_by_velocity,
+            args.color_by_age
+        ]
+        .iter()
+        .filter(|&&on| on)
+        .count()
+            <= 1,
+        "Pick one of --color-by-state, --color-by-velocity and --color-by-age"
+    );
+    ensure!(
+        !(args.tileable && args.remap != Remap::None),
+        "--tileable can't be combined with --remap"
+    );
 
-    let mut image: Array2D<[f32; 3]> = Array2D::new(args.width, args.height);
+    if !args.montage.is_empty() {
+        ensure!(
+            args.record.is_none() && !args.watch,
+            "--montage replaces the record argument"
+        );
+        ensure!(
+            args.background.is_none() && !args.medium_background,
+            "--montage can't be combined with a background"
+        );
+        ensure!(
+            args.sequence.is_none() && args.save_buffer.is_none(),
+            "--montage can't be combined with --sequence or --save-buffer"
+        );
+        ensure!(
+            args.resume.is_none() && args.merge.is_empty(),
+            "--montage can't be combined with --resume or --merge"
+        );
+        return render_montage(&args);
+    }
 
-    println!("Loading...");
-    let record = RecordFile::load(&args.record)?;
+    if let Some(n) = args.contact_sheet {
+        ensure!(n > 0, "--contact-sheet needs at least one tile");
+        ensure!(
+            args.background.is_none() && !args.medium_background,
+            "--contact-sheet can't be combined with a background"
+        );
+        ensure!(
+            !args.watch && args.crossfade.is_none() && args.frames_chunk.is_none(),
+            "--contact-sheet can't be combined with --watch, --crossfade or --frames-chunk"
+        );
+        ensure!(
+            args.sequence.is_none() && args.save_buffer.is_none(),
+            "--contact-sheet can't be combined with --sequence or --save-buffer"
+        );
+        ensure!(
+            args.resume.is_none() && args.merge.is_empty(),
+            "--contact-sheet can't be combined with --resume or --merge"
+        );
+        let path = args
+            .record
+            .as_ref()
+            .context("--contact-sheet needs a record")?;
+        return render_contact_sheet(path, n, &args);
+    }
 
-    let n_frames = record.frames.len();
-    let last_frame = args.last_frame.unwrap_or(n_frames);
+    if args.frames_chunk.is_some() {
+        ensure!(
+            args.save_buffer.is_some(),
+            "--frames-chunk only makes sense with --save-buffer"
+        );
+        ensure!(
+            args.sequence.is_none() && args.resume.is_none() && args.crossfade.is_none(),
+            "--frames-chunk can't be combined with --sequence, --resume or --crossfade"
+        );
+    }
 
-    let frames = &record.frames[args.first_frame..last_frame];
-    let first = record.frames.first().context("No frames :/")?;
+    if args.crossfade.is_some() {
+        ensure!(
+            args.resume.is_none() && args.merge.is_empty() && args.save_buffer.is_none(),
+            "--crossfade can't be combined with --resume, --merge or --save-buffer"
+        );
+    }
 
-    let mut last = first;
+    match (&args.record, args.watch) {
+        (Some(path), true) => watch(path, &args),
+        (None, true) => bail!("--watch needs a record"),
+        (_, false) => render_outputs(&args),
+    }
+}
+
+/// Re-render whenever the record changes on disk, until interrupted
+fn watch(path: &Path, args: &Opt) -> Result<()> {
+    let interval = Duration::from_secs_f32(args.watch_interval);
+    let mut rendered = None;
 
-    // Mapping from slime space to PNG space
-    let coord_map = |v: Vector2<f32>| {
-        (
-            v.x * args.width as f32 / record.width as f32,
-            v.y * args.height as f32 / record.height as f32,
-        )
+    loop {
+        let modified = std::fs::metadata(path)?.modified()?;
+        if rendered != Some(modified) {
+            rendered = Some(modified);
+            // The record may have been caught halfway through being written; the next change
+            // triggers another attempt
+            match render_outputs(args) {
+                Ok(()) => info!("Rendered {}", args.outfile.display()),
+                Err(e) => error!("Render failed: {:?}", e),
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Render each record of --montage into a tile of a grid, labeled with the file name
+fn render_montage(args: &Opt) -> Result<()> {
+    let post = PostProcess {
+        background: None,
+        lut: match &args.lut {
+            Some(path) => Some(Lut::load(path)?),
+            None => None,
+        },
     };
 
-    // Bounds check before plotting to image (additive)
-    let mut plot_point = |x: i32, y: i32, color: [f32; 3]| {
-        if x >= 0 && y >= 0 && x < args.width as i32 && y < args.height as i32 {
-            image[(x as usize, y as usize)]
-                .iter_mut()
-                .zip(color)
-                .for_each(|(o, i)| *o += i * args.intensity);
+    let n = args.montage.len();
+    let cols = (n as f32).sqrt().ceil() as usize;
+    let rows = (n + cols - 1) / cols;
+    let scale = (args.width / 200).max(1);
+
+    let mut image = Array2D::new(cols * args.width, rows * args.height);
+    let mut labels = vec![];
+
+    for (i, path) in args.montage.iter().enumerate() {
+        info!("Loading {}...", path.display());
+        let record = RecordFile::load(path)?;
+
+        let mut canvas = Canvas::new(args.width, args.height, args.blend, args.tileable);
+        let range = frame_range(args, record.frames.len());
+        render(&record, &mut canvas, range, &post, args)?;
+
+        let (ox, oy) = (i % cols * args.width, i / cols * args.height);
+        paste(&mut image, &canvas.image(), ox, oy);
+
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        labels.push(Label {
+            x: ox + 2 * scale,
+            y: oy + 2 * scale,
+            text: name.into_owned(),
+            scale,
+        });
+    }
+
+    info!("Writing...");
+    save_image(&args.outfile, image, &post, &labels, args)
+}
+
+/// Render `n` consecutive sections of the frame range of one record into a labeled grid
+fn render_contact_sheet(path: &Path, n: usize, args: &Opt) -> Result<()> {
+    info!("Loading {}...", path.display());
+    let record = RecordFile::load(path)?;
+    let post = PostProcess {
+        background: None,
+        lut: match &args.lut {
+            Some(path) => Some(Lut::load(path)?),
+            None => None,
+        },
+    };
+
+    let cols = (n as f32).sqrt().ceil() as usize;
+    let rows = (n + cols - 1) / cols;
+    let scale = (args.width / 200).max(1);
+    let dt = args
+        .dt
+        .or_else(|| record.meta.as_ref().map(|m| m.dt))
+        .unwrap_or(0.5);
+
+    let range = frame_range(args, record.frames.len());
+    let (first, len) = (range.start, range.len());
+    let mut image = Array2D::new(cols * args.width, rows * args.height);
+    let mut labels = vec![];
+    for i in 0..n {
+        let section = first + len * i / n..first + len * (i + 1) / n;
+        let last = section.end.saturating_sub(1);
+        let mut canvas = Canvas::new(args.width, args.height, args.blend, args.tileable);
+        render(&record, &mut canvas, section, &post, args)?;
+
+        let (ox, oy) = (i % cols * args.width, i / cols * args.height);
+        paste(&mut image, &canvas.image(), ox, oy);
+        labels.push(Label {
+            x: ox + 2 * scale,
+            y: oy + 2 * scale,
+            text: format!("frame {}  t={:.1}", last, last as f32 * dt),
+            scale,
+        });
+    }
+
+    info!("Writing...");
+    save_image(&args.outfile, image, &post, &labels, args)
+}
+
+/// Copy `tile` into `image` with its top left corner at (ox, oy)
+fn paste(image: &mut Array2D<Rgb>, tile: &Array2D<Rgb>, ox: usize, oy: usize) {
+    for y in 0..tile.height() {
+        for x in 0..tile.width() {
+            image[(ox + x, oy + y)] = tile[(x, y)];
         }
+    }
+}
+
+/// Text stamped onto the output after tone mapping, so it stays legible
+struct Label {
+    x: usize,
+    y: usize,
+    text: String,
+    scale: usize,
+}
+
+impl Label {
+    /// Draw white text on a black box
+    fn draw(&self, image: &mut Array2D<Rgb>) {
+        let pad = self.scale;
+        let w = text_width(&self.text, self.scale) + pad * 2;
+        let h = GLYPH_HEIGHT * self.scale + pad * 2;
+
+        let mut set = |x: usize, y: usize, color: Rgb| {
+            if x < image.width() && y < image.height() {
+                image[(x, y)] = color;
+            }
+        };
+
+        for y in 0..h {
+            for x in 0..w {
+                set(self.x + x, self.y + y, [0.; 3]);
+            }
+        }
+
+        draw_text(&self.text, self.scale, |x, y| {
+            set(self.x + pad + x, self.y + pad + y, [1.; 3])
+        });
+    }
+}
+
+/// Labels for --overlay describing the record at the given (fractional) frame, one per line.
+/// Empty without --overlay
+fn overlay(record: &RecordFile, frame: f32, args: &Opt) -> Vec<Label> {
+    if !args.overlay {
+        return vec![];
+    }
+
+    let idx = (frame.ceil() as usize).min(record.frames.len().saturating_sub(1));
+    let n = record.frames.get(idx).map_or(0, |f| f.slime.len());
+    let dt = args
+        .dt
+        .or_else(|| record.meta.as_ref().map(|m| m.dt))
+        .unwrap_or(0.5);
+    let mut lines = vec![format!("frame {}  t={:.1}  n={}", idx, frame * dt, n)];
+
+    if let Some(meta) = &record.meta {
+        let (agent, medium) = (&meta.config.agent, &meta.config.medium);
+        lines.push(format!(
+            "spread={} dist={} turn={} speed={} decay={} diffusion={}",
+            agent.sensor_spread,
+            agent.sensor_distance,
+            agent.turn_speed,
+            agent.move_speed,
+            medium.decay,
+            medium.diffusion
+        ));
+    }
+    lines.extend(args.overlay_text.clone());
+
+    let scale = (args.width / 250).max(1);
+    let line_height = (GLYPH_HEIGHT + 3) * scale;
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| Label {
+            x: 2 * scale,
+            y: 2 * scale + i * line_height,
+            text,
+            scale,
+        })
+        .collect()
+}
+
+/// Render the record (if any) along with resumed and merged buffers, and write the outputs
+fn render_outputs(args: &Opt) -> Result<()> {
+    let (mut canvas, resume_at) = match &args.resume {
+        Some(path) => {
+            let (canvas, next_frame) = Canvas::load(path, args)?;
+            (canvas, Some(next_frame))
+        }
+        None => (
+            Canvas::new(args.width, args.height, args.blend, args.tileable),
+            None,
+        ),
     };
 
-    let rec_center_x = record.width as f32 / 2.;
-    let rec_center_y = record.height as f32 / 2.;
+    for path in &args.merge {
+        let (other, _) = Canvas::load(path, args)?;
+        canvas.merge(&other);
+    }
+
+    let mut post = PostProcess {
+        background: match &args.background {
+            Some(path) => Some(resample(&load_rgb(path)?, args.width, args.height)),
+            None => None,
+        },
+        lut: match &args.lut {
+            Some(path) => Some(Lut::load(path)?),
+            None => None,
+        },
+    };
+
+    let mut labels = vec![];
+    if let Some(path) = &args.record {
+        info!("Loading...");
+        let record = RecordFile::load(path)?;
+
+        if args.medium_background {
+            let layer = medium_layer(&record, args)?;
+            post.background = Some(match post.background.take() {
+                Some(mut bg) => {
+                    for (px, &m) in bg.data_mut().iter_mut().zip(layer.data()) {
+                        *px = Blend::Screen.apply(*px, m);
+                    }
+                    bg
+                }
+                None => layer,
+            });
+        }
 
-    println!("Building SVG...");
-    for (idx, frame) in frames.into_iter().enumerate() {
-        if idx % 100 == 0 {
-            println!("{}/{}", idx, n_frames);
+        if let Some(path) = &args.crossfade {
+            let other = RecordFile::load(path)?;
+            return render_crossfade([&record, &other], &post, args);
         }
 
+        let range = frame_range(args, record.frames.len());
+        let start = resume_at.unwrap_or(range.start);
+        render(&record, &mut canvas, start..range.end, &post, args)?;
+
+        if let Some(idx) = range.end.checked_sub(1) {
+            labels = overlay(&record, idx as f32, args);
+        }
+    } else {
+        ensure!(
+            !args.merge.is_empty() || args.resume.is_some(),
+            "A record is required, unless writing out saved buffers with --merge or --resume"
+        );
+        ensure!(
+            !args.medium_background,
+            "--medium-background requires a record"
+        );
+    }
+
+    info!("Writing...");
+    save_image(&args.outfile, canvas.image(), &post, &labels, args)?;
+
+    Ok(())
+}
+
+/// Frames to render: --first-frame to --last-frame, or the part of that given by
+/// --frames-chunk. Chunks start on a multiple of --frame-step, so they fit together seamlessly
+fn frame_range(args: &Opt, n_frames: usize) -> Range<usize> {
+    let (first, last) = (args.first_frame, args.last_frame.unwrap_or(n_frames));
+    match args.frames_chunk {
+        Some(Chunk { index, count }) => {
+            let steps = (last.saturating_sub(first) + args.frame_step - 1) / args.frame_step;
+            let at = |i: usize| (first + steps * i / count * args.frame_step).min(last);
+            at(index)..at(index + 1)
+        }
+        None => first..last,
+    }
+}
+
+/// Plot the record's trails in the given frame range onto the canvas
+fn render(
+    record: &RecordFile,
+    canvas: &mut Canvas,
+    range: Range<usize>,
+    post: &PostProcess,
+    args: &Opt,
+) -> Result<()> {
+    let n_frames = record.frames.len();
+    let (start, last_frame) = (range.start, range.end);
+
+    let frames = record
+        .frames
+        .get(range)
+        .context("Frame range is outside of the record")?;
+    let first = record.frames.first().context("No frames :/")?;
+
+    // When picking up where a resumed buffer or an earlier chunk left off, continue the trails
+    // from the last frame that was plotted
+    let mut last = match start.checked_sub(args.frame_step) {
+        Some(prev) if start > args.first_frame => &record.frames[prev],
+        _ => first,
+    };
+    // The frame plotted before `last`, for spline interpolation
+    let mut before = last;
+
+    info!("Rendering frames {}..{} of {}", start, last_frame, n_frames);
+
+    let mut progress = Progress::new(frames.len(), args.verbosity.quiet());
+    for (idx, frame) in frames.iter().enumerate() {
+        progress.set(idx);
+
         if idx % args.frame_step != 0 {
             continue;
         }
 
-        for (part, prev) in frame.slime.iter().zip(&last.slime) {
-            if part.age != 0 {
-                let x_center_off: f32 = part.origin.x - rec_center_x;
-                let y_center_off: f32 = part.origin.y - rec_center_y;
-                let angle = y_center_off.atan2(x_center_off) + PI;
+        // Counts processed frames from --first-frame, across resumes
+        let step = (start + idx).saturating_sub(args.first_frame) / args.frame_step;
 
-                let color = if angle > 2. * TAU / 3. {
-                    [0xff, 0xcf, 0x00]
-                } else if angle > TAU / 3. {
-                    [0x00, 0xa9, 0xff]
-                } else {
-                    [0xff, 0x00, 0x88]
-                };
+        let after = record
+            .frames
+            .get(start + idx + args.frame_step)
+            .unwrap_or(frame);
+        let span = Span::new(record, [before, last, frame, after]);
 
-                let color = color.map(|v| v as f32 / 256.);
+        // With --interpolate, each recorded frame is split into several sub-frames
+        let parts = args.interpolate + 1;
+        for sub in 0..parts {
+            if args.decay < 1. {
+                canvas.decay(args.decay.powf(1. / parts as f32));
+            }
 
-                let color = |b: f32| color.map(|v| v * b);
+            let (t0, t1) = (sub as f32 / parts as f32, (sub + 1) as f32 / parts as f32);
+            let fresh = freshness(args, n_frames, start + idx);
+            plot_span(canvas, record, &span, (t0, t1), fresh, args);
 
-                let (x0, y0) = coord_map(prev.position);
-                let (x1, y1) = coord_map(part.position);
-                draw_line(x0, y0, x1, y1, |x, y, b| plot_point(x, y, color(b)));
+            if let Some(every) = args.sequence {
+                if step % every == 0 {
+                    let mut out = canvas.clone();
+                    if args.draw_particles {
+                        plot_particles(&mut out, record, &span, t1, args);
+                    }
+
+                    let at = (start + idx) as f32 - (1. - t1) * args.frame_step as f32;
+                    let labels = overlay(record, at, args);
+                    let path = numbered_path(&args.outfile, step / every * parts + sub);
+                    save_image(&path, out.image(), post, &labels, args)?;
+                }
             }
         }
 
+        before = last;
         last = frame;
+
+        if let Some((path, every)) = args.save_buffer.as_ref().zip(args.checkpoint_every) {
+            if (step + 1) % every == 0 {
+                canvas.save(path, start + idx + args.frame_step)?;
+            }
+        }
     }
 
-    println!("Writing...");
-    let data = rgb8_image(&image);
-    write_png(&args.outfile, &data, args.width as _, args.height as _)?;
+    progress.finish();
+
+    if let Some(path) = &args.save_buffer {
+        canvas.save(path, last_frame.max(start))?;
+    }
 
     Ok(())
 }
 
+/// Render both records in lockstep, writing --sequence frames that fade from the first one's
+/// trails to the second's over the frame range. The final frame goes to --outfile
+fn render_crossfade(records: [&RecordFile; 2], post: &PostProcess, args: &Opt) -> Result<()> {
+    let every = args.sequence.context("--crossfade needs --sequence")?;
+    let n_frames = records[0].frames.len().min(records[1].frames.len());
+    let last_frame = args.last_frame.unwrap_or(n_frames).min(n_frames);
+    ensure!(
+        args.first_frame < last_frame,
+        "Frame range is outside of the records"
+    );
+
+    let new_canvas = || Canvas::new(args.width, args.height, args.blend, args.tileable);
+    let mut canvases = [new_canvas(), new_canvas()];
+    let mut faded = new_canvas().image();
+
+    let frames: Vec<usize> = (args.first_frame..last_frame)
+        .step_by(args.frame_step)
+        .collect();
+
+    let mut labels = vec![];
+    let mut progress = Progress::new(frames.len(), args.verbosity.quiet());
+    for (step, &idx) in frames.iter().enumerate() {
+        progress.set(step);
+
+        for (canvas, record) in canvases.iter_mut().zip(records) {
+            if args.decay < 1. {
+                canvas.decay(args.decay);
+            }
+
+            let last = match step {
+                0 => &record.frames[0],
+                _ => &record.frames[idx - args.frame_step],
+            };
+            let frames = [last, last, &record.frames[idx], &record.frames[idx]];
+            let span = Span::new(record, frames);
+            let fresh = freshness(args, n_frames, idx);
+            plot_span(canvas, record, &span, (0., 1.), fresh, args);
+        }
+
+        let is_last = step + 1 == frames.len();
+        if step % every == 0 || is_last {
+            labels = overlay(records[0], idx as f32, args);
+            let weight = step as f32 / (frames.len() - 1).max(1) as f32;
+            let [a, b] = [canvases[0].image(), canvases[1].image()];
+            for ((out, a), b) in faded.data_mut().iter_mut().zip(a.data()).zip(b.data()) {
+                *out = [0, 1, 2].map(|i| a[i] * (1. - weight) + b[i] * weight);
+            }
+        }
+
+        if step % every == 0 {
+            let path = numbered_path(&args.outfile, step / every);
+            save_image(&path, faded.clone(), post, &labels, args)?;
+        }
+    }
+
+    progress.finish();
+
+    save_image(&args.outfile, faded, post, &labels, args)
+}
+
+/// Region of the record shown in the output as x, y, width and height, from --crop and
+/// --zoom
+fn view_rect(record: &RecordFile, args: &Opt) -> [f32; 4] {
+    let [x, y, w, h] = match args.crop[..] {
+        [x, y, w, h] => [x, y, w, h],
+        _ => [0., 0., record.width as f32, record.height as f32],
+    };
+    let (zw, zh) = (w / args.zoom, h / args.zoom);
+    [x + (w - zw) / 2., y + (h - zh) / 2., zw, zh]
+}
+
+/// Map a position in the record to a position in the output, in pixels
+fn coord_map(record: &RecordFile, args: &Opt, v: Vector2<f32>) -> (f32, f32) {
+    let [x, y, w, h] = view_rect(record, args);
+    let (u, v) = args.remap.apply((v.x - x) / w, (v.y - y) / h);
+    (u * args.width as f32, v * args.height as f32)
+}
+
+/// Clip the segment to the given rectangle (Liang-Barsky), or return None if it lies outside
+fn clip_line(
+    (x0, y0): (f32, f32),
+    (x1, y1): (f32, f32),
+    (w, h): (f32, f32),
+) -> Option<((f32, f32), (f32, f32))> {
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let (mut t0, mut t1) = (0f32, 1f32);
+
+    for (p, q) in [(-dx, x0), (dx, w - x0), (-dy, y0), (dy, h - y0)] {
+        if p == 0. {
+            if q < 0. {
+                return None;
+            }
+            continue;
+        }
+
+        let t = q / p;
+        if p < 0. {
+            t0 = t0.max(t);
+        } else {
+            t1 = t1.min(t);
+        }
+    }
+
+    (t0 <= t1).then(|| ((x0 + t0 * dx, y0 + t0 * dy), (x0 + t1 * dx, y0 + t1 * dy)))
+}
+
+/// Particles around the step from one plotted frame to the next, matched up by id. Holds the
+/// frames before and after too, as control points for spline interpolation
+struct Span<'a> {
+    /// Size of the record, around whose edges particles wrap
+    size: Vector2<f32>,
+    frame: &'a RecordFrame,
+    before: HashMap<u32, &'a SlimeParticle>,
+    last: HashMap<u32, &'a SlimeParticle>,
+    after: HashMap<u32, &'a SlimeParticle>,
+}
+
+impl<'a> Span<'a> {
+    /// From the frames before, at the start of, at the end of and after the step
+    fn new(record: &RecordFile, [before, last, frame, after]: [&'a RecordFrame; 4]) -> Self {
+        Self {
+            size: Vector2::new(record.width as f32, record.height as f32),
+            frame,
+            before: by_id(before),
+            last: by_id(last),
+            after: by_id(after),
+        }
+    }
+
+    /// Every particle present at both ends of the step, along with its four control points.
+    /// Particles missing from the outer frames reuse the inner ones. Control points are moved
+    /// across the edges the particle wrapped around, so they are continuous with the start of
+    /// the step
+    fn particles(&self) -> impl Iterator<Item = Trajectory<'a>> + '_ {
+        self.frame.slime.iter().filter_map(move |part| {
+            let prev = *self.last.get(&part.id)?;
+            let p0 = self
+                .before
+                .get(&part.id)
+                .map_or(prev.position, |p| p.position);
+            let p3 = self
+                .after
+                .get(&part.id)
+                .map_or(part.position, |p| p.position);
+            let p1 = prev.position;
+            let p0 = p1 + self.unwrap(p0 - p1);
+            let p2 = p1 + self.unwrap(part.position - p1);
+            let p3 = p2 + self.unwrap(p3 - part.position);
+            Some((part, [p0, p1, p2, p3]))
+        })
+    }
+
+    /// Shortest equivalent of the offset `d` on the torus
+    fn unwrap(&self, d: Vector2<f32>) -> Vector2<f32> {
+        Vector2::new(wrap_delta(d.x, self.size.x), wrap_delta(d.y, self.size.y))
+    }
+}
+
+/// A particle at the end of a span, and its four interpolation control points
+type Trajectory<'a> = (&'a SlimeParticle, [Vector2<f32>; 4]);
+
+/// Index a frame's particles by id, since particles may be born or die between frames
+fn by_id(frame: &RecordFrame) -> HashMap<u32, &SlimeParticle> {
+    frame.slime.iter().map(|p| (p.id, p)).collect()
+}
+
+/// Position between `points[1]` (at t = 0) and `points[2]` (at t = 1), linearly or along a
+/// Catmull-Rom spline through all four points
+fn interpolate(points: [Vector2<f32>; 4], t: f32, spline: bool) -> Vector2<f32> {
+    let [p0, p1, p2, p3] = points;
+    if !spline {
+        return p1 + (p2 - p1) * t;
+    }
+
+    let (t2, t3) = (t * t, t * t * t);
+    (p1 * 2.
+        + (p2 - p0) * t
+        + (p0 * 2. - p1 * 5. + p2 * 4. - p3) * t2
+        + (p1 * 3. - p0 - p2 * 3. + p3) * t3)
+        * 0.5
+}
+
+/// Where record frame `idx` lies in the whole frame range (ignoring --frames-chunk, so that
+/// chunks agree), from 0 at the first frame to 1 at the last
+fn freshness(args: &Opt, n_frames: usize, idx: usize) -> f32 {
+    let first = args.first_frame;
+    let last = args.last_frame.unwrap_or(n_frames).min(n_frames);
+    idx.saturating_sub(first) as f32 / last.saturating_sub(first + 1).max(1) as f32
+}
+
+/// Plot the part of every particle's trail from `t0` to `t1` along the span. `freshness` is
+/// that of the span's frame, for --color-by-age
+fn plot_span(
+    canvas: &mut Canvas,
+    record: &RecordFile,
+    span: &Span,
+    (t0, t1): (f32, f32),
+    freshness: f32,
+    args: &Opt,
+) {
+    for (part, points) in span.particles() {
+        if part.age == 0 {
+            continue;
+        }
+
+        let color = if args.color_by_velocity {
+            velocity_color(points[2] - points[1], args.max_speed)
+        } else if args.color_by_age {
+            age_color(freshness)
+        } else if args.color_by_state {
+            match part.state {
+                AgentState::Exploring => [0x00, 0xa9, 0xff],
+                AgentState::Exploiting => [0xff, 0xcf, 0x00],
+                AgentState::Homing => [0xff, 0x00, 0x88],
+            }
+            .map(|v| v as f32 / 256.)
+        } else {
+            group_color(part.group)
+        };
+
+        let color = color.map(|v| v * args.intensity);
+
+        let (mut x0, mut y0) = coord_map(record, args, interpolate(points, t0, args.spline));
+        let (x1, y1) = coord_map(record, args, interpolate(points, t1, args.spline));
+        if args.tileable {
+            // Take the short way around, so jumps across an edge stay short segments
+            x0 = x1 + wrap_delta(x0 - x1, args.width as f32);
+            y0 = y1 + wrap_delta(y0 - y1, args.height as f32);
+            draw_line(x0, y0, x1, y1, |x, y, b| canvas.plot(x, y, color, b));
+        } else {
+            // A pixel of margin, so antialiased edges at the border are kept
+            let bounds = (args.width as f32 + 2., args.height as f32 + 2.);
+            let shifted = ((x0 + 1., y0 + 1.), (x1 + 1., y1 + 1.));
+            if let Some(((x0, y0), (x1, y1))) = clip_line(shifted.0, shifted.1, bounds) {
+                draw_line(x0 - 1., y0 - 1., x1 - 1., y1 - 1., |x, y, b| {
+                    canvas.plot(x, y, color, b)
+                });
+            }
+        }
+    }
+}
+
+/// Mark each particle's position at `t` along the span
+fn plot_particles(canvas: &mut Canvas, record: &RecordFile, span: &Span, t: f32, args: &Opt) {
+    for (_, points) in span.particles() {
+        let p = interpolate(points, t, args.spline);
+        let p = Vector2::new(
+            p.x.rem_euclid(record.width as f32),
+            p.y.rem_euclid(record.height as f32),
+        );
+        let (x, y) = coord_map(record, args, p);
+        match args.particle_size {
+            Some(radius) => canvas.plot_disc(x, y, radius, [1.; 3]),
+            None => canvas.plot(x as i32, y as i32, [1.; 3], 1.),
+        }
+    }
+}
+
+/// The recorded medium chosen by --medium-frame, colored and resampled to the output size
+fn medium_layer(record: &RecordFile, args: &Opt) -> Result<Array2D<Rgb>> {
+    let medium = match args.medium_frame {
+        Some(idx) => record
+            .frames
+            .get(idx)
+            .context("--medium-frame is past the end of the record")?
+            .medium
+            .as_ref()
+            .context("The frame given by --medium-frame has no medium recorded")?,
+        None => record
+            .frames
+            .iter()
+            .rev()
+            .find_map(|f| f.medium.as_ref())
+            .context("No medium in this record; record with --record-medium-every")?,
+    };
+
+    let color = [
+        args.medium_color[0],
+        args.medium_color[1],
+        args.medium_color[2],
+    ];
+    let data = medium
+        .iter()
+        .map(|&v| color.map(|c| c * v.clamp(0., 1.)))
+        .collect();
+
+    let layer = Array2D::from_array(record.width, data);
+    Ok(resample(&layer, args.width, args.height))
+}
+
+/// Hue from the direction of `delta`, brightness from its length relative to `max_speed`
+fn velocity_color(delta: Vector2<f32>, max_speed: f32) -> Rgb {
+    let hue = (delta.y.atan2(delta.x) + PI) / TAU;
+    let value = (delta.norm() / max_speed).min(1.);
+    hsv_to_rgb(hue, 1., value)
+}
+
+/// Hue, saturation and value all in 0..1
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> Rgb {
+    let h = h.rem_euclid(1.) * 6.;
+    let f = h.fract();
+    let (p, q, t) = (v * (1. - s), v * (1. - s * f), v * (1. - s * (1. - f)));
+    match h as u32 {
+        0 => [v, t, p],
+        1 => [q, v, p],
+        2 => [p, v, t],
+        3 => [p, q, v],
+        4 => [t, p, v],
+        _ => [v, p, q],
+    }
+}
+
+/// `out.png` becomes `out_00042.png`
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_{:05}.{}", stem, n, ext))
+}
+
+/// Layers applied to the float buffer when writing it out
+struct PostProcess {
+    /// Resampled to the output size
+    background: Option<Array2D<Rgb>>,
+    lut: Option<Lut>,
+}
+
+/// Tone map, recolor, composite over the background (if any), stamp labels and write in the
+/// requested format
+fn save_image(
+    path: &Path,
+    mut image: Array2D<Rgb>,
+    post: &PostProcess,
+    labels: &[Label],
+    args: &Opt,
+) -> Result<()> {
+    if args.equalize {
+        equalize(&mut image);
+    }
+
+    let exposure = 2f32.powf(args.exposure);
+    for px in image.data_mut() {
+        *px = px.map(|v| (v * exposure).max(0.).powf(1. / args.gamma));
+    }
+
+    if let Some(lut) = &post.lut {
+        for px in image.data_mut() {
+            *px = lut.sample(px.iter().copied().fold(0., f32::max));
+        }
+    }
+
+    if let Some(background) = &post.background {
+        for (px, &bg) in image.data_mut().iter_mut().zip(background.data()) {
+            *px = args.background_blend.apply(bg, *px);
+        }
+    }
+
+    for label in labels {
+        label.draw(&mut image);
+    }
+
+    let (data, color) = if args.transparent {
+        (rgba8_image(&image), png::ColorType::Rgba)
+    } else {
+        (rgb8_image(&image), png::ColorType::Rgb)
+    };
+    write_png(path, &data, image.width() as _, image.height() as _, color)
+}
+
+/// Remap brightness so that the lit pixels are evenly distributed over 0..1, keeping hue.
+/// Black pixels are left out of the histogram, since they usually dominate it
+fn equalize(image: &mut Array2D<Rgb>) {
+    const BINS: usize = 4096;
+
+    let brightness = |px: &Rgb| px.iter().copied().fold(0., f32::max);
+    let max = image.data().iter().map(brightness).fold(0., f32::max);
+    if max <= 0. {
+        return;
+    }
+
+    let bin = |v: f32| ((v / max * BINS as f32) as usize).min(BINS - 1);
+
+    let mut cdf = vec![0usize; BINS];
+    for px in image.data() {
+        let v = brightness(px);
+        if v > 0. {
+            cdf[bin(v)] += 1;
+        }
+    }
+    let mut total = 0;
+    for count in &mut cdf {
+        total += *count;
+        *count = total;
+    }
+    let total = total as f32;
+
+    for px in image.data_mut() {
+        let v = brightness(px);
+        if v > 0. {
+            let target = cdf[bin(v)] as f32 / total;
+            *px = px.map(|c| c * target / v);
+        }
+    }
+}
+
 /// Convert the given floating point image data to RGB8
 fn rgb8_image(image: &Array2D<Rgb>) -> Vec<u8> {
     image
@@ -131,13 +1369,34 @@ fn rgb8_image(image: &Array2D<Rgb>) -> Vec<u8> {
         .collect()
 }
 
-/// Write a grayscale PNG at the given path
-fn write_png(path: &Path, data: &[u8], width: u32, height: u32) -> Result<()> {
+/// Convert the given floating point image data to RGBA8, using the brightest channel as alpha
+/// and un-premultiplying the color by it
+fn rgba8_image(image: &Array2D<Rgb>) -> Vec<u8> {
+    image
+        .data()
+        .iter()
+        .map(|rgb| {
+            let alpha = rgb.iter().copied().fold(0., f32::max).min(1.);
+            let color = rgb.map(|x| if alpha > 0. { x / alpha } else { 0. });
+            [color[0], color[1], color[2], alpha].map(|x| (x.clamp(0., 1.) * 256.) as u8)
+        })
+        .flatten()
+        .collect()
+}
+
+/// Write a PNG of the given color type at the given path
+fn write_png(
+    path: &Path,
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+) -> Result<()> {
     let file = File::create(path)?;
     let ref mut w = BufWriter::new(file);
 
     let mut encoder = png::Encoder::new(w, width, height);
-    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_color(color);
     encoder.set_depth(png::BitDepth::Eight);
 
     let mut writer = encoder.write_header()?;
@@ -0,0 +1,1201 @@
+use anyhow::{bail, ensure, Context, Result};
+use idek_basics::Array2D;
+use nalgebra::Vector3;
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::prelude::*;
+use slime::{
+    contour::contours,
+    export::{
+        scale_layers, space_time_points, trajectory_layers, write_dxf, write_obj, write_ply,
+        write_stl, write_svg, write_usda, write_xyz, Layer, PointColor, Units,
+    },
+    image::downsample,
+    init::InitParticles,
+    isosurface::{isosurface, Triangle},
+    logging::{self, Verbosity},
+    lut::Lut,
+    metrics::{measure, METRICS},
+    npz::write_record_npz,
+    palette::group_color,
+    preset::{commented_toml, preset, PRESETS},
+    progress::Progress,
+    record::{record_frame, Overflow, RecordBudget, RecordFile, RecordMeta},
+    render::{thumbnail, trail_image, THUMBNAIL_SIZE},
+    sim::*,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+use structopt::{clap::ArgMatches, StructOpt};
+use tracing::{debug, info, info_span, warn};
+
+/// Utilities for working with sim configs, and for running the sim without a window
+#[derive(Debug, StructOpt)]
+enum Opt {
+    /// Write a commented config file with every parameter, starting from a preset
+    InitConfig {
+        /// Preset to start from. Leave out to list them
+        preset: Option<String>,
+
+        /// File to write. Defaults to stdout
+        #[structopt(short = "o", long)]
+        output: Option<PathBuf>,
+    },
+    /// Run the sim headless for a fixed number of frames, e.g. overnight
+    Run(RunOpt),
+    /// Run the same config with several seeds in parallel, and summarize how their metrics
+    /// vary over time
+    Ensemble(EnsembleOpt),
+    /// Write out the thumbnails embedded in records, to browse them without rendering each one
+    Thumbnail {
+        /// Records to take the thumbnails of
+        #[structopt(required = true)]
+        records: Vec<PathBuf>,
+
+        /// Directory for the thumbnails. Defaults to next to each record, as <name>.thumb.png
+        #[structopt(short = "o", long)]
+        output: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        verbosity: Verbosity,
+    },
+    /// Trace the outlines of the trail network at some densities, as vector lines
+    Contours(ContourOpt),
+    /// Write the path of every particle through a record as vector lines
+    Paths {
+        /// Record to take the paths from
+        record: PathBuf,
+
+        /// File to write, as SVG or DXF by its extension
+        #[structopt(short = "o", long, default_value = "paths.dxf")]
+        output: PathBuf,
+
+        #[structopt(flatten)]
+        sheet: SheetOpt,
+
+        #[structopt(flatten)]
+        verbosity: Verbosity,
+    },
+    /// Write the particles of a record as a 3D point cloud, with time as the third axis
+    Points(PointsOpt),
+    /// Stack the recorded medium up along time into a solid, and write its surface as a mesh,
+    /// e.g. for 3D printing
+    Mesh(MeshOpt),
+    /// Write the particles of a record as an animated USD point cache, for Blender or Houdini
+    PointCache(PointCacheOpt),
+    /// Re-run a seeded simulation and check it step by step against a reference record, to
+    /// catch changes that alter the sim's behavior
+    Verify(VerifyOpt),
+    /// Bundle a record into one NumPy .npz: particles, media, metrics and config
+    Npz {
+        /// Record to bundle
+        record: PathBuf,
+
+        /// File to write. Defaults to the record's name, as .npz
+        #[structopt(short = "o", long)]
+        output: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        verbosity: Verbosity,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct VerifyOpt {
+    #[structopt(short = "t", long, default_value = "0.5")]
+    dt: f32,
+
+    #[structopt(short = "w", long, default_value = "400")]
+    width: usize,
+
+    #[structopt(short = "h", long, default_value = "400")]
+    height: usize,
+
+    #[structopt(short = "n", long, default_value = "4000")]
+    n_particles: usize,
+
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    /// Number of frames to simulate and compare
+    #[structopt(long, default_value = "500")]
+    frames: usize,
+
+    /// Reference record
+    #[structopt(long)]
+    against: PathBuf,
+
+    /// Write the reference record instead of checking against it
+    #[structopt(long)]
+    bless: bool,
+
+    /// Largest difference in position or heading that still counts as a match
+    #[structopt(long, default_value = "0")]
+    tolerance: f32,
+
+    /// Read the sim parameters from this TOML file. Flags given on the command line override it
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    // The starting population the reference was made from, if it wasn't the random one
+    #[structopt(flatten)]
+    init: InitParticles,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+
+    #[structopt(flatten)]
+    cfg: SlimeConfig,
+}
+
+#[derive(Debug, StructOpt)]
+struct PointCacheOpt {
+    /// Record to take the particles from
+    record: PathBuf,
+
+    /// File to write, as ASCII USD
+    #[structopt(short = "o", long, default_value = "points.usda")]
+    output: PathBuf,
+
+    /// Only take every Nth frame
+    #[structopt(long, default_value = "1")]
+    every: usize,
+
+    /// Frames per second of the animation
+    #[structopt(long, default_value = "30")]
+    fps: f32,
+
+    /// Size of a grid cell in the scene
+    #[structopt(long, default_value = "0.01")]
+    cell_size: f32,
+
+    /// Width of the points, in cells
+    #[structopt(long, default_value = "0.5")]
+    point_width: f32,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(Debug, StructOpt)]
+struct MeshOpt {
+    /// Record to take the medium from. Needs one made with --record-medium-every
+    record: PathBuf,
+
+    /// File to write, as STL or OBJ by its extension
+    #[structopt(short = "o", long, default_value = "mesh.stl")]
+    output: PathBuf,
+
+    /// Density at the surface. Must be positive
+    #[structopt(long, default_value = "0.5")]
+    threshold: f32,
+
+    /// Only take every Nth frame that has the medium
+    #[structopt(long, default_value = "1")]
+    every: usize,
+
+    /// Height of each slice of medium along the time axis, in cells
+    #[structopt(long, default_value = "1.0")]
+    time_scale: f32,
+
+    /// Shrink the medium by this factor first, for a smaller mesh
+    #[structopt(long, default_value = "1")]
+    downsample: usize,
+
+    /// Size of a grid cell in the output, e.g. in millimeters for printing
+    #[structopt(long, default_value = "1.0")]
+    cell_size: f32,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(Debug, StructOpt)]
+struct PointsOpt {
+    /// Record to take the particles from
+    record: PathBuf,
+
+    /// File to write, as PLY or XYZ by its extension
+    #[structopt(short = "o", long, default_value = "points.ply")]
+    output: PathBuf,
+
+    /// Height of each frame along the time axis, in cells
+    #[structopt(long, default_value = "1.0")]
+    time_scale: f32,
+
+    /// Only take every Nth frame
+    #[structopt(long, default_value = "1")]
+    every: usize,
+
+    /// Color the points by group, state or time
+    #[structopt(long, default_value = "group")]
+    color: PointColor,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(Debug, StructOpt)]
+struct EnsembleOpt {
+    /// Number of runs. Run k is seeded with `seed + k`
+    #[structopt(long, default_value = "8")]
+    runs: u64,
+
+    /// Directory for the metrics of each run and their summary
+    #[structopt(long, default_value = "ensemble")]
+    output: PathBuf,
+
+    #[structopt(flatten)]
+    run: RunOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ContourOpt {
+    /// Record to trace
+    record: PathBuf,
+
+    /// File to write, as SVG or DXF by its extension
+    #[structopt(short = "o", long, default_value = "contours.svg")]
+    output: PathBuf,
+
+    /// Densities to trace, one layer each, comma separated
+    #[structopt(long, use_delimiter = true, default_value = "0.25,0.5")]
+    thresholds: Vec<f32>,
+
+    /// Frame whose medium is traced. Defaults to the last one that has it. Records without
+    /// the medium (see --record-medium-every) are traced from where the particles went
+    /// instead, scaled to 0..1
+    #[structopt(long)]
+    frame: Option<usize>,
+
+    #[structopt(flatten)]
+    sheet: SheetOpt,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+}
+
+/// Size and units of vector exports
+#[derive(Debug, StructOpt)]
+struct SheetOpt {
+    /// Unit of the coordinates: none, mm, cm, m or in
+    #[structopt(long, default_value = "mm")]
+    units: Units,
+
+    /// Size of a grid cell, in --units
+    #[structopt(long, default_value = "1.0")]
+    cell_size: f32,
+
+    /// Width of the lines in SVGs, in --units. DXF lines have no width
+    #[structopt(long, default_value = "0.5")]
+    stroke_width: f32,
+}
+
+#[derive(Debug, StructOpt)]
+struct RunOpt {
+    #[structopt(short = "t", long, default_value = "0.5")]
+    dt: f32,
+
+    #[structopt(short = "w", long, default_value = "400")]
+    width: usize,
+
+    #[structopt(short = "h", long, default_value = "400")]
+    height: usize,
+
+    #[structopt(short = "n", long, default_value = "4000")]
+    n_particles: usize,
+
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    /// Number of frames to simulate
+    #[structopt(long, default_value = "1000")]
+    frames: usize,
+
+    #[structopt(long, default_value = "1")]
+    steps_per_frame: usize,
+
+    /// Resize the grid part way through, as FRAME:WIDTHxHEIGHT, e.g. to explore at a low
+    /// resolution and finish at a high one. Can't be combined with --record
+    #[structopt(long)]
+    resize: Option<Resize>,
+
+    /// Record the run to this file, for the png binary
+    #[structopt(long)]
+    record: Option<PathBuf>,
+
+    /// Also store the medium in every Nth recorded frame
+    #[structopt(long)]
+    record_medium_every: Option<usize>,
+
+    /// Save the record so far every this many frames, instead of only at the end
+    #[structopt(long)]
+    autosave_every: Option<usize>,
+
+    /// Limit the memory used by the record, in megabytes
+    #[structopt(long)]
+    record_max_mb: Option<f32>,
+
+    /// What to do when the record reaches --record-max-mb: stop recording, or thin out the
+    /// frames and record less often
+    #[structopt(long, default_value = "thin")]
+    record_overflow: Overflow,
+
+    /// Write measurements of the sim (see the metrics module) to this CSV file
+    #[structopt(long)]
+    metrics: Option<PathBuf>,
+
+    /// Take measurements every this many frames
+    #[structopt(long, default_value = "10")]
+    metrics_every: usize,
+
+    /// Save an image of the medium every this many frames
+    #[structopt(long)]
+    snapshot_every: Option<usize>,
+
+    /// Path of each snapshot. {seed}, {frame} and any parameter name (e.g. {decay}) are
+    /// replaced by their value, with an optional width and precision as in {frame:06} or
+    /// {decay:.3}
+    #[structopt(long, default_value = "run_{seed}_{frame:06}.png")]
+    snapshot_template: String,
+
+    /// Color lookup table for the snapshots, as a .cube file or a gradient PNG
+    #[structopt(long)]
+    lut: Option<PathBuf>,
+
+    /// Write a Chrome trace of the time spent in each phase of the sim to this file. Needs the
+    /// profile feature
+    #[structopt(long)]
+    profile: Option<PathBuf>,
+
+    /// Decay and diffuse the medium on the GPU, for big grids. Needs the gpu feature
+    #[structopt(long)]
+    gpu: bool,
+
+    #[structopt(flatten)]
+    init: InitParticles,
+
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+
+    /// Read the sim parameters from this TOML file. Flags given on the command line override it
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    cfg: SlimeConfig,
+}
+
+/// A change of grid size at a given frame
+#[derive(Debug)]
+struct Resize {
+    frame: usize,
+    width: usize,
+    height: usize,
+}
+
+impl FromStr for Resize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = || {
+            let (frame, size) = s.split_once(':')?;
+            let (width, height) = size.split_once('x')?;
+            Some(Self {
+                frame: frame.trim().parse().ok()?,
+                width: width.trim().parse().ok()?,
+                height: height.trim().parse().ok()?,
+            })
+        };
+        match parse() {
+            Some(resize) if resize.width > 0 && resize.height > 0 => Ok(resize),
+            _ => bail!(
+                "Expected FRAME:WIDTHxHEIGHT, e.g. 1000:1600x1600, not \"{}\"",
+                s
+            ),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = Opt::clap().get_matches();
+    match Opt::from_clap(&matches) {
+        Opt::InitConfig { preset, output } => init_config(preset, output),
+        Opt::Run(args) => {
+            let matches = matches
+                .subcommand_matches("run")
+                .context("Missing run args")?;
+            run(args, matches)
+        }
+        Opt::Ensemble(args) => {
+            let matches = matches
+                .subcommand_matches("ensemble")
+                .context("Missing ensemble args")?;
+            ensemble(args, matches)
+        }
+        Opt::Thumbnail {
+            records,
+            output,
+            verbosity,
+        } => {
+            logging::init(&verbosity, None)?;
+            extract_thumbnails(&records, output.as_deref())
+        }
+        Opt::Contours(args) => {
+            logging::init(&args.verbosity, None)?;
+            export_contours(&args)
+        }
+        Opt::Paths {
+            record,
+            output,
+            sheet,
+            verbosity,
+        } => {
+            logging::init(&verbosity, None)?;
+            let record = RecordFile::load(&record)
+                .with_context(|| format!("Failed to load {}", record.display()))?;
+            write_layers(&output, &record, &sheet, trajectory_layers(&record))
+        }
+        Opt::Points(args) => {
+            logging::init(&args.verbosity, None)?;
+            export_points(&args)
+        }
+        Opt::Mesh(args) => {
+            logging::init(&args.verbosity, None)?;
+            export_mesh(&args)
+        }
+        Opt::PointCache(args) => {
+            logging::init(&args.verbosity, None)?;
+            export_point_cache(&args)
+        }
+        Opt::Verify(args) => {
+            let matches = matches
+                .subcommand_matches("verify")
+                .context("Missing verify args")?;
+            verify(args, matches)
+        }
+        Opt::Npz {
+            record: path,
+            output,
+            verbosity,
+        } => {
+            logging::init(&verbosity, None)?;
+            let record = RecordFile::load(&path)
+                .with_context(|| format!("Failed to load {}", path.display()))?;
+            let output = output.unwrap_or_else(|| path.with_extension("npz"));
+            let file = File::create(&output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            write_record_npz(BufWriter::new(file), &record)?;
+            info!("Wrote {}", output.display());
+            Ok(())
+        }
+    }
+}
+
+fn verify(mut args: VerifyOpt, matches: &ArgMatches) -> Result<()> {
+    logging::init(&args.verbosity, None)?;
+    if let Some(path) = &args.config {
+        args.cfg = SlimeConfig::load(path)?.with_overrides(&args.cfg, matches)?;
+    }
+
+    let reference = if args.bless {
+        None
+    } else {
+        Some(check_reference(&args)?)
+    };
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut sim = SlimeSim::new(args.width, args.height, args.n_particles, &mut rng);
+    // Applied as `slime run` does, so that its records can be checked
+    args.init.apply(&mut sim, &mut rng)?;
+    let mut record = RecordFile::new(args.width, args.height);
+    record.meta = Some(RecordMeta {
+        config: args.cfg.clone(),
+        dt: args.dt,
+        thumbnail: None,
+    });
+    for _ in 0..args.frames {
+        record_frame(&mut record, &sim, false);
+        sim.step(&args.cfg, args.dt, &mut rng);
+    }
+
+    let reference = match reference {
+        Some(reference) => reference,
+        None => {
+            record.save(&args.against)?;
+            info!("Wrote {} frames to {}", args.frames, args.against.display());
+            return Ok(());
+        }
+    };
+
+    // Records don't say how their particles started out
+    let init_hint = "The reference starts from different particles; pass the --init-particles or \
+                     --init-from-record it was made with";
+    for (idx, (ours, theirs)) in record.frames.iter().zip(&reference.frames).enumerate() {
+        if ours.slime.len() != theirs.slime.len() {
+            ensure!(idx > 0, init_hint);
+            bail!(
+                "Diverged at frame {}: {} particles, but the reference has {}",
+                idx,
+                ours.slime.len(),
+                theirs.slime.len()
+            );
+        }
+
+        for (a, b) in ours.slime.iter().zip(&theirs.slime) {
+            let off = (a.position - b.position)
+                .norm()
+                .max((a.heading - b.heading).norm());
+            if a.id != b.id || off > args.tolerance {
+                ensure!(idx > 0, init_hint);
+                bail!(
+                    "Diverged at frame {}: particle {} is at {:?} heading {:?}, but the reference \
+                     has particle {} at {:?} heading {:?}",
+                    idx,
+                    a.id,
+                    a.position,
+                    a.heading,
+                    b.id,
+                    b.position,
+                    b.heading
+                );
+            }
+        }
+    }
+
+    info!("{} frames match", args.frames);
+    Ok(())
+}
+
+/// Load the reference record of `slime verify`, and check that it was made the same way, so
+/// that a mismatched config isn't reported as a divergence
+fn check_reference(args: &VerifyOpt) -> Result<RecordFile> {
+    let reference = RecordFile::load(&args.against)
+        .with_context(|| format!("Failed to load {}", args.against.display()))?;
+    ensure!(
+        (reference.width, reference.height) == (args.width, args.height),
+        "Reference is {}x{}, but the sim is {}x{}",
+        reference.width,
+        reference.height,
+        args.width,
+        args.height
+    );
+    ensure!(
+        reference.frames.len() >= args.frames,
+        "Reference only has {} frames",
+        reference.frames.len()
+    );
+
+    let meta = match &reference.meta {
+        Some(meta) => meta,
+        None => {
+            warn!("The reference doesn't store its config, so only the particles are compared");
+            return Ok(reference);
+        }
+    };
+    ensure!(
+        meta.dt == args.dt,
+        "Reference was made with --dt {}, but this run uses {}",
+        meta.dt,
+        args.dt
+    );
+    let differences = config_differences(&meta.config, &args.cfg)?;
+    if !differences.is_empty() {
+        bail!(
+            "Reference was made with a different config:\n{}",
+            differences.join("\n")
+        );
+    }
+    Ok(reference)
+}
+
+/// The parameters which differ between two configs, one line each
+fn config_differences(reference: &SlimeConfig, ours: &SlimeConfig) -> Result<Vec<String>> {
+    // Parameters by their group and name
+    let flatten = |cfg: &SlimeConfig| -> Result<BTreeMap<String, String>> {
+        let mut params = BTreeMap::new();
+        for (group, value) in toml::Value::try_from(cfg)?.as_table().into_iter().flatten() {
+            match value.as_table() {
+                Some(table) => {
+                    for (key, value) in table {
+                        params.insert(format!("{}.{}", group, key), value.to_string());
+                    }
+                }
+                None => {
+                    params.insert(group.clone(), value.to_string());
+                }
+            }
+        }
+        Ok(params)
+    };
+    let (reference, ours) = (flatten(reference)?, flatten(ours)?);
+
+    let mut names: Vec<&String> = reference.keys().chain(ours.keys()).collect();
+    names.sort_unstable();
+    names.dedup();
+    let unset = "unset".to_string();
+    Ok(names
+        .into_iter()
+        .filter(|name| reference.get(*name) != ours.get(*name))
+        .map(|name| {
+            format!(
+                "  {}: {} in the reference, {} here",
+                name,
+                reference.get(name).unwrap_or(&unset),
+                ours.get(name).unwrap_or(&unset)
+            )
+        })
+        .collect())
+}
+
+fn extract_thumbnails(records: &[PathBuf], output: Option<&Path>) -> Result<()> {
+    for path in records {
+        let mut record =
+            RecordFile::load(path).with_context(|| format!("Failed to load {}", path.display()))?;
+        let png = match record.meta.as_mut().and_then(|meta| meta.thumbnail.take()) {
+            Some(png) => png,
+            // Saved before thumbnails were embedded
+            None => thumbnail(&record, THUMBNAIL_SIZE)?,
+        };
+
+        let out = path.with_extension("thumb.png");
+        let out = match output {
+            Some(dir) => dir.join(out.file_name().context("Record path has no file name")?),
+            None => out,
+        };
+        std::fs::write(&out, png).with_context(|| format!("Failed to write {}", out.display()))?;
+        info!("Wrote {}", out.display());
+    }
+    Ok(())
+}
+
+fn export_contours(args: &ContourOpt) -> Result<()> {
+    ensure!(!args.thresholds.is_empty(), "Give at least one threshold");
+    let record = RecordFile::load(&args.record)
+        .with_context(|| format!("Failed to load {}", args.record.display()))?;
+    let field = traced_field(&record, args.frame)?;
+
+    let layers: Vec<Layer> = args
+        .thresholds
+        .iter()
+        .enumerate()
+        .map(|(k, &threshold)| Layer {
+            name: format!("density-{}", threshold),
+            color: group_color(k as u32),
+            lines: contours(&field, threshold),
+        })
+        .collect();
+    write_layers(&args.output, &record, &args.sheet, layers)
+}
+
+fn export_points(args: &PointsOpt) -> Result<()> {
+    let path = &args.output;
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    ensure!(
+        matches!(ext, "ply" | "xyz"),
+        "{} should end in .ply or .xyz",
+        path.display()
+    );
+    ensure!(args.every > 0, "--every must be at least 1");
+
+    let record = RecordFile::load(&args.record)
+        .with_context(|| format!("Failed to load {}", args.record.display()))?;
+    let points = space_time_points(&record, args.every, args.time_scale, args.color);
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let out = BufWriter::new(file);
+    match ext {
+        "ply" => write_ply(out, &points)?,
+        _ => write_xyz(out, &points)?,
+    }
+    info!("Wrote {} points to {}", points.len(), path.display());
+    Ok(())
+}
+
+fn export_mesh(args: &MeshOpt) -> Result<()> {
+    let path = &args.output;
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    ensure!(
+        matches!(ext, "stl" | "obj"),
+        "{} should end in .stl or .obj",
+        path.display()
+    );
+    ensure!(args.threshold > 0., "--threshold must be positive");
+    ensure!(
+        args.every > 0 && args.downsample > 0,
+        "--every and --downsample must be at least 1"
+    );
+
+    let record = RecordFile::load(&args.record)
+        .with_context(|| format!("Failed to load {}", args.record.display()))?;
+    let width = (record.width / args.downsample).max(1);
+    let height = (record.height / args.downsample).max(1);
+    let slices: Vec<Array2D<f32>> = record
+        .frames
+        .iter()
+        .filter_map(|frame| frame.medium.as_ref())
+        .step_by(args.every)
+        .map(|medium| {
+            let medium = Array2D::from_array(record.width, medium.clone());
+            downsample(&medium, width, height)
+        })
+        .collect();
+    ensure!(
+        !slices.is_empty(),
+        "{} has no medium; record it with --record-medium-every",
+        args.record.display()
+    );
+
+    let scale = Vector3::new(
+        record.width as f32 / width as f32 * args.cell_size,
+        record.height as f32 / height as f32 * args.cell_size,
+        args.time_scale * args.cell_size,
+    );
+    let triangles: Vec<Triangle> = isosurface(&slices, args.threshold, 1.)
+        .into_iter()
+        .map(|tri| tri.map(|v| v.component_mul(&scale)))
+        .collect();
+    ensure!(
+        !triangles.is_empty(),
+        "Nothing in the record reaches a density of {}",
+        args.threshold
+    );
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let out = BufWriter::new(file);
+    match ext {
+        "stl" => write_stl(out, &triangles)?,
+        _ => write_obj(out, &triangles)?,
+    }
+    info!(
+        "Wrote {} triangles from {} slices to {}",
+        triangles.len(),
+        slices.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn export_point_cache(args: &PointCacheOpt) -> Result<()> {
+    let path = &args.output;
+    ensure!(
+        path.extension().map_or(false, |ext| ext == "usda"),
+        "{} should end in .usda",
+        path.display()
+    );
+    ensure!(args.every > 0, "--every must be at least 1");
+    ensure!(args.fps > 0., "--fps must be positive");
+
+    let record = RecordFile::load(&args.record)
+        .with_context(|| format!("Failed to load {}", args.record.display()))?;
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    write_usda(
+        BufWriter::new(file),
+        &record,
+        args.every,
+        args.fps,
+        args.cell_size,
+        args.point_width,
+    )?;
+    info!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Write the layers, in cells of the record, to an SVG or DXF depending on the extension of
+/// `path`
+fn write_layers(
+    path: &Path,
+    record: &RecordFile,
+    sheet: &SheetOpt,
+    mut layers: Vec<Layer>,
+) -> Result<()> {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    ensure!(
+        matches!(ext, "svg" | "dxf"),
+        "{} should end in .svg or .dxf",
+        path.display()
+    );
+
+    scale_layers(&mut layers, sheet.cell_size);
+    let (width, height) = (
+        record.width as f32 * sheet.cell_size,
+        record.height as f32 * sheet.cell_size,
+    );
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let out = BufWriter::new(file);
+    match ext {
+        "svg" => write_svg(out, width, height, sheet.units, sheet.stroke_width, &layers)?,
+        _ => write_dxf(out, height, sheet.units, &layers)?,
+    }
+
+    let n_lines: usize = layers.iter().map(|layer| layer.lines.len()).sum();
+    info!("Wrote {} lines to {}", n_lines, path.display());
+    Ok(())
+}
+
+/// The medium of `frame` (or of the last frame that has it), or the trail image where the
+/// record has no medium
+fn traced_field(record: &RecordFile, frame: Option<usize>) -> Result<Array2D<f32>> {
+    let n_frames = record.frames.len();
+    let medium = match frame {
+        Some(idx) => {
+            ensure!(idx < n_frames, "The record only has {} frames", n_frames);
+            let medium = record.frames[idx].medium.as_ref();
+            Some(medium.with_context(|| format!("Frame {} has no medium", idx))?)
+        }
+        None => record.frames.iter().rev().find_map(|f| f.medium.as_ref()),
+    };
+    Ok(match medium {
+        Some(medium) => Array2D::from_array(record.width, medium.clone()),
+        None => {
+            info!("The record has no medium, tracing the trails instead");
+            trail_image(record, record.width, record.height)
+        }
+    })
+}
+
+fn init_config(preset_name: Option<String>, output: Option<PathBuf>) -> Result<()> {
+    let name = match preset_name {
+        Some(name) => name,
+        None => {
+            for (name, description, _) in PRESETS {
+                println!("{:<12}{}", name, description);
+            }
+            return Ok(());
+        }
+    };
+
+    let text = commented_toml(&preset(&name)?)?;
+    match output {
+        Some(path) => std::fs::write(&path, text)
+            .with_context(|| format!("Failed to write {}", path.display())),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+/// Measurements taken during a run: the frame, the sim time, and the metrics
+type Samples = Vec<(usize, f32, [f32; 5])>;
+
+/// Frames between progress messages in the log
+const LOG_INTERVAL: usize = 100;
+
+fn run(mut args: RunOpt, matches: &ArgMatches) -> Result<()> {
+    let _profiler = logging::init(&args.verbosity, args.profile.as_deref())?;
+    if let Some(path) = &args.config {
+        args.cfg = SlimeConfig::load(path)?.with_overrides(&args.cfg, matches)?;
+    }
+
+    let lut = match &args.lut {
+        Some(path) => Some(Lut::load(path)?),
+        None => None,
+    };
+
+    // Catch template typos before the run rather than at the first snapshot
+    if args.snapshot_every.is_some() {
+        snapshot_path(&args, args.seed, 0)?;
+    }
+
+    let samples = simulate(&args, args.seed, lut.as_ref(), args.verbosity.quiet())?;
+    if let Some(path) = &args.metrics {
+        write_metrics(path, &samples)?;
+        info!("Wrote metrics to {}", path.display());
+    }
+    Ok(())
+}
+
+fn ensemble(mut args: EnsembleOpt, matches: &ArgMatches) -> Result<()> {
+    let _profiler = logging::init(&args.run.verbosity, args.run.profile.as_deref())?;
+    ensure!(
+        args.run.record.is_none() && args.run.metrics.is_none(),
+        "Ensembles write their metrics to --output, and can't be recorded"
+    );
+    ensure!(args.runs > 0, "An ensemble needs at least one run");
+    if let Some(path) = &args.run.config {
+        args.run.cfg = SlimeConfig::load(path)?.with_overrides(&args.run.cfg, matches)?;
+    }
+
+    let lut = match &args.run.lut {
+        Some(path) => Some(Lut::load(path)?),
+        None => None,
+    };
+    if args.run.snapshot_every.is_some() {
+        snapshot_path(&args.run, args.run.seed, 0)?;
+    }
+
+    std::fs::create_dir_all(&args.output)
+        .with_context(|| format!("Failed to create {}", args.output.display()))?;
+
+    // Individual runs stay quiet, and the bar counts finished runs instead
+    let progress = Mutex::new(Progress::new(
+        args.runs as usize,
+        args.run.verbosity.quiet(),
+    ));
+    let done = Mutex::new(0);
+    let runs: Vec<Samples> = (0..args.runs)
+        .into_par_iter()
+        .map(|k| {
+            let seed = args.run.seed + k;
+            let samples = simulate(&args.run, seed, lut.as_ref(), true)?;
+            write_metrics(&args.output.join(format!("run_{}.csv", seed)), &samples)?;
+            debug!("Run with seed {} finished", seed);
+
+            let mut done = done.lock().unwrap();
+            *done += 1;
+            progress.lock().unwrap().set(*done);
+            Ok(samples)
+        })
+        .collect::<Result<_>>()?;
+    progress.into_inner().unwrap().finish();
+
+    write_summary(&args.output.join("summary.csv"), &runs)?;
+    info!(
+        "Wrote {} runs and their summary to {}",
+        runs.len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Run the sim from the given seed, taking the snapshots and recording asked for, and return
+/// the measurements taken along the way
+fn simulate(args: &RunOpt, seed: u64, lut: Option<&Lut>, quiet: bool) -> Result<Samples> {
+    ensure!(
+        args.resize.is_none() || args.record.is_none(),
+        "--resize can't be combined with --record"
+    );
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut sim = SlimeSim::new(args.width, args.height, args.n_particles, &mut rng);
+    args.init.apply(&mut sim, &mut rng)?;
+    if args.gpu {
+        sim.use_gpu()?;
+    }
+
+    let mut record = args.record.as_ref().map(|_| RecordFile {
+        meta: Some(RecordMeta {
+            config: args.cfg.clone(),
+            dt: args.dt,
+            thumbnail: None,
+        }),
+        ..RecordFile::new(args.width, args.height)
+    });
+    let mut budget = args
+        .record_max_mb
+        .map(|mb| RecordBudget::new(mb, args.record_overflow));
+
+    let mut samples = vec![];
+    let mut progress = Progress::new(args.frames, quiet);
+    for frame in 0..args.frames {
+        if let Some(resize) = args.resize.as_ref().filter(|r| r.frame == frame) {
+            sim.resize(resize.width, resize.height);
+            debug!("Resized to {}x{}", resize.width, resize.height);
+        }
+
+        if args.metrics_every > 0 && frame % args.metrics_every == 0 {
+            samples.push((frame, sim.time(), measure(&sim)));
+        }
+
+        let wanted = budget.as_mut().map_or(true, |b| b.wants_frame());
+        if let Some(record) = record.as_mut().filter(|_| wanted) {
+            let _span = info_span!("record").entered();
+            let with_medium = args
+                .record_medium_every
+                .map_or(false, |n| n > 0 && frame % n == 0);
+            record_frame(record, &sim, with_medium);
+            if let Some(budget) = &mut budget {
+                budget.enforce(record);
+            }
+
+            if let Some(path) = &args.record {
+                let n = record.frames.len();
+                if args
+                    .autosave_every
+                    .map_or(false, |every| every > 0 && n % every == 0)
+                {
+                    record.autosave(path)?;
+                    info!("Autosaved {} frames to {}", n, path.display());
+                }
+            }
+        }
+
+        if let Some(every) = args.snapshot_every.filter(|&n| n > 0) {
+            if frame % every == 0 {
+                let _span = info_span!("snapshot").entered();
+                let path = snapshot_path(args, seed, frame)?;
+                save_snapshot(&path, sim.frame(), lut)?;
+                debug!("Saved snapshot {}", path.display());
+            }
+        }
+
+        for _ in 0..args.steps_per_frame {
+            sim.step(&args.cfg, args.dt, &mut rng);
+        }
+        progress.set(frame + 1);
+        if (frame + 1) % LOG_INTERVAL == 0 {
+            debug!(
+                "Seed {}: frame {} of {}, {} particles",
+                seed,
+                frame + 1,
+                args.frames,
+                sim.frame().slime.len()
+            );
+        }
+    }
+    progress.finish();
+
+    if let (Some(record), Some(path)) = (&record, &args.record) {
+        record.save(path)?;
+        info!("Saved {} frames to {}", record.frames.len(), path.display());
+    }
+    Ok(samples)
+}
+
+fn write_metrics(path: &Path, samples: &Samples) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "frame,time,{}", METRICS.join(","))?;
+    for (frame, time, values) in samples {
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        writeln!(out, "{},{},{}", frame, time, values.join(","))?;
+    }
+    Ok(())
+}
+
+/// Mean and sample variance of each metric over the runs, at each time they were measured.
+/// Every run takes the same measurements, since they share their settings
+fn write_summary(path: &Path, runs: &[Samples]) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+
+    let columns: Vec<String> = METRICS
+        .iter()
+        .flat_map(|m| [format!("{}_mean", m), format!("{}_var", m)])
+        .collect();
+    writeln!(out, "frame,time,{}", columns.join(","))?;
+
+    let n = runs.len() as f32;
+    for (idx, &(frame, time, _)) in runs[0].iter().enumerate() {
+        let mut row = vec![];
+        for m in 0..METRICS.len() {
+            let values = runs.iter().map(|r| r[idx].2[m]);
+            let mean = values.clone().sum::<f32>() / n;
+            let var = match runs.len() {
+                1 => 0.,
+                _ => values.map(|v| (v - mean).powi(2)).sum::<f32>() / (n - 1.),
+            };
+            row.push(format!("{},{}", mean, var));
+        }
+        writeln!(out, "{},{},{}", frame, time, row.join(","))?;
+    }
+    Ok(())
+}
+
+/// Fill in the snapshot template for the given run and frame
+fn snapshot_path(args: &RunOpt, seed: u64, frame: usize) -> Result<PathBuf> {
+    let mut vars: HashMap<String, toml::Value> = HashMap::new();
+    let groups = toml::Value::try_from(&args.cfg)?;
+    for params in groups.as_table().into_iter().flat_map(|t| t.values()) {
+        for (key, value) in params.as_table().into_iter().flatten() {
+            vars.insert(key.clone(), value.clone());
+        }
+    }
+    vars.insert("seed".into(), toml::Value::Integer(seed as i64));
+    vars.insert("frame".into(), toml::Value::Integer(frame as i64));
+
+    let template = &args.snapshot_template;
+    let mut path = String::new();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        path.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unclosed {{ in snapshot template \"{}\"", template))?;
+        let inner = &rest[start + 1..start + end];
+        let (name, spec) = inner.split_once(':').unwrap_or((inner, ""));
+        let value = vars
+            .get(name)
+            .with_context(|| format!("Unknown variable {{{}}} in snapshot template", name))?;
+        path.push_str(&format_var(value, spec)?);
+        rest = &rest[start + end + 1..];
+    }
+    path.push_str(rest);
+
+    Ok(PathBuf::from(path))
+}
+
+/// Format a template variable with a spec like `06` or `.3`
+fn format_var(value: &toml::Value, spec: &str) -> Result<String> {
+    let (zero, spec) = match spec.strip_prefix('0') {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+    let (width, precision) = match spec.split_once('.') {
+        Some((width, precision)) => (width, Some(precision)),
+        None => (spec, None),
+    };
+    let width: usize = match width {
+        "" => 0,
+        w => w.parse().context("Invalid width in snapshot template")?,
+    };
+
+    let text = match (value, precision) {
+        (toml::Value::Float(v), Some(p)) => {
+            let p: usize = p
+                .parse()
+                .context("Invalid precision in snapshot template")?;
+            format!("{:.*}", p, v)
+        }
+        (toml::Value::String(s), _) => s.clone(),
+        (_, Some(_)) => bail!("Only floating point parameters take a precision"),
+        (other, None) => other.to_string(),
+    };
+
+    Ok(if zero {
+        format!("{:0>1$}", text, width)
+    } else {
+        format!("{:>1$}", text, width)
+    })
+}
+
+/// Write the primary channel of the medium as a PNG, through the LUT if there is one
+fn save_snapshot(path: &Path, data: &SlimeData, lut: Option<&Lut>) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let medium = data.medium();
+    let pixels: Vec<u8> = medium
+        .data()
+        .iter()
+        .flat_map(|&v| {
+            let rgb = lut.map_or([v; 3], |lut| lut.sample(v));
+            rgb.map(|c| (c.clamp(0., 1.) * 255.) as u8)
+        })
+        .collect();
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        medium.width() as u32,
+        medium.height() as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
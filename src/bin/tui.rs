@@ -0,0 +1,148 @@
+use anyhow::{ensure, Result};
+use slime::{
+    expr::Oscillation,
+    image::downsample,
+    init::InitParticles,
+    logging::{self, Verbosity},
+    lut::Lut,
+    sim::*,
+};
+use std::{
+    io::{BufWriter, Write},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// Live preview of the medium in the terminal, for machines without a window
+#[derive(Debug, StructOpt)]
+struct Opt {
+    #[structopt(short = "t", long, default_value = "0.5")]
+    dt: f32,
+
+    #[structopt(short = "w", long, default_value = "400")]
+    width: usize,
+
+    #[structopt(short = "h", long, default_value = "400")]
+    height: usize,
+
+    #[structopt(short = "n", long, default_value = "4000")]
+    n_particles: usize,
+
+    #[structopt(long, default_value = "1")]
+    steps_per_frame: usize,
+
+    /// Width of the preview in terminal columns
+    #[structopt(long, default_value = "80")]
+    columns: usize,
+
+    /// Maximum redraws per second
+    #[structopt(long, default_value = "15")]
+    fps: f32,
+
+    /// Decay and diffuse the medium on the GPU, for big grids. Needs the gpu feature
+    #[structopt(long)]
+    gpu: bool,
+
+    /// Color lookup table for the medium, as a .cube file or a gradient PNG
+    #[structopt(long)]
+    lut: Option<PathBuf>,
+
+    /// Swing a parameter between two values over a period of sim time, e.g. "decay=0.02..0.2/500"
+    #[structopt(long)]
+    oscillate: Vec<Oscillation>,
+
+    /// Read the sim parameters from this TOML file. Flags given on the command line override it
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    init: InitParticles,
+
+    // Log messages go to stderr, which is best redirected away from the preview
+    #[structopt(flatten)]
+    verbosity: Verbosity,
+
+    #[structopt(flatten)]
+    cfg: SlimeConfig,
+}
+
+fn main() -> Result<()> {
+    let matches = Opt::clap().get_matches();
+    let mut args = Opt::from_clap(&matches);
+    ensure!(args.fps > 0., "--fps must be positive");
+    logging::init(&args.verbosity, None)?;
+    if let Some(path) = &args.config {
+        args.cfg = SlimeConfig::load(path)?.with_overrides(&args.cfg, &matches)?;
+    }
+
+    // Catch typos in parameter names before clearing the screen
+    for o in &args.oscillate {
+        args.cfg.clone().set_param(&o.param, 0.)?;
+    }
+
+    let lut = match &args.lut {
+        Some(path) => Some(Lut::load(path)?),
+        None => None,
+    };
+
+    let mut sim = SlimeSim::new(
+        args.width,
+        args.height,
+        args.n_particles,
+        &mut rand::thread_rng(),
+    );
+    args.init.apply(&mut sim, rand::thread_rng())?;
+    if args.gpu {
+        sim.use_gpu()?;
+    }
+
+    // Each character cell shows two vertically stacked pixels, and is about twice as tall as
+    // it is wide
+    let columns = args.columns.max(1);
+    let rows = (columns * args.height / args.width / 2).max(1);
+
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    write!(out, "\x1b[2J")?;
+
+    let frame_time = Duration::from_secs_f32(1. / args.fps);
+    loop {
+        let start = Instant::now();
+
+        for _ in 0..args.steps_per_frame {
+            let mut cfg = args.cfg.clone();
+            for o in &args.oscillate {
+                cfg.set_param(&o.param, o.value(sim.time()))?;
+            }
+            sim.step(&cfg, args.dt, &mut rand::thread_rng());
+        }
+
+        let pixels = downsample(sim.frame().medium(), columns, rows * 2);
+        let color = |v: f32| {
+            let rgb = lut.as_ref().map_or([v; 3], |lut| lut.sample(v));
+            rgb.map(|c| (c.clamp(0., 1.) * 255.) as u8)
+        };
+
+        write!(out, "\x1b[H")?;
+        for row in 0..rows {
+            for col in 0..columns {
+                let [tr, tg, tb] = color(pixels[(col, row * 2)]);
+                let [br, bg, bb] = color(pixels[(col, row * 2 + 1)]);
+                write!(
+                    out,
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                    tr, tg, tb, br, bg, bb
+                )?;
+            }
+            writeln!(out, "\x1b[0m")?;
+        }
+        write!(out, "step {}\x1b[K", sim.steps())?;
+        out.flush()?;
+
+        if let Some(rest) = frame_time.checked_sub(start.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+}
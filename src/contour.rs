@@ -0,0 +1,133 @@
+//! Contour lines of a density field, by marching squares
+use idek_basics::Array2D;
+use nalgebra::Vector2;
+use std::collections::HashMap;
+
+/// A connected run of contour segments. Closed lines end where they start, without repeating
+/// the first point
+#[derive(Clone, Debug)]
+pub struct Polyline {
+    pub points: Vec<Vector2<f32>>,
+    pub closed: bool,
+}
+
+/// Grid edge a contour crosses: the corner it starts at, and whether it runs down (to the
+/// corner below) rather than right
+type Edge = (usize, usize, bool);
+
+/// The lines where `field` crosses `threshold`, in the field's cell coordinates with each
+/// value taken at the center of its cell. Lines running into the border are left open
+pub fn contours(field: &Array2D<f32>, threshold: f32) -> Vec<Polyline> {
+    let (width, height) = (field.width(), field.height());
+    if width < 2 || height < 2 {
+        return vec![];
+    }
+
+    let inside = |x: usize, y: usize| field[(x, y)] >= threshold;
+    let mut segments: Vec<[Edge; 2]> = vec![];
+    for y in 0..height - 1 {
+        for x in 0..width - 1 {
+            let case = inside(x, y) as u8
+                | (inside(x + 1, y) as u8) << 1
+                | (inside(x + 1, y + 1) as u8) << 2
+                | (inside(x, y + 1) as u8) << 3;
+
+            let top = (x, y, false);
+            let right = (x + 1, y, true);
+            let bottom = (x, y + 1, false);
+            let left = (x, y, true);
+
+            // Saddles are resolved by the average of the corners
+            let center =
+                (field[(x, y)] + field[(x + 1, y)] + field[(x + 1, y + 1)] + field[(x, y + 1)])
+                    / 4.
+                    >= threshold;
+
+            match case {
+                1 | 14 => segments.push([left, top]),
+                2 | 13 => segments.push([top, right]),
+                3 | 12 => segments.push([left, right]),
+                4 | 11 => segments.push([right, bottom]),
+                6 | 9 => segments.push([top, bottom]),
+                7 | 8 => segments.push([left, bottom]),
+                5 if center => segments.extend([[top, right], [bottom, left]]),
+                5 => segments.extend([[left, top], [right, bottom]]),
+                10 if center => segments.extend([[left, top], [right, bottom]]),
+                10 => segments.extend([[top, right], [bottom, left]]),
+                _ => (),
+            }
+        }
+    }
+
+    let point = |(x, y, down): Edge| {
+        let (x1, y1) = if down { (x, y + 1) } else { (x + 1, y) };
+        let (a, b) = (field[(x, y)], field[(x1, y1)]);
+        let t = if a == b {
+            0.5
+        } else {
+            (threshold - a) / (b - a)
+        };
+        let (x, y) = (x as f32, y as f32);
+        let p = if down {
+            Vector2::new(x, y + t)
+        } else {
+            Vector2::new(x + t, y)
+        };
+        p + Vector2::new(0.5, 0.5)
+    };
+
+    // Every edge is shared by at most the two cells either side of it, so the segments link
+    // up into simple chains and loops
+    let mut by_edge: HashMap<Edge, Vec<usize>> = HashMap::new();
+    for (idx, seg) in segments.iter().enumerate() {
+        for &edge in seg {
+            by_edge.entry(edge).or_default().push(idx);
+        }
+    }
+
+    let mut used = vec![false; segments.len()];
+    let walk = |start: usize, from: Edge, used: &mut [bool]| {
+        let mut edges = vec![from];
+        let mut seg = start;
+        let mut at = from;
+        loop {
+            used[seg] = true;
+            let [a, b] = segments[seg];
+            at = if a == at { b } else { a };
+            edges.push(at);
+            match by_edge[&at].iter().find(|&&next| !used[next]) {
+                Some(&next) => seg = next,
+                None => break,
+            }
+        }
+        edges
+    };
+
+    let mut lines = vec![];
+    // Open lines start at an edge only one segment touches
+    for (idx, seg) in segments.iter().enumerate() {
+        if used[idx] {
+            continue;
+        }
+        let open_end = seg.iter().find(|&&edge| by_edge[&edge].len() == 1).copied();
+        if let Some(from) = open_end {
+            let edges = walk(idx, from, &mut used);
+            lines.push(Polyline {
+                points: edges.into_iter().map(point).collect(),
+                closed: false,
+            });
+        }
+    }
+    // What's left are loops
+    for (idx, seg) in segments.iter().enumerate() {
+        if !used[idx] {
+            let mut edges = walk(idx, seg[0], &mut used);
+            edges.pop();
+            lines.push(Polyline {
+                points: edges.into_iter().map(point).collect(),
+                closed: true,
+            });
+        }
+    }
+    lines
+}
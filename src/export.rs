@@ -0,0 +1,467 @@
+//! Exports of records to vector, CAD and 3D formats, for tools outside the renderer
+use crate::{
+    contour::Polyline,
+    isosurface::Triangle,
+    palette::{age_color, group_color},
+    record::RecordFile,
+    sim::{AgentState, SlimeParticle},
+};
+use anyhow::{bail, Result};
+use nalgebra::Vector2;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// Named group of lines, drawn in one color
+pub struct Layer {
+    pub name: String,
+    pub color: [f32; 3],
+    pub lines: Vec<Polyline>,
+}
+
+/// Physical unit of the exported coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Units {
+    Unitless,
+    Millimeters,
+    Centimeters,
+    Meters,
+    Inches,
+}
+
+impl Units {
+    /// The $INSUNITS code of the unit
+    fn dxf_code(self) -> u8 {
+        match self {
+            Units::Unitless => 0,
+            Units::Inches => 1,
+            Units::Millimeters => 4,
+            Units::Centimeters => 5,
+            Units::Meters => 6,
+        }
+    }
+
+    /// An SVG length of `v` in the unit. SVG has no meters, so those are given in centimeters
+    fn svg_length(self, v: f32) -> String {
+        match self {
+            Units::Unitless => format!("{}", v),
+            Units::Millimeters => format!("{}mm", v),
+            Units::Centimeters => format!("{}cm", v),
+            Units::Meters => format!("{}cm", v * 100.),
+            Units::Inches => format!("{}in", v),
+        }
+    }
+}
+
+impl FromStr for Units {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Units::Unitless,
+            "mm" => Units::Millimeters,
+            "cm" => Units::Centimeters,
+            "m" => Units::Meters,
+            "in" => Units::Inches,
+            _ => bail!("Unknown units \"{}\"; expected none, mm, cm, m or in", s),
+        })
+    }
+}
+
+/// What the points of a space-time cloud are colored by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PointColor {
+    Group,
+    State,
+    /// When the point was recorded, from cool at the start to warm at the end
+    Time,
+}
+
+impl FromStr for PointColor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "group" => PointColor::Group,
+            "state" => PointColor::State,
+            "time" => PointColor::Time,
+            _ => bail!(
+                "Unknown point color \"{}\"; expected group, state or time",
+                s
+            ),
+        })
+    }
+}
+
+/// Colored point of a cloud
+pub struct Point {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+/// Every particle of every `every`th frame as a point at (x, y, t), with t the frame index
+/// times `time_scale`, so that the run stacks up into a solid
+pub fn space_time_points(
+    record: &RecordFile,
+    every: usize,
+    time_scale: f32,
+    color: PointColor,
+) -> Vec<Point> {
+    let n_frames = record.frames.len();
+    record
+        .frames
+        .iter()
+        .enumerate()
+        .step_by(every.max(1))
+        .flat_map(|(idx, frame)| {
+            let t = idx as f32 / n_frames.saturating_sub(1).max(1) as f32;
+            frame.slime.iter().map(move |part| Point {
+                position: [part.position.x, part.position.y, idx as f32 * time_scale],
+                color: match color {
+                    PointColor::Group => group_color(part.group),
+                    PointColor::State => match part.state {
+                        AgentState::Exploring => [0.0, 0.66, 1.0],
+                        AgentState::Exploiting => [1.0, 0.81, 0.0],
+                        AgentState::Homing => [1.0, 0.0, 0.53],
+                    },
+                    PointColor::Time => age_color(t),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Write the points as a binary PLY, with 8-bit colors
+pub fn write_ply(mut out: impl Write, points: &[Point]) -> io::Result<()> {
+    write!(
+        out,
+        "ply\n\
+         format binary_little_endian 1.0\n\
+         element vertex {}\n\
+         property float x\n\
+         property float y\n\
+         property float z\n\
+         property uchar red\n\
+         property uchar green\n\
+         property uchar blue\n\
+         end_header\n",
+        points.len()
+    )?;
+    for point in points {
+        for v in point.position {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        out.write_all(&point.color.map(to_u8))?;
+    }
+    Ok(())
+}
+
+/// Write the points as XYZ text, one `x y z r g b` line each
+pub fn write_xyz(mut out: impl Write, points: &[Point]) -> io::Result<()> {
+    for Point { position, color } in points {
+        let [x, y, z] = position;
+        let [r, g, b] = color.map(to_u8);
+        writeln!(out, "{} {} {} {} {} {}", x, y, z, r, g, b)?;
+    }
+    Ok(())
+}
+
+/// Write the particles of every `every`th frame as an animated USD points prim, in ASCII. Frames
+/// are time codes, played back at `fps`, and positions are scaled by `cell_size`. Particles keep
+/// their ids, and carry velocities for motion blur and their group's color
+pub fn write_usda(
+    mut out: impl Write,
+    record: &RecordFile,
+    every: usize,
+    fps: f32,
+    cell_size: f32,
+    point_width: f32,
+) -> io::Result<()> {
+    let frames: Vec<_> = record.frames.iter().step_by(every.max(1)).collect();
+    let (width, height) = (record.width as f32, record.height as f32);
+    writeln!(out, "#usda 1.0")?;
+    writeln!(out, "(")?;
+    writeln!(out, "    startTimeCode = 0")?;
+    writeln!(out, "    endTimeCode = {}", frames.len().saturating_sub(1))?;
+    writeln!(out, "    timeCodesPerSecond = {}", fps)?;
+    writeln!(out, "    upAxis = \"Z\"")?;
+    writeln!(out, ")")?;
+    writeln!(out)?;
+    writeln!(out, "def Points \"slime\"")?;
+    writeln!(out, "{{")?;
+    writeln!(
+        out,
+        "    float3[] extent = [(0, 0, 0), ({}, {}, 0)]",
+        width * cell_size,
+        height * cell_size
+    )?;
+    writeln!(
+        out,
+        "    float[] widths = [{}] (\n        interpolation = \"constant\"\n    )",
+        point_width * cell_size
+    )?;
+    writeln!(
+        out,
+        "    color3f[] primvars:displayColor (\n        interpolation = \"vertex\"\n    )"
+    )?;
+
+    // One time-sampled attribute at a time, each a list of values per frame
+    let mut samples = |decl: &str, value: &dyn Fn(usize, &SlimeParticle) -> String| {
+        writeln!(out, "    {}.timeSamples = {{", decl)?;
+        for (t, frame) in frames.iter().enumerate() {
+            let values: Vec<String> = frame.slime.iter().map(|part| value(t, part)).collect();
+            writeln!(out, "        {}: [{}],", t, values.join(", "))?;
+        }
+        writeln!(out, "    }}")
+    };
+
+    samples("point3f[] points", &|_, part| {
+        let p = part.position * cell_size;
+        format!("({}, {}, 0)", p.x, p.y)
+    })?;
+    samples("int64[] ids", &|_, part| part.id.to_string())?;
+
+    let previous: Vec<HashMap<u32, Vector2<f32>>> = frames
+        .iter()
+        .map(|frame| frame.slime.iter().map(|p| (p.id, p.position)).collect())
+        .collect();
+    samples("vector3f[] velocities", &|t, part| {
+        // Per second, and zero where the particle is new or wrapped around an edge
+        let d = t
+            .checked_sub(1)
+            .and_then(|prev| previous[prev].get(&part.id))
+            .map(|&prev| part.position - prev)
+            .filter(|d| d.x.abs() < width / 2. && d.y.abs() < height / 2.)
+            .unwrap_or_else(Vector2::zeros);
+        let v = d * (fps * cell_size);
+        format!("({}, {}, 0)", v.x, v.y)
+    })?;
+    samples("color3f[] primvars:displayColor", &|_, part| {
+        let [r, g, b] = group_color(part.group);
+        format!("({}, {}, {})", r, g, b)
+    })?;
+    writeln!(out, "}}")
+}
+
+/// Write the triangles as a binary STL
+pub fn write_stl(mut out: impl Write, triangles: &[Triangle]) -> io::Result<()> {
+    out.write_all(&[0; 80])?;
+    out.write_all(&(triangles.len() as u32).to_le_bytes())?;
+    for tri in triangles {
+        let normal = (tri[1] - tri[0]).cross(&(tri[2] - tri[0]));
+        let normal = normal.try_normalize(0.).unwrap_or(normal);
+        for v in std::iter::once(&normal).chain(tri).flat_map(|v| v.iter()) {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        out.write_all(&[0; 2])?;
+    }
+    Ok(())
+}
+
+/// Write the triangles as a Wavefront OBJ, merging the vertices they share
+pub fn write_obj(mut out: impl Write, triangles: &[Triangle]) -> io::Result<()> {
+    let mut index: HashMap<[u32; 3], usize> = HashMap::new();
+    let mut faces = Vec::with_capacity(triangles.len());
+    for tri in triangles {
+        let mut face = [0; 3];
+        for (slot, v) in face.iter_mut().zip(tri) {
+            let next = index.len() + 1;
+            *slot = *index
+                .entry([v.x, v.y, v.z].map(f32::to_bits))
+                .or_insert(next);
+            if *slot == next {
+                writeln!(out, "v {} {} {}", v.x, v.y, v.z)?;
+            }
+        }
+        faces.push(face);
+    }
+    for [a, b, c] in faces {
+        writeln!(out, "f {} {} {}", a, b, c)?;
+    }
+    Ok(())
+}
+
+fn to_u8(v: f32) -> u8 {
+    (v.clamp(0., 1.) * 255.).round() as u8
+}
+
+/// Multiply every point of the layers by `factor`, e.g. to go from cells to physical units
+pub fn scale_layers(layers: &mut [Layer], factor: f32) {
+    for line in layers.iter_mut().flat_map(|layer| &mut layer.lines) {
+        line.points.iter_mut().for_each(|p| *p *= factor);
+    }
+}
+
+/// The path of every particle through the record, one layer per group. Paths are broken where
+/// a particle wraps around an edge or respawns, so that no line crosses the sheet
+pub fn trajectory_layers(record: &RecordFile) -> Vec<Layer> {
+    let (width, height) = (record.width as f32, record.height as f32);
+    let mut groups: BTreeMap<u32, Vec<Polyline>> = BTreeMap::new();
+    // Path being drawn for each particle id, and its group
+    let mut open: BTreeMap<u32, (u32, Vec<Vector2<f32>>)> = BTreeMap::new();
+
+    let mut close = |group: u32, points: Vec<Vector2<f32>>| {
+        if points.len() > 1 {
+            groups.entry(group).or_default().push(Polyline {
+                points,
+                closed: false,
+            });
+        }
+    };
+
+    for frame in &record.frames {
+        let mut next = BTreeMap::new();
+        for part in &frame.slime {
+            let (group, mut points) = open
+                .remove(&part.id)
+                .unwrap_or_else(|| (part.group, vec![]));
+            if let Some(last) = points.last() {
+                let d = part.position - last;
+                if d.x.abs() > width / 2. || d.y.abs() > height / 2. {
+                    close(group, std::mem::take(&mut points));
+                }
+            }
+            points.push(part.position);
+            next.insert(part.id, (group, points));
+        }
+        // Particles missing from this frame are gone
+        for (group, points) in std::mem::replace(&mut open, next).into_values() {
+            close(group, points);
+        }
+    }
+    for (group, points) in open.into_values() {
+        close(group, points);
+    }
+
+    groups
+        .into_iter()
+        .map(|(group, lines)| Layer {
+            name: format!("group-{}", group),
+            color: group_color(group),
+            lines,
+        })
+        .collect()
+}
+
+/// Write the layers as an SVG of `width` by `height` units, one group of stroked paths per
+/// layer
+pub fn write_svg(
+    mut out: impl Write,
+    width: f32,
+    height: f32,
+    units: Units,
+    stroke_width: f32,
+    layers: &[Layer],
+) -> io::Result<()> {
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+        units.svg_length(width),
+        units.svg_length(height),
+        width,
+        height
+    )?;
+    for layer in layers {
+        let [r, g, b] = layer.color.map(to_u8);
+        writeln!(
+            out,
+            r##"<g id="{}" fill="none" stroke="#{:02x}{:02x}{:02x}" stroke-width="{}" stroke-linejoin="round">"##,
+            layer.name, r, g, b, stroke_width
+        )?;
+        for line in &layer.lines {
+            let mut points = line.points.iter();
+            let first = match points.next() {
+                Some(p) => p,
+                None => continue,
+            };
+            write!(out, r#"<path d="M{:.2} {:.2}"#, first.x, first.y)?;
+            for p in points {
+                write!(out, " L{:.2} {:.2}", p.x, p.y)?;
+            }
+            writeln!(out, r#"{}"/>"#, if line.closed { " Z" } else { "" })?;
+        }
+        writeln!(out, "</g>")?;
+    }
+    writeln!(out, "</svg>")
+}
+
+/// Write the layers as an ASCII DXF of polylines, one DXF layer each. DXF's y axis points up,
+/// so lines are flipped within the sheet's `height` to come out the same way up as in the SVG
+pub fn write_dxf(
+    mut out: impl Write,
+    height: f32,
+    units: Units,
+    layers: &[Layer],
+) -> io::Result<()> {
+    // Pairs of group code and value, which is all a DXF is
+    let mut pair = |code: u16, value: &dyn std::fmt::Display| writeln!(out, "{}\n{}", code, value);
+
+    pair(0, &"SECTION")?;
+    pair(2, &"HEADER")?;
+    pair(9, &"$INSUNITS")?;
+    pair(70, &units.dxf_code())?;
+    pair(0, &"ENDSEC")?;
+
+    pair(0, &"SECTION")?;
+    pair(2, &"TABLES")?;
+    pair(0, &"TABLE")?;
+    pair(2, &"LAYER")?;
+    pair(70, &layers.len())?;
+    for layer in layers {
+        pair(0, &"LAYER")?;
+        pair(2, &layer.name)?;
+        pair(70, &0)?;
+        pair(62, &aci_color(layer.color))?;
+        pair(6, &"CONTINUOUS")?;
+    }
+    pair(0, &"ENDTAB")?;
+    pair(0, &"ENDSEC")?;
+
+    pair(0, &"SECTION")?;
+    pair(2, &"ENTITIES")?;
+    for layer in layers {
+        for line in &layer.lines {
+            pair(0, &"POLYLINE")?;
+            pair(8, &layer.name)?;
+            pair(66, &1)?;
+            pair(70, &(line.closed as u8))?;
+            for code in [10, 20, 30] {
+                pair(code, &0.0)?;
+            }
+            for p in &line.points {
+                pair(0, &"VERTEX")?;
+                pair(8, &layer.name)?;
+                pair(10, &format_args!("{:.4}", p.x))?;
+                pair(20, &format_args!("{:.4}", height - p.y))?;
+                pair(30, &0.0)?;
+            }
+            pair(0, &"SEQEND")?;
+            pair(8, &layer.name)?;
+        }
+    }
+    pair(0, &"ENDSEC")?;
+    pair(0, &"EOF")
+}
+
+/// The closest of the standard AutoCAD colors red, yellow, green, cyan, blue, magenta and white
+fn aci_color(color: [f32; 3]) -> u8 {
+    const STANDARD: [[f32; 3]; 7] = [
+        [1., 0., 0.],
+        [1., 1., 0.],
+        [0., 1., 0.],
+        [0., 1., 1.],
+        [0., 0., 1.],
+        [1., 0., 1.],
+        [1., 1., 1.],
+    ];
+    let dist = |c: &[f32; 3]| -> f32 { c.iter().zip(color).map(|(a, b)| (a - b).powi(2)).sum() };
+    let mut best = (f32::INFINITY, 7);
+    for (idx, standard) in STANDARD.iter().enumerate() {
+        if dist(standard) < best.0 {
+            best = (dist(standard), idx as u8 + 1);
+        }
+    }
+    best.1
+}
@@ -0,0 +1,544 @@
+//! A tiny expression language for driving parameters and forces without recompiling, e.g.
+//! `0.05 + 0.04 * sin(t * 0.1)`.
+//!
+//! Supports numbers, named variables, `+ - * / % ^`, parentheses, the constants `pi` and
+//! `tau`, and the functions `sin cos tan abs sqrt exp ln floor ceil min max clamp`.
+use crate::sim::SlimeConfig;
+use anyhow::{bail, ensure, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    root: Node,
+    /// Text the expression was parsed from, to write it back out
+    src: String,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(f32),
+    /// Index into the variable list given at parse time
+    Var(usize),
+    Neg(Box<Node>),
+    Bin(char, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Func {
+    Sin,
+    Cos,
+    Tan,
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+    Floor,
+    Ceil,
+    Min,
+    Max,
+    Clamp,
+}
+
+impl Func {
+    fn from_name(name: &str) -> Option<(Self, usize)> {
+        Some(match name {
+            "sin" => (Func::Sin, 1),
+            "cos" => (Func::Cos, 1),
+            "tan" => (Func::Tan, 1),
+            "abs" => (Func::Abs, 1),
+            "sqrt" => (Func::Sqrt, 1),
+            "exp" => (Func::Exp, 1),
+            "ln" => (Func::Ln, 1),
+            "floor" => (Func::Floor, 1),
+            "ceil" => (Func::Ceil, 1),
+            "min" => (Func::Min, 2),
+            "max" => (Func::Max, 2),
+            "clamp" => (Func::Clamp, 3),
+            _ => return None,
+        })
+    }
+
+    fn apply(self, a: &[f32]) -> f32 {
+        match self {
+            Func::Sin => a[0].sin(),
+            Func::Cos => a[0].cos(),
+            Func::Tan => a[0].tan(),
+            Func::Abs => a[0].abs(),
+            Func::Sqrt => a[0].sqrt(),
+            Func::Exp => a[0].exp(),
+            Func::Ln => a[0].ln(),
+            Func::Floor => a[0].floor(),
+            Func::Ceil => a[0].ceil(),
+            Func::Min => a[0].min(a[1]),
+            Func::Max => a[0].max(a[1]),
+            Func::Clamp => a[0].max(a[1]).min(a[2]),
+        }
+    }
+}
+
+impl Expr {
+    /// Parse `src`, which may refer to the given variables. Values for them are passed to
+    /// `eval` in the same order.
+    pub fn parse(src: &str, vars: &[&str]) -> Result<Self> {
+        let mut parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+            vars,
+        };
+        let root = parser
+            .expr()
+            .with_context(|| format!("Failed to parse expression \"{}\"", src))?;
+        parser.skip_whitespace();
+        ensure!(
+            parser.pos == parser.chars.len(),
+            "Unexpected trailing input in expression \"{}\"",
+            src
+        );
+        Ok(Self {
+            root,
+            src: src.trim().to_string(),
+        })
+    }
+
+    /// Evaluate with the variables in the order given to `parse`
+    pub fn eval(&self, values: &[f32]) -> f32 {
+        eval(&self.root, values)
+    }
+}
+
+/// Variables available to parameter modulation expressions: sim time and step count
+pub const MODULATION_VARS: &[&str] = &["t", "step"];
+
+/// Variables available to force field expressions: cell position, sim time and grid size
+pub const FORCE_VARS: &[&str] = &["x", "y", "t", "width", "height"];
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.src)
+    }
+}
+
+/// A component of a force field as an expression of the `FORCE_VARS`
+#[derive(Clone, Debug)]
+pub struct ForceExpr(pub Expr);
+
+impl ForceExpr {
+    /// Force at (x, y) on a grid of the given size at time `t`
+    pub fn eval(&self, x: f32, y: f32, t: f32, width: f32, height: f32) -> f32 {
+        self.0.eval(&[x, y, t, width, height])
+    }
+}
+
+impl FromStr for ForceExpr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(Expr::parse(s, FORCE_VARS)?))
+    }
+}
+
+impl fmt::Display for ForceExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A config parameter driven by an expression of the `MODULATION_VARS`, written as `name=expr`
+#[derive(Clone, Debug)]
+pub struct Modulation {
+    pub param: String,
+    pub expr: Expr,
+}
+
+impl Modulation {
+    /// Value of the parameter at sim time `t` and the given step
+    pub fn value(&self, t: f32, step: usize) -> f32 {
+        self.expr.eval(&[t, step as f32])
+    }
+}
+
+impl FromStr for Modulation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (param, expr) = s
+            .split_once('=')
+            .context("Expected a modulation of the form name=expr")?;
+        let param = param.trim().to_string();
+        // Catch typos now, rather than once the sim runs
+        SlimeConfig::default().set_param(&param, 0.)?;
+        Ok(Self {
+            param,
+            expr: Expr::parse(expr, MODULATION_VARS)?,
+        })
+    }
+}
+
+impl fmt::Display for Modulation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.param, self.expr)
+    }
+}
+
+/// Expressions are stored in config files as the text they were parsed from
+macro_rules! serde_as_text {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                let text = String::deserialize(d)?;
+                text.parse().map_err(|e| de::Error::custom(format!("{:#}", e)))
+            }
+        }
+    )*};
+}
+
+serde_as_text!(ForceExpr, Modulation);
+
+/// A config parameter swinging sinusoidally between two values over a period of sim time,
+/// written as `name=min..max/period`, e.g. `decay=0.02..0.2/500`
+#[derive(Clone, Debug)]
+pub struct Oscillation {
+    pub param: String,
+    pub min: f32,
+    pub max: f32,
+    pub period: f32,
+}
+
+impl Oscillation {
+    /// Value of the parameter at time `t`, starting halfway between `min` and `max` and rising
+    pub fn value(&self, t: f32) -> f32 {
+        let phase = std::f32::consts::TAU * t / self.period;
+        self.min + (self.max - self.min) * (0.5 + 0.5 * phase.sin())
+    }
+}
+
+impl FromStr for Oscillation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let form = "Expected an oscillation of the form name=min..max/period";
+        let (param, rest) = s.split_once('=').context(form)?;
+        let (range, period) = rest.split_once('/').context(form)?;
+        let (min, max) = range.split_once("..").context(form)?;
+        let num = |v: &str| {
+            v.trim()
+                .parse::<f32>()
+                .with_context(|| format!("Invalid number \"{}\" in oscillation", v.trim()))
+        };
+        let period = num(period)?;
+        ensure!(period > 0., "Oscillation period must be positive");
+        Ok(Self {
+            param: param.trim().to_string(),
+            min: num(min)?,
+            max: num(max)?,
+            period,
+        })
+    }
+}
+
+fn eval(node: &Node, values: &[f32]) -> f32 {
+    match node {
+        Node::Num(v) => *v,
+        Node::Var(idx) => values[*idx],
+        Node::Neg(a) => -eval(a, values),
+        Node::Bin(op, a, b) => {
+            let (a, b) = (eval(a, values), eval(b, values));
+            match op {
+                '+' => a + b,
+                '-' => a - b,
+                '*' => a * b,
+                '/' => a / b,
+                '%' => a.rem_euclid(b),
+                '^' => a.powf(b),
+                _ => unreachable!(),
+            }
+        }
+        Node::Call(func, args) => {
+            let args: Vec<f32> = args.iter().map(|a| eval(a, values)).collect();
+            func.apply(&args)
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    vars: &'a [&'a str],
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .get(self.pos)
+            .map_or(false, |c| c.is_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `c` if it's next
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        let found = self.chars.get(self.pos) == Some(&c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    // expr = term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Node> {
+        let mut node = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                '+'
+            } else if self.eat('-') {
+                '-'
+            } else {
+                return Ok(node);
+            };
+            node = Node::Bin(op, Box::new(node), Box::new(self.term()?));
+        }
+    }
+
+    // term = power (('*' | '/' | '%') power)*
+    fn term(&mut self) -> Result<Node> {
+        let mut node = self.power()?;
+        loop {
+            let op = if self.eat('*') {
+                '*'
+            } else if self.eat('/') {
+                '/'
+            } else if self.eat('%') {
+                '%'
+            } else {
+                return Ok(node);
+            };
+            node = Node::Bin(op, Box::new(node), Box::new(self.power()?));
+        }
+    }
+
+    // power = unary ('^' power)?
+    fn power(&mut self) -> Result<Node> {
+        let base = self.unary()?;
+        if self.eat('^') {
+            Ok(Node::Bin('^', Box::new(base), Box::new(self.power()?)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    // unary = '-' unary | atom
+    fn unary(&mut self) -> Result<Node> {
+        if self.eat('-') {
+            Ok(Node::Neg(Box::new(self.unary()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    // atom = number | '(' expr ')' | ident | ident '(' expr (',' expr)* ')'
+    fn atom(&mut self) -> Result<Node> {
+        if self.eat('(') {
+            let node = self.expr()?;
+            ensure!(self.eat(')'), "Expected ')' at {}", self.pos);
+            return Ok(node);
+        }
+
+        self.skip_whitespace();
+        let start = self.pos;
+        let c = match self.chars.get(self.pos) {
+            Some(&c) => c,
+            None => bail!("Unexpected end of expression"),
+        };
+
+        if c.is_ascii_digit() || c == '.' {
+            while self
+                .chars
+                .get(self.pos)
+                .map_or(false, |c| c.is_ascii_digit() || *c == '.')
+            {
+                self.pos += 1;
+            }
+            let text: String = self.chars[start..self.pos].iter().collect();
+            return Ok(Node::Num(
+                text.parse()
+                    .with_context(|| format!("Invalid number \"{}\"", text))?,
+            ));
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            while self
+                .chars
+                .get(self.pos)
+                .map_or(false, |c| c.is_alphanumeric() || *c == '_')
+            {
+                self.pos += 1;
+            }
+            let name: String = self.chars[start..self.pos].iter().collect();
+
+            if let Some(idx) = self.vars.iter().position(|v| *v == name) {
+                return Ok(Node::Var(idx));
+            }
+
+            match name.as_str() {
+                "pi" => return Ok(Node::Num(std::f32::consts::PI)),
+                "tau" => return Ok(Node::Num(std::f32::consts::TAU)),
+                _ => (),
+            }
+
+            let (func, n_args) = match Func::from_name(&name) {
+                Some(f) => f,
+                None => bail!(
+                    "Unknown name \"{}\"; available variables are {:?}",
+                    name,
+                    self.vars
+                ),
+            };
+
+            ensure!(self.eat('('), "Expected '(' after {}", name);
+            let mut args = vec![self.expr()?];
+            while self.eat(',') {
+                args.push(self.expr()?);
+            }
+            ensure!(self.eat(')'), "Expected ')' at {}", self.pos);
+            ensure!(
+                args.len() == n_args,
+                "{} takes {} arguments, got {}",
+                name,
+                n_args,
+                args.len()
+            );
+
+            return Ok(Node::Call(func, args));
+        }
+
+        bail!("Unexpected '{}' at {}", c, self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: &[&str] = &["x", "y"];
+
+    /// Value of `src` with x = 2 and y = 3
+    fn value(src: &str) -> f32 {
+        Expr::parse(src, VARS).unwrap().eval(&[2., 3.])
+    }
+
+    fn error(src: &str) -> String {
+        format!("{:#}", Expr::parse(src, VARS).unwrap_err())
+    }
+
+    fn check(cases: &[(&str, f32)]) {
+        for &(src, expected) in cases {
+            let got = value(src);
+            assert!(
+                (got - expected).abs() < 1e-5,
+                "{} = {}, not {}",
+                src,
+                got,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        check(&[
+            ("1 + 2 * 3", 7.),
+            ("(1 + 2) * 3", 9.),
+            ("10 - 4 - 3", 3.),
+            ("12 / 3 / 2", 2.),
+            ("7 % 4 * 2", 6.),
+            ("1 + 2 ^ 3 * 2", 17.),
+            ("x * y + x ^ 2", 10.),
+        ]);
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        check(&[("2 ^ 3 ^ 2", 512.), ("(2 ^ 3) ^ 2", 64.), ("2 ^ 2 ^ 0", 2.)]);
+    }
+
+    #[test]
+    fn unary_minus() {
+        check(&[
+            ("-x", -2.),
+            ("--x", 2.),
+            ("y - -x", 5.),
+            ("-(x + y)", -5.),
+            ("2 ^ -1", 0.5),
+            // Negation binds tighter than powers
+            ("-x ^ 2", 4.),
+            ("-7 % 3", 2.),
+        ]);
+    }
+
+    #[test]
+    fn names() {
+        check(&[
+            ("max(x, y)", 3.),
+            ("min(x, y)", 2.),
+            ("clamp(5, x, y)", 3.),
+            ("sin(pi / 2)", 1.),
+            ("tau / pi", 2.),
+            ("floor(2.5) + ceil(2.5)", 5.),
+        ]);
+    }
+
+    #[test]
+    fn wrong_number_of_arguments() {
+        assert!(error("sin(1, 2)").contains("sin takes 1 arguments, got 2"));
+        assert!(error("max(1)").contains("max takes 2 arguments, got 1"));
+        assert!(error("clamp(1, 2)").contains("clamp takes 3 arguments, got 2"));
+        assert!(error("sin x").contains("Expected '(' after sin"));
+    }
+
+    #[test]
+    fn unknown_names() {
+        assert!(error("z + 1").contains("Unknown name \"z\""));
+        assert!(error("foo(1)").contains("Unknown name \"foo\""));
+    }
+
+    #[test]
+    fn trailing_input() {
+        for src in ["1 2", "x y", "(1 + 2))", "max(x, y) 1"] {
+            assert!(error(src).contains("trailing input"), "{}", src);
+        }
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(error("1 +").contains("Unexpected end"));
+        assert!(error("").contains("Unexpected end"));
+        assert!(error("(1").contains("Expected ')'"));
+        assert!(error("1..2").contains("Invalid number"));
+        assert!(error("1 + #").contains("Unexpected '#'"));
+    }
+
+    #[test]
+    fn oscillations_swing_between_their_bounds() {
+        let osc: Oscillation = "decay = 0.02..0.2/500".parse().unwrap();
+        assert_eq!(osc.param, "decay");
+        for (t, expected) in [(0., 0.11), (125., 0.2), (375., 0.02), (500., 0.11)] {
+            let got = osc.value(t);
+            assert!((got - expected).abs() < 1e-5, "{} at {}", got, t);
+        }
+
+        assert!("decay=0.02..0.2/0".parse::<Oscillation>().is_err());
+        assert!("decay=0.02/500".parse::<Oscillation>().is_err());
+    }
+}
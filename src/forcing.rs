@@ -0,0 +1,94 @@
+//! Built-in force patterns, evaluated directly at any point of the (wrapped) grid
+use crate::noise::perlin3;
+use crate::sim::wrap_delta;
+use anyhow::{bail, Result};
+use nalgebra::Vector2;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Forcing {
+    /// Two vortices of opposite spin, side by side across the middle
+    VortexPair,
+    /// A stream along x through the middle
+    Jet,
+    /// Bands flowing alternately left and right
+    Shear,
+    /// Swirling noise which changes over the correlation time
+    Stir,
+}
+
+impl Forcing {
+    /// Force at `pos` on a torus of the given size at time `t`. It peaks at about `strength`,
+    /// and its features (vortex cores, jet and band widths, eddies) are about `scale` across
+    pub fn force(
+        self,
+        pos: Vector2<f32>,
+        size: Vector2<f32>,
+        strength: f32,
+        scale: f32,
+        correlation_time: f32,
+        t: f32,
+    ) -> Vector2<f32> {
+        let scale = scale.max(1e-3);
+        let offset = |center: Vector2<f32>| {
+            let d = pos - center;
+            Vector2::new(wrap_delta(d.x, size.x), wrap_delta(d.y, size.y))
+        };
+
+        match self {
+            Forcing::VortexPair => {
+                // Speed rises linearly inside the core and falls off outside, peaking at one
+                // `scale` from the center
+                let vortex = |center: Vector2<f32>, spin: f32| {
+                    let d = offset(center) / scale;
+                    Vector2::new(-d.y, d.x) * (0.5 - d.norm_squared() / 2.).exp() * spin
+                };
+                let left = vortex(Vector2::new(size.x / 3., size.y / 2.), 1.);
+                let right = vortex(Vector2::new(size.x * 2. / 3., size.y / 2.), -1.);
+                (left + right) * strength
+            }
+            Forcing::Jet => {
+                let dy = offset(size / 2.).y / scale;
+                Vector2::new((-dy * dy / 2.).exp() * strength, 0.)
+            }
+            Forcing::Shear => {
+                // A whole number of pairs of bands, so the pattern wraps seamlessly
+                let pairs = (size.y / (2. * scale)).round().max(1.);
+                let phase = pos.y / size.y * pairs * TAU;
+                Vector2::new(phase.sin() * strength, 0.)
+            }
+            Forcing::Stir => {
+                // The curl of a noise field, which swirls without sources or sinks
+                let z = t / correlation_time.max(1e-3);
+                let psi = |p: Vector2<f32>| perlin3(p.x / scale, p.y / scale, z, 2);
+                let eps = 0.5;
+                let dx = Vector2::new(eps, 0.);
+                let dy = Vector2::new(0., eps);
+                let grad =
+                    Vector2::new(psi(pos + dx) - psi(pos - dx), psi(pos + dy) - psi(pos - dy))
+                        * (scale / (2. * eps));
+                Vector2::new(grad.y, -grad.x) * strength
+            }
+        }
+    }
+}
+
+impl FromStr for Forcing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "vortex-pair" => Forcing::VortexPair,
+            "jet" => Forcing::Jet,
+            "shear" => Forcing::Shear,
+            "stir" => Forcing::Stir,
+            _ => bail!(
+                "Unknown forcing \"{}\"; expected vortex-pair, jet, shear or stir",
+                s
+            ),
+        })
+    }
+}
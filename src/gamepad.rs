@@ -0,0 +1,167 @@
+//! Gamepad control of the sim for live tweaking: sticks and triggers drive config parameters,
+//! and buttons reset, pause and record. Only available with the `gamepad` feature
+use crate::sim::SlimeConfig;
+use anyhow::{bail, Context, Result};
+use std::str::FromStr;
+
+/// Sticks and triggers which can be bound to a parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl FromStr for Control {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "left_x" => Control::LeftX,
+            "left_y" => Control::LeftY,
+            "right_x" => Control::RightX,
+            "right_y" => Control::RightY,
+            "left_trigger" => Control::LeftTrigger,
+            "right_trigger" => Control::RightTrigger,
+            _ => bail!(
+                "Unknown gamepad control \"{}\"; expected left_x, left_y, right_x, right_y, \
+                 left_trigger or right_trigger",
+                s
+            ),
+        })
+    }
+}
+
+/// A config parameter following a stick or trigger, written as `control=name:min..max`, e.g.
+/// `left_x=turn_speed:0.5..2`. Sticks span the range from one side to the other, so they rest
+/// halfway; triggers rest at `min`
+#[derive(Clone, Debug)]
+pub struct GamepadBinding {
+    pub control: Control,
+    pub param: String,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl FromStr for GamepadBinding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let form = "Expected a gamepad binding of the form control=name:min..max";
+        let (control, rest) = s.split_once('=').context(form)?;
+        let (param, range) = rest.split_once(':').context(form)?;
+        let (min, max) = range.split_once("..").context(form)?;
+        let num = |v: &str| {
+            v.trim()
+                .parse::<f32>()
+                .with_context(|| format!("Invalid number \"{}\" in gamepad binding", v.trim()))
+        };
+        Ok(Self {
+            control: control.trim().parse()?,
+            param: param.trim().to_string(),
+            min: num(min)?,
+            max: num(max)?,
+        })
+    }
+}
+
+/// What the buttons do: Select (Back) resets the sim, Start pauses it, and South (A or Cross)
+/// pauses and resumes recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GamepadAction {
+    Reset,
+    Pause,
+    Record,
+}
+
+/// Input from every connected gamepad
+pub struct Gamepad {
+    #[cfg(feature = "gamepad")]
+    gilrs: gilrs::Gilrs,
+    /// Where each control was last seen, from 0 to 1. Controls which haven't been touched
+    /// leave their parameters alone
+    positions: [Option<f32>; 6],
+}
+
+impl Gamepad {
+    #[cfg(feature = "gamepad")]
+    pub fn new() -> Result<Self> {
+        let gilrs =
+            gilrs::Gilrs::new().map_err(|e| anyhow::anyhow!("Failed to open gamepads: {}", e))?;
+        for (_, pad) in gilrs.gamepads() {
+            tracing::info!("Found gamepad {}", pad.name());
+        }
+        Ok(Self {
+            gilrs,
+            positions: [None; 6],
+        })
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn new() -> Result<Self> {
+        bail!("Built without gamepad support; rebuild with --features gamepad")
+    }
+
+    /// Take in the input since the last call, returning the buttons pressed
+    #[cfg(feature = "gamepad")]
+    pub fn poll(&mut self) -> Vec<GamepadAction> {
+        use gilrs::{Axis, Button, EventType};
+
+        let mut actions = vec![];
+        while let Some(event) = self.gilrs.next_event() {
+            let (control, position) = match event.event {
+                EventType::AxisChanged(axis, value, _) => {
+                    let control = match axis {
+                        Axis::LeftStickX => Control::LeftX,
+                        Axis::LeftStickY => Control::LeftY,
+                        Axis::RightStickX => Control::RightX,
+                        Axis::RightStickY => Control::RightY,
+                        _ => continue,
+                    };
+                    (control, (value + 1.) / 2.)
+                }
+                EventType::ButtonChanged(Button::LeftTrigger2, value, _) => {
+                    (Control::LeftTrigger, value)
+                }
+                EventType::ButtonChanged(Button::RightTrigger2, value, _) => {
+                    (Control::RightTrigger, value)
+                }
+                EventType::ButtonPressed(button, _) => {
+                    match button {
+                        Button::Select => actions.push(GamepadAction::Reset),
+                        Button::Start => actions.push(GamepadAction::Pause),
+                        Button::South => actions.push(GamepadAction::Record),
+                        _ => (),
+                    }
+                    continue;
+                }
+                EventType::Connected => {
+                    tracing::info!("Gamepad {} connected", self.gilrs.gamepad(event.id).name());
+                    continue;
+                }
+                _ => continue,
+            };
+            self.positions[control as usize] = Some(position.clamp(0., 1.));
+        }
+        actions
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn poll(&mut self) -> Vec<GamepadAction> {
+        vec![]
+    }
+
+    /// Set the parameters of the bindings whose controls have been moved
+    pub fn apply(&self, bindings: &[GamepadBinding], cfg: &mut SlimeConfig) -> Result<()> {
+        for binding in bindings {
+            if let Some(position) = self.positions[binding.control as usize] {
+                let value = binding.min + (binding.max - binding.min) * position;
+                cfg.set_param(&binding.param, value)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+//! Decay and diffusion of the medium in a compute shader, for big grids where that pass is
+//! what the step spends its time on. Particles still sense and deposit on the CPU, so the
+//! medium goes up to the GPU and comes back down every step. Only available with the `gpu`
+//! feature
+use anyhow::Result;
+use idek_basics::Array2D;
+
+/// Decay and diffusion rates of one channel, as `SlimeSim::diffuse` picks them out of the config
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "gpu", derive(bytemuck::Pod, bytemuck::Zeroable))]
+#[repr(C)]
+pub struct ChannelRates {
+    pub diffusion: f32,
+    pub diffusion_x: f32,
+    pub diffusion_y: f32,
+    pub decay: f32,
+}
+
+/// A compute pipeline for the diffusion pass, with buffers for a grid of the last size used
+pub struct GpuDiffusion {
+    #[cfg(feature = "gpu")]
+    device: wgpu::Device,
+    #[cfg(feature = "gpu")]
+    queue: wgpu::Queue,
+    #[cfg(feature = "gpu")]
+    pipeline: wgpu::ComputePipeline,
+    #[cfg(feature = "gpu")]
+    buffers: Option<Buffers>,
+}
+
+/// Everything sized by the grid, each channel after the other in the medium buffers
+#[cfg(feature = "gpu")]
+struct Buffers {
+    width: usize,
+    height: usize,
+    n_channels: usize,
+    params: wgpu::Buffer,
+    rates: wgpu::Buffer,
+    front: wgpu::Buffer,
+    back: wgpu::Buffer,
+    obstacles: wgpu::Buffer,
+    readback: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// Whether the obstacle mask needs uploading before the next pass
+    obstacles_dirty: bool,
+}
+
+#[cfg(feature = "gpu")]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct Params {
+    width: u32,
+    height: u32,
+    anisotropic: u32,
+    has_obstacles: u32,
+}
+
+/// Cells per side of each workgroup, as in the shader
+#[cfg(feature = "gpu")]
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Same sums in the same order as `SlimeSim::diffuse`, so results only differ by rounding
+#[cfg(feature = "gpu")]
+const SHADER: &str = r#"
+struct Params {
+    width: u32,
+    height: u32,
+    anisotropic: u32,
+    has_obstacles: u32,
+}
+
+struct Rates {
+    diffusion: f32,
+    diffusion_x: f32,
+    diffusion_y: f32,
+    decay: f32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> rates: array<Rates>;
+@group(0) @binding(2) var<storage, read> front: array<f32>;
+@group(0) @binding(3) var<storage, read_write> back: array<f32>;
+@group(0) @binding(4) var<storage, read> obstacles: array<u32>;
+
+// The grid is a torus, so coordinates past one edge wrap around to the opposite one
+fn cell(x: i32, y: i32, k: u32) -> f32 {
+    let w = i32(params.width);
+    let h = i32(params.height);
+    let wx = u32(((x % w) + w) % w);
+    let wy = u32(((y % h) + h) % h);
+    return front[(k * params.height + wy) * params.width + wx];
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.height) {
+        return;
+    }
+    let x = i32(id.x);
+    let y = i32(id.y);
+    let k = id.z;
+    let r = rates[k];
+    let center = cell(x, y, k);
+
+    var diffused: f32;
+    if (params.anisotropic != 0u) {
+        let horiz = (cell(x - 1, y, k) + center + cell(x + 1, y, k)) / 3.0;
+        let vert = (cell(x, y - 1, k) + center + cell(x, y + 1, k)) / 3.0;
+        diffused = center + r.diffusion_x * (horiz - center) + r.diffusion_y * (vert - center);
+    } else {
+        var sum = 0.0;
+        for (var dy = -1; dy <= 1; dy = dy + 1) {
+            for (var dx = -1; dx <= 1; dx = dx + 1) {
+                sum = sum + cell(x + dx, y + dy, k);
+            }
+        }
+        diffused = (1.0 - r.diffusion) * center + r.diffusion * (sum / 9.0);
+    }
+
+    var value = max(1.0 - r.decay, 0.0) * diffused;
+    if (params.has_obstacles != 0u && obstacles[id.y * params.width + id.x] != 0u) {
+        value = 0.0;
+    }
+    back[(k * params.height + id.y) * params.width + id.x] = value;
+}
+"#;
+
+impl GpuDiffusion {
+    #[cfg(feature = "gpu")]
+    pub fn new() -> Result<Self> {
+        use anyhow::Context;
+
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        }))
+        .context("No GPU adapter found")?;
+        tracing::info!("Diffusing on {}", adapter.get_info().name);
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("diffusion"),
+                features: wgpu::Features::empty(),
+                limits: adapter.limits(),
+            },
+            None,
+        ))?;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("diffusion"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("diffusion"),
+            layout: None,
+            module: &shader,
+            entry_point: "main",
+        });
+
+        Ok(Self {
+            device,
+            queue,
+            pipeline,
+            buffers: None,
+        })
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    pub fn new() -> Result<Self> {
+        anyhow::bail!("Built without GPU support; rebuild with --features gpu")
+    }
+
+    /// Upload the obstacle mask again before the next pass, as it has changed
+    pub fn obstacles_changed(&mut self) {
+        #[cfg(feature = "gpu")]
+        if let Some(buffers) = &mut self.buffers {
+            buffers.obstacles_dirty = true;
+        }
+    }
+
+    /// Decay and diffuse each channel of `front` into the same channel of `back`, at the rates
+    /// of the same index. Obstacle cells are emptied
+    #[cfg(feature = "gpu")]
+    pub fn diffuse(
+        &mut self,
+        front: &[Array2D<f32>],
+        back: &mut [Array2D<f32>],
+        rates: &[ChannelRates],
+        anisotropic: bool,
+        obstacles: Option<&Array2D<bool>>,
+    ) -> Result<()> {
+        let (width, height) = (front[0].width(), front[0].height());
+        let n_channels = front.len();
+        let fits = self.buffers.as_ref().map_or(false, |b| {
+            (b.width, b.height, b.n_channels) == (width, height, n_channels)
+        });
+        if !fits {
+            self.buffers = Some(self.create_buffers(width, height, n_channels));
+        }
+        let buffers = self.buffers.as_mut().unwrap();
+
+        let params = Params {
+            width: width as u32,
+            height: height as u32,
+            anisotropic: anisotropic as u32,
+            has_obstacles: obstacles.is_some() as u32,
+        };
+        self.queue
+            .write_buffer(&buffers.params, 0, bytemuck::bytes_of(&params));
+        self.queue
+            .write_buffer(&buffers.rates, 0, bytemuck::cast_slice(rates));
+        let channel_bytes = (width * height * std::mem::size_of::<f32>()) as u64;
+        for (k, channel) in front.iter().enumerate() {
+            self.queue.write_buffer(
+                &buffers.front,
+                k as u64 * channel_bytes,
+                bytemuck::cast_slice(channel.data()),
+            );
+        }
+        if let Some(obstacles) = obstacles.filter(|_| buffers.obstacles_dirty) {
+            let mask: Vec<u32> = obstacles.data().iter().map(|&b| b as u32).collect();
+            self.queue
+                .write_buffer(&buffers.obstacles, 0, bytemuck::cast_slice(&mask));
+            buffers.obstacles_dirty = false;
+        }
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("diffusion"),
+            });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("diffusion"),
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &buffers.bind_group, &[]);
+            let groups = |len: usize| (len as u32 + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+            pass.dispatch_workgroups(groups(width), groups(height), n_channels as u32);
+        }
+        let total_bytes = channel_bytes * n_channels as u64;
+        encoder.copy_buffer_to_buffer(&buffers.back, 0, &buffers.readback, 0, total_bytes);
+        self.queue.submit(Some(encoder.finish()));
+
+        // Sensing needs the result before the particles move
+        let slice = buffers.readback.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+        {
+            let mapped = slice.get_mapped_range();
+            let values: &[f32] = bytemuck::cast_slice(&mapped);
+            for (k, channel) in back.iter_mut().enumerate() {
+                let cells = width * height;
+                channel
+                    .data_mut()
+                    .copy_from_slice(&values[k * cells..(k + 1) * cells]);
+            }
+        }
+        buffers.readback.unmap();
+        Ok(())
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    pub fn diffuse(
+        &mut self,
+        _front: &[Array2D<f32>],
+        _back: &mut [Array2D<f32>],
+        _rates: &[ChannelRates],
+        _anisotropic: bool,
+        _obstacles: Option<&Array2D<bool>>,
+    ) -> Result<()> {
+        anyhow::bail!("Built without GPU support; rebuild with --features gpu")
+    }
+
+    #[cfg(feature = "gpu")]
+    fn create_buffers(&self, width: usize, height: usize, n_channels: usize) -> Buffers {
+        use wgpu::BufferUsages as Usage;
+
+        let buffer = |label: &str, size: usize, usage: Usage| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: size as u64,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let cells = width * height;
+        let medium_bytes = cells * n_channels * std::mem::size_of::<f32>();
+        let params = buffer(
+            "params",
+            std::mem::size_of::<Params>(),
+            Usage::UNIFORM | Usage::COPY_DST,
+        );
+        let rates = buffer(
+            "rates",
+            n_channels * std::mem::size_of::<ChannelRates>(),
+            Usage::STORAGE | Usage::COPY_DST,
+        );
+        let front = buffer("front", medium_bytes, Usage::STORAGE | Usage::COPY_DST);
+        let back = buffer("back", medium_bytes, Usage::STORAGE | Usage::COPY_SRC);
+        let obstacles = buffer(
+            "obstacles",
+            cells * std::mem::size_of::<u32>(),
+            Usage::STORAGE | Usage::COPY_DST,
+        );
+        let readback = buffer("readback", medium_bytes, Usage::MAP_READ | Usage::COPY_DST);
+
+        let bindings = [&params, &rates, &front, &back, &obstacles];
+        let entries: Vec<wgpu::BindGroupEntry> = bindings
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("diffusion"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+
+        Buffers {
+            width,
+            height,
+            n_channels,
+            params,
+            rates,
+            front,
+            back,
+            obstacles,
+            readback,
+            bind_group,
+            obstacles_dirty: true,
+        }
+    }
+}
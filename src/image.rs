@@ -0,0 +1,198 @@
+use crate::noise::perlin3;
+use anyhow::{bail, Context, Result};
+use idek_basics::Array2D;
+use nalgebra::Vector2;
+use std::{
+    fs::File,
+    ops::{Add, Mul},
+    path::Path,
+    str::FromStr,
+};
+
+/// Load a PNG as grayscale, with values in 0..1
+pub fn load_grayscale(path: &Path) -> Result<Array2D<f32>> {
+    let rgb = load_rgb(path)?;
+    let data = rgb
+        .data()
+        .iter()
+        .map(|px| px.iter().sum::<f32>() / 3.)
+        .collect();
+    Ok(Array2D::from_array(rgb.width(), data))
+}
+
+/// Load a PNG as RGB, with values in 0..1. Alpha is ignored
+pub fn load_rgb(path: &Path) -> Result<Array2D<[f32; 3]>> {
+    let mut decoder = png::Decoder::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+
+    let samples = info.color_type.samples();
+    let gray = matches!(
+        info.color_type,
+        png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha
+    );
+
+    let data = buf[..info.buffer_size()]
+        .chunks_exact(samples)
+        .map(|px| {
+            let rgb = if gray {
+                [px[0]; 3]
+            } else {
+                [px[0], px[1], px[2]]
+            };
+            rgb.map(|v| v as f32 / 255.)
+        })
+        .collect();
+
+    Ok(Array2D::from_array(info.width as usize, data))
+}
+
+/// How a render is composited over a background image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blend {
+    Add,
+    Screen,
+    Multiply,
+    Lighten,
+}
+
+impl Blend {
+    /// Composite `fg` over `bg`, both in 0..1
+    pub fn apply(self, bg: [f32; 3], fg: [f32; 3]) -> [f32; 3] {
+        let mut out = [0.; 3];
+        for ((o, b), f) in out.iter_mut().zip(bg).zip(fg) {
+            let f = f.clamp(0., 1.);
+            *o = match self {
+                Blend::Add => (b + f).min(1.),
+                Blend::Screen => 1. - (1. - b) * (1. - f),
+                Blend::Multiply => b * f,
+                Blend::Lighten => b.max(f),
+            };
+        }
+        out
+    }
+}
+
+impl Default for Blend {
+    fn default() -> Self {
+        Blend::Screen
+    }
+}
+
+impl FromStr for Blend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "add" => Blend::Add,
+            "screen" => Blend::Screen,
+            "multiply" => Blend::Multiply,
+            "lighten" => Blend::Lighten,
+            _ => bail!(
+                "Unknown blend mode \"{}\"; expected add, screen, multiply or lighten",
+                s
+            ),
+        })
+    }
+}
+
+/// Nearest-neighbor resample of `arr` to the given size
+pub fn resample<T: Copy>(arr: &Array2D<T>, width: usize, height: usize) -> Array2D<T> {
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let sx = x * arr.width() / width;
+            let sy = y * arr.height() / height;
+            data.push(arr[(sx, sy)]);
+        }
+    }
+    Array2D::from_array(width, data)
+}
+
+/// Bilinear resample of `arr` to the given size, treating values as samples at cell centers.
+/// Edges are clamped
+pub fn resample_bilinear<T>(arr: &Array2D<T>, width: usize, height: usize) -> Array2D<T>
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let (aw, ah) = (arr.width(), arr.height());
+    // Source coordinate of an output cell, and how far it is towards the next source cell
+    let source = |i: usize, out: usize, len: usize| {
+        let s = ((i as f32 + 0.5) * len as f32 / out as f32 - 0.5).clamp(0., (len - 1) as f32);
+        let i0 = s as usize;
+        (i0, (i0 + 1).min(len - 1), s - i0 as f32)
+    };
+
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let (y0, y1, ty) = source(y, height, ah);
+        for x in 0..width {
+            let (x0, x1, tx) = source(x, width, aw);
+            let top = arr[(x0, y0)] * (1. - tx) + arr[(x1, y0)] * tx;
+            let bottom = arr[(x0, y1)] * (1. - tx) + arr[(x1, y1)] * tx;
+            data.push(top * (1. - ty) + bottom * ty);
+        }
+    }
+    Array2D::from_array(width, data)
+}
+
+/// Box-filter `arr` down to the given size, averaging every cell that falls in an output cell
+pub fn downsample(arr: &Array2D<f32>, width: usize, height: usize) -> Array2D<f32> {
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (x0, x1) = (x * arr.width() / width, (x + 1) * arr.width() / width);
+            let (y0, y1) = (y * arr.height() / height, (y + 1) * arr.height() / height);
+            let (x1, y1) = (x1.max(x0 + 1), y1.max(y0 + 1));
+
+            let mut sum = 0.;
+            for sy in y0..y1.min(arr.height()) {
+                for sx in x0..x1.min(arr.width()) {
+                    sum += arr[(sx, sy)];
+                }
+            }
+            data.push(sum / ((x1 - x0) * (y1 - y0)) as f32);
+        }
+    }
+    Array2D::from_array(width, data)
+}
+
+/// Central-difference gradient of a scalar field, scaled by `strength`. If `swirl` is set, the
+/// gradient is rotated a quarter turn so that particles circle around bright regions instead of
+/// being pulled towards them.
+pub fn gradient_field(arr: &Array2D<f32>, strength: f32, swirl: bool) -> Array2D<Vector2<f32>> {
+    let (w, h) = (arr.width(), arr.height());
+    let mut data = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let dx = arr[((x + 1).min(w - 1), y)] - arr[(x.saturating_sub(1), y)];
+            let dy = arr[(x, (y + 1).min(h - 1))] - arr[(x, y.saturating_sub(1))];
+            let grad = Vector2::new(dx, dy) * strength / 2.;
+            data.push(if swirl {
+                Vector2::new(-grad.y, grad.x)
+            } else {
+                grad
+            });
+        }
+    }
+    Array2D::from_array(w, data)
+}
+
+/// A `width` by `height` field of smooth noise with features about `scale` cells across, with
+/// values in 0..1
+pub fn noise_image(width: usize, height: usize, scale: f32, seed: u32) -> Array2D<f32> {
+    let scale = scale.max(1e-3);
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let v = perlin3(x as f32 / scale, y as f32 / scale, 0., seed);
+            data.push((v * 0.5 + 0.5).clamp(0., 1.));
+        }
+    }
+    Array2D::from_array(width, data)
+}
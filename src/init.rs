@@ -0,0 +1,185 @@
+//! Starting populations read from files, in place of the random one: particles listed in a
+//! CSV, or a frame of an earlier record
+use crate::{
+    record::RecordFile,
+    sim::{SlimeParticle, SlimeSim},
+};
+use anyhow::{bail, ensure, Context, Result};
+use nalgebra::Vector2;
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct InitParticles {
+    /// Start with the particles listed in this CSV file, one per line as x,y,heading[,age]
+    /// with the heading in radians. A header line is skipped
+    #[structopt(long)]
+    pub init_particles: Option<PathBuf>,
+
+    /// Start with the particles of a frame of this record, scaled to the size of the sim
+    #[structopt(long)]
+    pub init_from_record: Option<PathBuf>,
+
+    /// Frame of --init-from-record to start from. Defaults to the last one
+    #[structopt(long)]
+    pub frame: Option<usize>,
+}
+
+impl InitParticles {
+    /// Replace the sim's population with the one asked for, if any
+    pub fn apply(&self, sim: &mut SlimeSim, rng: impl Rng) -> Result<()> {
+        let slime = match (&self.init_particles, &self.init_from_record) {
+            (Some(_), Some(_)) => bail!("Pick one of --init-particles and --init-from-record"),
+            (Some(path), None) => load_csv(path, sim, rng)?,
+            (None, Some(path)) => from_record(path, self.frame, sim)?,
+            (None, None) => return Ok(()),
+        };
+        sim.set_particles(slime);
+        Ok(())
+    }
+}
+
+/// Particles listed as x,y,heading[,age] lines. Blank lines and lines starting with # are
+/// skipped, as is a first line which doesn't parse, taking it for a header
+pub fn load_csv(path: &Path, sim: &mut SlimeSim, mut rng: impl Rng) -> Result<Vec<SlimeParticle>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let medium = sim.frame().medium();
+    let (width, height) = (medium.width() as f32, medium.height() as f32);
+
+    let mut slime = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Result<Vec<f32>, _> = line.split(',').map(|f| f.trim().parse()).collect();
+        let fields = match fields {
+            Ok(fields) => fields,
+            Err(_) if idx == 0 => continue,
+            Err(e) => bail!("{}:{}: {}", path.display(), idx + 1, e),
+        };
+        let (x, y, heading, age) = match fields[..] {
+            [x, y, heading] => (x, y, heading, 0.),
+            [x, y, heading, age] => (x, y, heading, age),
+            _ => bail!("{}:{}: expected x,y,heading[,age]", path.display(), idx + 1),
+        };
+        ensure!(
+            (0. ..width).contains(&x) && (0. ..height).contains(&y),
+            "{}:{}: ({}, {}) is outside the {}x{} sim",
+            path.display(),
+            idx + 1,
+            x,
+            y,
+            width,
+            height
+        );
+
+        let heading = Vector2::new(heading.cos(), heading.sin());
+        let part = sim.new_particle(Vector2::new(x, y), heading, &mut rng);
+        slime.push(SlimeParticle {
+            age: age.max(0.) as u32,
+            ..part
+        });
+    }
+
+    ensure!(!slime.is_empty(), "{} lists no particles", path.display());
+    Ok(slime)
+}
+
+/// The particles of `frame` of a record (the last one if `None`), scaled from the record's size
+/// to the sim's
+pub fn from_record(
+    path: &Path,
+    frame: Option<usize>,
+    sim: &SlimeSim,
+) -> Result<Vec<SlimeParticle>> {
+    let record =
+        RecordFile::load(path).with_context(|| format!("Failed to load {}", path.display()))?;
+    let n_frames = record.frames.len();
+    ensure!(n_frames > 0, "{} has no frames", path.display());
+    let frame = frame.unwrap_or(n_frames - 1);
+    ensure!(
+        frame < n_frames,
+        "{} only has {} frames",
+        path.display(),
+        n_frames
+    );
+
+    let medium = sim.frame().medium();
+    let scale = Vector2::new(
+        medium.width() as f32 / record.width as f32,
+        medium.height() as f32 / record.height as f32,
+    );
+    Ok(record.frames[frame]
+        .slime
+        .iter()
+        .map(|part| SlimeParticle {
+            position: part.position.component_mul(&scale),
+            origin: part.origin.component_mul(&scale),
+            ..*part
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::record_frame;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("slime-init-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn csv_lists_particles() {
+        let mut sim = SlimeSim::new(16, 16, 0, StdRng::seed_from_u64(0));
+        let path = temp_path("particles.csv");
+        let text = "x,y,heading,age\n# Comment\n1.5,2.5,0\n\n3,4,3.14159265,7\n";
+        std::fs::write(&path, text).unwrap();
+        let slime = load_csv(&path, &mut sim, StdRng::seed_from_u64(0)).unwrap();
+        std::fs::write(&path, "1,2,0\n20,2,0\n").unwrap();
+        let outside = load_csv(&path, &mut sim, StdRng::seed_from_u64(0)).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(slime.len(), 2);
+        assert_eq!(slime[0].position, Vector2::new(1.5, 2.5));
+        assert_eq!(slime[0].heading, Vector2::new(1., 0.));
+        assert_eq!(slime[0].age, 0);
+        assert!((slime[1].heading - Vector2::new(-1., 0.)).norm() < 1e-6);
+        assert_eq!(slime[1].age, 7);
+        let message = format!("{:#}", outside);
+        assert!(
+            message.contains(":2: (20, 2) is outside the 16x16 sim"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn record_frames_are_scaled_to_the_sim() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let small = SlimeSim::new(8, 8, 5, &mut rng);
+        let mut record = RecordFile::new(8, 8);
+        record_frame(&mut record, &small, false);
+        let path = temp_path("frames.slm");
+        record.save(&path).unwrap();
+
+        let big = SlimeSim::new(16, 32, 0, &mut rng);
+        let slime = from_record(&path, None, &big).unwrap();
+        let missing = from_record(&path, Some(1), &big).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(slime.len(), 5);
+        let scale = Vector2::new(2., 4.);
+        for (scaled, part) in slime.iter().zip(&small.frame().slime) {
+            assert_eq!(scaled.position, part.position.component_mul(&scale));
+            assert_eq!(scaled.origin, part.origin.component_mul(&scale));
+            assert_eq!(scaled.id, part.id);
+        }
+        assert!(format!("{:#}", missing).contains("only has 1 frames"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Surfaces of a stack of density fields, treated as a volume, by marching tetrahedra
+use idek_basics::Array2D;
+use nalgebra::Vector3;
+
+pub type Triangle = [Vector3<f32>; 3];
+
+/// The six tetrahedra each cube is split into, as corners of the cube (bit 0 for x, 1 for y
+/// and 2 for z). They all share the 0-7 diagonal, which keeps neighboring cubes' faces matched
+const TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 1, 3, 7],
+    [0, 3, 2, 7],
+    [0, 2, 6, 7],
+    [0, 6, 4, 7],
+    [0, 4, 5, 7],
+    [0, 5, 1, 7],
+];
+
+/// The surface where the volume crosses `threshold`, with the slices stacked `z_scale` apart
+/// along z. Everything outside the volume counts as zero, so surfaces at positive thresholds are
+/// closed. Triangles wind counterclockwise seen from outside
+pub fn isosurface(slices: &[Array2D<f32>], threshold: f32, z_scale: f32) -> Vec<Triangle> {
+    let (width, height) = match slices.first() {
+        Some(slice) => (slice.width() as isize, slice.height() as isize),
+        None => return vec![],
+    };
+    let depth = slices.len() as isize;
+
+    let value = |x: isize, y: isize, z: isize| {
+        let inside = (0..width).contains(&x) && (0..height).contains(&y) && (0..depth).contains(&z);
+        if inside {
+            slices[z as usize][(x as usize, y as usize)]
+        } else {
+            0.
+        }
+    };
+    let position =
+        |[x, y, z]: [isize; 3]| Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 * z_scale);
+
+    let mut triangles = vec![];
+    for z in -1..depth {
+        for y in -1..height {
+            for x in -1..width {
+                let mut corners = [[0; 3]; 8];
+                for (i, corner) in corners.iter_mut().enumerate() {
+                    let i = i as isize;
+                    *corner = [x + (i & 1), y + ((i >> 1) & 1), z + (i >> 2)];
+                }
+                let values = corners.map(|[x, y, z]| value(x, y, z));
+                if values.iter().all(|&v| v >= threshold) || values.iter().all(|&v| v < threshold) {
+                    continue;
+                }
+
+                for tet in TETRAHEDRA {
+                    let (inside, outside): (Vec<usize>, Vec<usize>) =
+                        tet.into_iter().partition(|&i| values[i] >= threshold);
+
+                    // Where the surface crosses the edge from corner a to corner b. Edges are
+                    // always interpolated in the same direction, so that shared vertices match
+                    let cross = |a: usize, b: usize| {
+                        let (a, b) = if corners[a] < corners[b] {
+                            (a, b)
+                        } else {
+                            (b, a)
+                        };
+                        let (va, vb) = (values[a], values[b]);
+                        let (pa, pb) = (position(corners[a]), position(corners[b]));
+                        let t = ((threshold - va) / (vb - va)).clamp(0., 1.);
+                        pa + (pb - pa) * t
+                    };
+
+                    let mut emit = |tri: Triangle| {
+                        // Face away from the inside corners
+                        let center = |idx: &[usize]| {
+                            idx.iter()
+                                .map(|&i| position(corners[i]))
+                                .sum::<Vector3<f32>>()
+                                / idx.len() as f32
+                        };
+                        let out = center(&outside) - center(&inside);
+                        let normal = (tri[1] - tri[0]).cross(&(tri[2] - tri[0]));
+                        triangles.push(if normal.dot(&out) < 0. {
+                            [tri[0], tri[2], tri[1]]
+                        } else {
+                            tri
+                        });
+                    };
+
+                    match (&inside[..], &outside[..]) {
+                        (&[a], &[b, c, d]) | (&[b, c, d], &[a]) => {
+                            emit([cross(a, b), cross(a, c), cross(a, d)])
+                        }
+                        (&[a, b], &[c, d]) => {
+                            let quad = [cross(a, c), cross(a, d), cross(b, d), cross(b, c)];
+                            emit([quad[0], quad[1], quad[2]]);
+                            emit([quad[0], quad[2], quad[3]]);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    triangles
+}
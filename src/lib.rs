@@ -1,3 +1,24 @@
 pub mod sim;
 pub mod record;
 pub mod xiaolin;
+pub mod image;
+pub mod noise;
+pub mod expr;
+pub mod lut;
+pub mod progress;
+pub mod font;
+pub mod spatial;
+pub mod forcing;
+pub mod palette;
+pub mod preset;
+pub mod metrics;
+pub mod profile;
+pub mod logging;
+pub mod render;
+pub mod init;
+pub mod contour;
+pub mod export;
+pub mod isosurface;
+pub mod npz;
+pub mod gamepad;
+pub mod gpu;
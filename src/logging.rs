@@ -0,0 +1,56 @@
+//! Logging for the binaries. Messages go to stderr through `tracing`, filtered by `-v`/`-q`,
+//! or by `RUST_LOG` when it is set
+use crate::profile::{self, Profiler};
+use anyhow::Result;
+use std::path::Path;
+use structopt::StructOpt;
+use tracing_subscriber::{filter::EnvFilter, prelude::*};
+
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct Verbosity {
+    /// Log more. Repeat for even more
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Log less, and hide progress bars. Repeat to only log errors
+    #[structopt(short = "q", long = "quiet", parse(from_occurrences))]
+    pub quiet: u8,
+}
+
+impl Verbosity {
+    /// Whether progress bars should be hidden
+    pub fn quiet(&self) -> bool {
+        self.quiet > 0
+    }
+
+    fn level(&self) -> &'static str {
+        match self.verbose as i32 - self.quiet as i32 {
+            i32::MIN..=-2 => "error",
+            -1 => "warn",
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        }
+    }
+}
+
+/// Start logging, and profiling to `profile` if given. The profile is written as long as the
+/// returned profiler is alive
+pub fn init(verbosity: &Verbosity, profile: Option<&Path>) -> Result<Option<Profiler>> {
+    let filter = match std::env::var_os("RUST_LOG") {
+        Some(_) => EnvFilter::from_default_env(),
+        None => EnvFilter::new(verbosity.level()),
+    };
+    let log = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .with_filter(filter);
+
+    // The filter only applies to the log, so that profiles include every span
+    let (chrome, profiler) = profile::layer(profile)?;
+    tracing_subscriber::registry()
+        .with(log)
+        .with(chrome)
+        .try_init()?;
+    Ok(profiler)
+}
@@ -0,0 +1,91 @@
+//! Color lookup tables, loaded from `.cube` files or gradient images
+use crate::image::load_rgb;
+use anyhow::{bail, ensure, Context, Result};
+use std::path::Path;
+
+/// A 1D gradient mapping values in 0..1 to colors
+#[derive(Clone, Debug)]
+pub struct Lut {
+    colors: Vec<[f32; 3]>,
+}
+
+impl Lut {
+    /// Load a `.cube` file, or otherwise a PNG whose longer side is read as the gradient
+    pub fn load(path: &Path) -> Result<Self> {
+        let is_cube = path
+            .extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("cube"));
+
+        let colors = if is_cube {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            parse_cube(&text).with_context(|| format!("Failed to parse {}", path.display()))?
+        } else {
+            let image = load_rgb(path)?;
+            if image.width() >= image.height() {
+                (0..image.width()).map(|x| image[(x, 0)]).collect()
+            } else {
+                (0..image.height()).map(|y| image[(0, y)]).collect()
+            }
+        };
+
+        ensure!(!colors.is_empty(), "{} has no colors", path.display());
+        Ok(Self { colors })
+    }
+
+    /// Color at `t`, linearly interpolated. Values outside 0..1 are clamped
+    pub fn sample(&self, t: f32) -> [f32; 3] {
+        let pos = t.clamp(0., 1.) * (self.colors.len() - 1) as f32;
+        let (idx, frac) = (pos as usize, pos.fract());
+        let a = self.colors[idx];
+        let b = self.colors[(idx + 1).min(self.colors.len() - 1)];
+        [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * frac)
+    }
+}
+
+/// Parse the entries of a .cube file. 3D tables are reduced to their gray diagonal, since only
+/// a single value is being mapped
+fn parse_cube(text: &str) -> Result<Vec<[f32; 3]>> {
+    let mut size_1d = None;
+    let mut size_3d = None;
+    let mut entries = vec![];
+
+    for line in text.lines().map(str::trim) {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => continue,
+            Some(w) if w.starts_with('#') => continue,
+            Some("TITLE" | "DOMAIN_MIN" | "DOMAIN_MAX") => continue,
+            Some("LUT_1D_SIZE") => size_1d = Some(parse_size(words.next())?),
+            Some("LUT_3D_SIZE") => size_3d = Some(parse_size(words.next())?),
+            Some(_) => {
+                let values = line
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<f32>, _>>()
+                    .with_context(|| format!("Invalid entry \"{}\"", line))?;
+                ensure!(values.len() == 3, "Expected r g b, got \"{}\"", line);
+                entries.push([values[0], values[1], values[2]]);
+            }
+        }
+    }
+
+    match (size_1d, size_3d) {
+        (Some(n), None) => {
+            ensure!(entries.len() == n, "Expected {} entries", n);
+            Ok(entries)
+        }
+        (None, Some(n)) => {
+            ensure!(entries.len() == n * n * n, "Expected {} entries", n * n * n);
+            // Red varies fastest, then green, then blue
+            Ok((0..n).map(|i| entries[i + i * n + i * n * n]).collect())
+        }
+        _ => bail!("Expected exactly one of LUT_1D_SIZE or LUT_3D_SIZE"),
+    }
+}
+
+fn parse_size(word: Option<&str>) -> Result<usize> {
+    word.context("Missing LUT size")?
+        .parse()
+        .context("Invalid LUT size")
+}
@@ -0,0 +1,46 @@
+//! Summary measurements of the sim state, for comparing runs quantitatively
+use crate::sim::{AgentState, SlimeParticle, SlimeSim};
+
+/// Names of the values returned by `measure`, in order
+pub const METRICS: [&str; 5] = [
+    "particles",
+    "mean_density",
+    "max_density",
+    "coverage",
+    "exploiting",
+];
+
+/// Density above which a cell counts as covered by trails
+const COVERAGE_THRESHOLD: f32 = 0.1;
+
+/// Population, mean and peak density of the primary channel, the fraction of cells covered by
+/// trails, and the fraction of particles exploiting a trail
+pub fn measure(sim: &SlimeSim) -> [f32; 5] {
+    let frame = sim.frame();
+    measure_frame(Some(frame.medium().data()), &frame.slime)
+}
+
+/// The measurements of `measure`, from the cells of the primary channel and the particles, e.g.
+/// of a recorded frame. Without the cells, the density measurements are NaN
+pub fn measure_frame(cells: Option<&[f32]>, slime: &[SlimeParticle]) -> [f32; 5] {
+    let (mean, max, covered) = match cells {
+        Some(cells) => {
+            let n_cells = cells.len().max(1) as f32;
+            let mean = cells.iter().sum::<f32>() / n_cells;
+            let max = cells.iter().copied().fold(0., f32::max);
+            let covered =
+                cells.iter().filter(|&&v| v > COVERAGE_THRESHOLD).count() as f32 / n_cells;
+            (mean, max, covered)
+        }
+        None => (f32::NAN, f32::NAN, f32::NAN),
+    };
+
+    let particles = slime.len();
+    let exploiting = slime
+        .iter()
+        .filter(|p| p.state == AgentState::Exploiting)
+        .count() as f32
+        / particles.max(1) as f32;
+
+    [particles as f32, mean, max, covered, exploiting]
+}
@@ -0,0 +1,66 @@
+/// 3D gradient noise (Perlin), roughly in -1..1. Different seeds give independent fields.
+pub fn perlin3(x: f32, y: f32, z: f32, seed: u32) -> f32 {
+    let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+    let (xf, yf, zf) = (x - x0, y - y0, z - z0);
+    let (xi, yi, zi) = (x0 as i32, y0 as i32, z0 as i32);
+
+    // Contribution of the lattice corner at the given offset
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let h = hash(xi + dx, yi + dy, zi + dz, seed);
+        grad(h, xf - dx as f32, yf - dy as f32, zf - dz as f32)
+    };
+
+    let (u, v, w) = (fade(xf), fade(yf), fade(zf));
+
+    let near = lerp(
+        v,
+        lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+        lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+    );
+    let far = lerp(
+        v,
+        lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+        lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+    );
+
+    lerp(w, near, far)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x8da6b343)
+        ^ (y as u32).wrapping_mul(0xd8163841)
+        ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a2d39);
+    h ^= h >> 15;
+    h
+}
+
+/// Dot product with one of Ken Perlin's 12 gradient directions
+fn grad(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    match h % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
@@ -0,0 +1,301 @@
+//! NumPy `.npz` bundles of records, for analysis outside the sim. An .npz is a zip of `.npy`
+//! arrays; they're written uncompressed, so nothing beyond the format itself is needed
+use crate::{
+    metrics::{measure_frame, METRICS},
+    record::RecordFile,
+    sim::{AgentState, SlimeParticle},
+};
+use anyhow::{bail, ensure, Result};
+use nalgebra::Vector2;
+use std::io::Write;
+
+/// Element type of an array
+pub trait NpyType: Copy {
+    /// The dtype, as in `np.dtype("<f4")`
+    const DESCR: &'static str;
+    fn write_le(self, out: &mut Vec<u8>);
+}
+
+macro_rules! npy_type {
+    ($($ty:ty => $descr:literal),*) => {$(
+        impl NpyType for $ty {
+            const DESCR: &'static str = $descr;
+            fn write_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+
+npy_type!(f32 => "<f4", u32 => "<u4", i64 => "<i8", u8 => "|u1");
+
+/// Writes arrays one at a time into an .npz. Call `finish` to complete the archive
+pub struct NpzWriter<W: Write> {
+    out: W,
+    offset: u32,
+    /// Name, CRC, size and offset of each file written so far
+    entries: Vec<(String, u32, u32, u32)>,
+    crc_table: [u32; 256],
+}
+
+impl<W: Write> NpzWriter<W> {
+    pub fn new(out: W) -> Self {
+        let mut crc_table = [0; 256];
+        for (n, entry) in crc_table.iter_mut().enumerate() {
+            *entry = (0..8).fold(n as u32, |c, _| {
+                if c & 1 == 1 {
+                    0xedb88320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                }
+            });
+        }
+        Self {
+            out,
+            offset: 0,
+            entries: vec![],
+            crc_table,
+        }
+    }
+
+    /// Add an array of the given shape, with `data` in row-major order
+    pub fn array<T: NpyType>(&mut self, name: &str, shape: &[usize], data: &[T]) -> Result<()> {
+        ensure!(
+            shape.iter().product::<usize>() == data.len(),
+            "Array {} doesn't fit its shape {:?}",
+            name,
+            shape
+        );
+        let mut bytes = npy_header(T::DESCR, shape);
+        bytes.reserve(data.len() * std::mem::size_of::<T>());
+        for &v in data {
+            v.write_le(&mut bytes);
+        }
+        self.file(&format!("{}.npy", name), &bytes)
+    }
+
+    /// Add a string, as a 0-d unicode array
+    pub fn string(&mut self, name: &str, text: &str) -> Result<()> {
+        // UTF-32, and numpy has no zero-length strings
+        let chars: Vec<u32> = text.chars().map(|c| c as u32).collect();
+        let len = chars.len().max(1);
+        let mut bytes = npy_header(&format!("<U{}", len), &[]);
+        for c in chars.iter().copied().chain(std::iter::repeat(0)).take(len) {
+            bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        self.file(&format!("{}.npy", name), &bytes)
+    }
+
+    /// Write the directory of the archive, and hand back the writer
+    pub fn finish(mut self) -> Result<W> {
+        let directory_offset = self.offset;
+        let mut directory = vec![];
+        for (name, crc, size, offset) in &self.entries {
+            directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            // Made by and needs version 2.0, no flags, stored, at 1980-01-01 00:00
+            for v in [20u16, 20, 0, 0, 0, 0x21] {
+                directory.extend_from_slice(&v.to_le_bytes());
+            }
+            for v in [*crc, *size, *size] {
+                directory.extend_from_slice(&v.to_le_bytes());
+            }
+            // Name length, then no extra field, comment, disk number or attributes
+            for v in [name.len() as u16, 0, 0, 0, 0] {
+                directory.extend_from_slice(&v.to_le_bytes());
+            }
+            directory.extend_from_slice(&0u32.to_le_bytes());
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        self.out.write_all(&directory)?;
+
+        let n = self.entries.len() as u16;
+        self.out.write_all(&0x06054b50u32.to_le_bytes())?;
+        for v in [0u16, 0, n, n] {
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        self.out
+            .write_all(&(directory.len() as u32).to_le_bytes())?;
+        self.out.write_all(&directory_offset.to_le_bytes())?;
+        self.out.write_all(&0u16.to_le_bytes())?;
+        Ok(self.out)
+    }
+
+    fn file(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let size = u32::try_from(bytes.len()).ok();
+        let header_len = 30 + name.len() as u32;
+        let end = size.and_then(|size| self.offset.checked_add(header_len + size));
+        let (size, end) = match (size, end) {
+            (Some(size), Some(end)) => (size, end),
+            _ => bail!("{} doesn't fit in an .npz without zip64", name),
+        };
+        ensure!(self.entries.len() < u16::MAX as usize, "Too many arrays");
+
+        let crc = !bytes.iter().fold(!0u32, |c, &b| {
+            self.crc_table[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+        });
+        self.out.write_all(&0x04034b50u32.to_le_bytes())?;
+        // Needs version 2.0, no flags, stored, at 1980-01-01 00:00
+        for v in [20u16, 0, 0, 0, 0x21] {
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        for v in [crc, size, size] {
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        for v in [name.len() as u16, 0] {
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        self.out.write_all(name.as_bytes())?;
+        self.out.write_all(bytes)?;
+
+        self.entries
+            .push((name.to_string(), crc, size, self.offset));
+        self.offset = end;
+        Ok(())
+    }
+}
+
+/// Version 1.0 .npy header, padded so that the data starts 64-byte aligned
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    while (10 + dict.len() + 1) % 64 != 0 {
+        dict.push(' ');
+    }
+    dict.push('\n');
+
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+/// Bundle the whole record into one .npz:
+/// - `frame_start`: index of each frame's first particle in the particle arrays, then the
+///   total number of particles
+/// - `position`, `heading`, `origin` (n, 2); `id`, `group`, `age`; `state` (0 exploring, 1
+///   exploiting, 2 homing): every particle of every frame, frame after frame
+/// - `medium` (m, height, width) and `medium_frame`: the recorded media and their frames
+/// - `time`, and one array per metric (see `metrics::METRICS`), for every frame. Density
+///   metrics are NaN for frames without the medium
+/// - `config`: the sim parameters as JSON, if the record has them, and `dt`
+pub fn write_record_npz(out: impl Write, record: &RecordFile) -> Result<()> {
+    let mut npz = NpzWriter::new(out);
+    let particles = record.frames.iter().flat_map(|frame| &frame.slime);
+    let n_particles = particles.clone().count();
+
+    let mut frame_start = vec![0i64];
+    for frame in &record.frames {
+        frame_start.push(frame_start.last().unwrap() + frame.slime.len() as i64);
+    }
+    npz.array("frame_start", &[frame_start.len()], &frame_start)?;
+
+    let vectors = |f: fn(&SlimeParticle) -> Vector2<f32>| -> Vec<f32> {
+        particles.clone().flat_map(|p| [f(p).x, f(p).y]).collect()
+    };
+    npz.array("position", &[n_particles, 2], &vectors(|p| p.position))?;
+    npz.array("heading", &[n_particles, 2], &vectors(|p| p.heading))?;
+    npz.array("origin", &[n_particles, 2], &vectors(|p| p.origin))?;
+
+    let id: Vec<u32> = particles.clone().map(|p| p.id).collect();
+    npz.array("id", &[n_particles], &id)?;
+    let group: Vec<u32> = particles.clone().map(|p| p.group).collect();
+    npz.array("group", &[n_particles], &group)?;
+    let age: Vec<u32> = particles.clone().map(|p| p.age).collect();
+    npz.array("age", &[n_particles], &age)?;
+    let state: Vec<u8> = particles
+        .clone()
+        .map(|p| match p.state {
+            AgentState::Exploring => 0,
+            AgentState::Exploiting => 1,
+            AgentState::Homing => 2,
+        })
+        .collect();
+    npz.array("state", &[n_particles], &state)?;
+
+    let media: Vec<(usize, &Vec<f32>)> = record
+        .frames
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, frame)| Some((idx, frame.medium.as_ref()?)))
+        .collect();
+    let medium_frame: Vec<i64> = media.iter().map(|&(idx, _)| idx as i64).collect();
+    npz.array("medium_frame", &[media.len()], &medium_frame)?;
+    let medium: Vec<f32> = media.iter().flat_map(|(_, m)| m.iter().copied()).collect();
+    npz.array(
+        "medium",
+        &[media.len(), record.height, record.width],
+        &medium,
+    )?;
+
+    let dt = record.meta.as_ref().map_or(f32::NAN, |meta| meta.dt);
+    let time: Vec<f32> = (0..record.frames.len()).map(|i| i as f32 * dt).collect();
+    npz.array("time", &[time.len()], &time)?;
+    let metrics: Vec<[f32; 5]> = record
+        .frames
+        .iter()
+        .map(|frame| measure_frame(frame.medium.as_deref(), &frame.slime))
+        .collect();
+    for (k, name) in METRICS.iter().enumerate() {
+        let series: Vec<f32> = metrics.iter().map(|m| m[k]).collect();
+        npz.array(name, &[series.len()], &series)?;
+    }
+
+    if let Some(meta) = &record.meta {
+        npz.array("dt", &[], &[meta.dt])?;
+        npz.string("config", &to_json(&toml::Value::try_from(&meta.config)?))?;
+    }
+    npz.finish()?.flush()?;
+    Ok(())
+}
+
+/// A TOML value as JSON. Dates become strings
+fn to_json(value: &toml::Value) -> String {
+    use toml::Value;
+    match value {
+        Value::String(s) => json_string(s),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) if f.is_finite() => f.to_string(),
+        Value::Float(_) => "null".into(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(d) => json_string(&d.to_string()),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(to_json).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Table(table) => {
+            let entries: Vec<String> = table
+                .iter()
+                .map(|(k, v)| format!("{}: {}", json_string(k), to_json(v)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,34 @@
+//! Colors shared by the renderers, so that particle groups and trail ages look the same
+//! everywhere
+
+/// Colors of the first groups, repeating for any further ones
+const GROUP_COLORS: [[u8; 3]; 8] = [
+    [0xff, 0x00, 0x88],
+    [0x00, 0xa9, 0xff],
+    [0xff, 0xcf, 0x00],
+    [0x44, 0xdd, 0x66],
+    [0xaa, 0x55, 0xff],
+    [0xff, 0x77, 0x22],
+    [0x22, 0xdd, 0xdd],
+    [0xee, 0xee, 0xee],
+];
+
+/// Colors of the oldest and the freshest trails
+const OLD_TRAIL: [f32; 3] = [0.1, 0.25, 0.7];
+const NEW_TRAIL: [f32; 3] = [1.0, 0.85, 0.5];
+
+/// Color of a trail by its freshness, from cool blue for old structure (0) to bright warm white
+/// for new growth (1)
+pub fn age_color(freshness: f32) -> [f32; 3] {
+    let t = freshness.clamp(0., 1.);
+    let mut color = OLD_TRAIL;
+    for (c, new) in color.iter_mut().zip(NEW_TRAIL) {
+        *c += (new - *c) * t;
+    }
+    color
+}
+
+/// Color of particle group `group`, in 0..1
+pub fn group_color(group: u32) -> [f32; 3] {
+    GROUP_COLORS[group as usize % GROUP_COLORS.len()].map(|v| v as f32 / 256.)
+}
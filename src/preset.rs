@@ -0,0 +1,200 @@
+//! Named starting points for the sim parameters, and commented config files to edit them in
+use crate::sim::*;
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use structopt::{clap::App, StructOpt};
+
+/// Name, description and flags of each preset
+pub const PRESETS: &[(&str, &str, &[&str])] = &[
+    ("default", "The defaults of every flag", &[]),
+    (
+        "network",
+        "Long-lived, sharply defined transport networks",
+        &[
+            "--decay",
+            "0.02",
+            "--sensor-spread",
+            "0.5",
+            "--diffusion",
+            "0.05",
+        ],
+    ),
+    (
+        "mandala",
+        "Six-fold mirrored patterns around the center",
+        &["--symmetry", "6", "--mirror", "--decay", "0.03"],
+    ),
+    (
+        "foraging",
+        "Particles ferrying food back to where they started",
+        &[
+            "--home-with-food",
+            "--food-consumption",
+            "0.05",
+            "--food-regrowth",
+            "0.01",
+            "--homing-after",
+            "400",
+        ],
+    ),
+    (
+        "growth",
+        "Trails recruiting new particles up to a cap",
+        &[
+            "--split-threshold",
+            "2.0",
+            "--split-probability",
+            "0.02",
+            "--max-particles",
+            "20000",
+        ],
+    ),
+    (
+        "turbulent",
+        "Trails stirred around by a drifting eddy field",
+        &[
+            "--forcing",
+            "stir",
+            "--forcing-strength",
+            "0.8",
+            "--flow-decay",
+            "0.5",
+        ],
+    ),
+];
+
+/// The config of the named preset
+pub fn preset(name: &str) -> Result<SlimeConfig> {
+    let (_, _, flags) = PRESETS
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .with_context(|| {
+            let names: Vec<&str> = PRESETS.iter().map(|(n, _, _)| *n).collect();
+            format!(
+                "Unknown preset \"{}\"; expected one of {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+    SlimeConfig::from_iter_safe(std::iter::once("slime").chain(flags.iter().copied()))
+        .map_err(|e| anyhow!(e.message))
+}
+
+const HEADER: &str = "\
+# Sim parameters, for use with --config. Every key is optional; missing keys take the
+# defaults shown, and flags given on the command line override the file
+";
+
+/// `cfg` as a config file, with every parameter explained by its flag's help text. Parameters
+/// which are unset are included commented out
+pub fn commented_toml(cfg: &SlimeConfig) -> Result<String> {
+    let mut out = String::from(HEADER);
+
+    group(&mut out, "agent", &cfg.agent, AgentConfig::clap())?;
+    group(&mut out, "medium", &cfg.medium, MediumConfig::clap())?;
+    group(&mut out, "food", &cfg.food, FoodConfig::clap())?;
+    group(
+        &mut out,
+        "population",
+        &cfg.population,
+        PopulationConfig::clap(),
+    )?;
+    group(&mut out, "perturb", &cfg.perturb, PerturbConfig::clap())?;
+    group(&mut out, "forcing", &cfg.forcing, ForcingConfig::clap())?;
+    group(
+        &mut out,
+        "modulation",
+        &cfg.modulation,
+        ModulationConfig::clap(),
+    )?;
+    Ok(out)
+}
+
+/// Write the table of one config group. `app` must be the group's own command line, which
+/// holds the help text of each of its parameters
+fn group<T: Serialize + Default>(
+    out: &mut String,
+    name: &str,
+    params: &T,
+    app: App<'static, 'static>,
+) -> Result<()> {
+    // Clap has no public accessors for the arguments it was built with. They're named in
+    // kebab-case, while the keys are in snake_case
+    let help: HashMap<String, &str> = app
+        .p
+        .flags
+        .iter()
+        .map(|a| (a.b.name, a.b.help))
+        .chain(app.p.opts.iter().map(|a| (a.b.name, a.b.help)))
+        .map(|(name, help)| (name.replace('-', "_"), help.unwrap_or("")))
+        .collect();
+
+    let defaults = toml::Value::try_from(T::default())?;
+    let default = |key: &str| defaults.get(key).map(|v| v.to_string());
+
+    writeln!(out, "\n[{}]", name)?;
+    let body = toml::to_string_pretty(params)?;
+    let mut written = vec![];
+    for line in body.lines() {
+        // Keys start at the beginning of the line, while array items are indented
+        let key = line
+            .split_once(" = ")
+            .map(|(k, _)| k)
+            .filter(|k| !k.starts_with(' '));
+        if let Some(key) = key {
+            explain(out, help.get(key).copied(), default(key))?;
+            written.push(key.to_string());
+        }
+        writeln!(out, "{}", line)?;
+    }
+
+    // Unset optional parameters don't serialize at all
+    let mut unset: Vec<&str> = help
+        .keys()
+        .map(String::as_str)
+        .filter(|k| !matches!(*k, "help" | "version"))
+        .filter(|k| !written.iter().any(|w| w == k))
+        .collect();
+    unset.sort_unstable();
+    for key in unset {
+        explain(out, help.get(key).copied(), default(key))?;
+        writeln!(out, "#{} = ", key)?;
+    }
+    Ok(())
+}
+
+fn explain(out: &mut String, help: Option<&str>, default: Option<String>) -> Result<()> {
+    writeln!(out)?;
+    for line in help.unwrap_or("").lines() {
+        writeln!(out, "# {}", line)?;
+    }
+    match default {
+        Some(default) => writeln!(out, "# Default: {}", default)?,
+        None => writeln!(out, "# Unset by default")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commented_config_round_trips() {
+        let cfg = SlimeConfig::default();
+        let text = commented_toml(&cfg).unwrap();
+
+        // Multi-word parameters are explained right above their key, and only listed once
+        let help = text
+            .find("\n# Turn rate, radians/time\n# Default: ")
+            .unwrap();
+        let key = text.find("\nturn_speed = ").unwrap();
+        assert!(help < key && !text[help + 1..key].contains("\n\n"));
+        assert!(!text.contains("#turn_speed = ") && !text.contains("#turn-speed = "));
+
+        let parsed = SlimeConfig::from_toml(&text).unwrap();
+        assert_eq!(parsed.agent.turn_speed, cfg.agent.turn_speed);
+    }
+}
@@ -0,0 +1,56 @@
+//! Timing of the sim and renderer phases, written as a Chrome trace. Open it in Perfetto or
+//! `chrome://tracing` for a flame graph. Only available with the `profile` feature
+use anyhow::Result;
+use std::path::Path;
+
+/// Keeps the trace going. Call `finish` (or drop it) to complete the file
+pub struct Profiler {
+    #[cfg(feature = "profile")]
+    guard: tracing_chrome::FlushGuard,
+}
+
+impl Profiler {
+    /// Write out everything traced so far. Needed where the process exits without unwinding,
+    /// like at the end of the gui's event loop
+    pub fn finish(&self) {
+        #[cfg(feature = "profile")]
+        self.guard.flush();
+    }
+}
+
+/// Layer tracing every span to a trace file at `path`, if there is one. Installed by
+/// `logging::init`
+#[cfg(feature = "profile")]
+pub(crate) fn layer<S>(
+    path: Option<&Path>,
+) -> Result<(Option<tracing_chrome::ChromeLayer<S>>, Option<Profiler>)>
+where
+    S: tracing::Subscriber
+        + for<'span> tracing_subscriber::registry::LookupSpan<'span>
+        + Send
+        + Sync,
+{
+    Ok(match path {
+        Some(path) => {
+            let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+                .file(path.to_path_buf())
+                .build();
+            (Some(layer), Some(Profiler { guard }))
+        }
+        None => (None, None),
+    })
+}
+
+#[cfg(not(feature = "profile"))]
+pub(crate) fn layer(
+    path: Option<&Path>,
+) -> Result<(
+    Option<tracing_subscriber::layer::Identity>,
+    Option<Profiler>,
+)> {
+    anyhow::ensure!(
+        path.is_none(),
+        "Built without profiling; rebuild with --features profile"
+    );
+    Ok((None, None))
+}
@@ -0,0 +1,84 @@
+//! Terminal progress bar for the offline tools
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Redraws at most this often, so tight loops aren't slowed down by printing
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const BAR_WIDTH: usize = 30;
+
+pub struct Progress {
+    total: usize,
+    start: Instant,
+    last_draw: Option<Instant>,
+    quiet: bool,
+}
+
+impl Progress {
+    /// A bar counting up to `total`. Nothing is printed if `quiet` is set
+    pub fn new(total: usize, quiet: bool) -> Self {
+        Self {
+            total,
+            start: Instant::now(),
+            last_draw: None,
+            quiet,
+        }
+    }
+
+    /// Report that `done` of the total are finished
+    pub fn set(&mut self, done: usize) {
+        if self.quiet
+            || self
+                .last_draw
+                .map_or(false, |t| t.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        self.draw(done);
+    }
+
+    /// Draw the completed bar and move to the next line
+    pub fn finish(&mut self) {
+        if !self.quiet {
+            self.draw(self.total);
+            eprintln!();
+        }
+    }
+
+    fn draw(&self, done: usize) {
+        let done = done.min(self.total);
+        let fraction = if self.total == 0 {
+            1.
+        } else {
+            done as f32 / self.total as f32
+        };
+
+        let filled = (fraction * BAR_WIDTH as f32) as usize;
+        let bar: String = (0..BAR_WIDTH)
+            .map(|i| if i < filled { '#' } else { '-' })
+            .collect();
+
+        let elapsed = self.start.elapsed().as_secs_f32();
+        let rate = done as f32 / elapsed.max(1e-6);
+        let eta = match done {
+            0 => "?".to_string(),
+            _ => format_duration((self.total - done) as f32 / rate),
+        };
+
+        eprint!(
+            "\r[{}] {}/{} {:.1}/s ETA {}\x1b[K",
+            bar, done, self.total, rate, eta
+        );
+        let _ = std::io::stderr().flush();
+    }
+}
+
+fn format_duration(secs: f32) -> String {
+    let secs = secs.round() as u64;
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
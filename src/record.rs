@@ -1,11 +1,18 @@
-use std::{path::Path, io::{BufReader, BufWriter}, fs::File};
+use std::{path::{Path, PathBuf}, io::{BufRead, BufReader, BufWriter}, fs::File, str::FromStr};
+use std::sync::Mutex;
+use anyhow::{anyhow, bail};
+use tracing::warn;
 use idek_basics::idek::prelude::Result;
-use crate::sim::{SlimeParticle, SlimeSim};
+use crate::sim::{spawn_group, AgentState, SlimeConfig, SlimeParticle, SlimeSim};
+use crate::render::{thumbnail, THUMBNAIL_SIZE};
+use nalgebra::Vector2;
 use serde::{Serialize, Deserialize};
 
-pub fn record_frame(record: &mut RecordFile, sim: &SlimeSim) {
+/// Append the current state of the sim, optionally along with the medium
+pub fn record_frame(record: &mut RecordFile, sim: &SlimeSim, with_medium: bool) {
     let slime = sim.frame().slime.clone();
-    record.frames.push(RecordFrame { slime });
+    let medium = with_medium.then(|| sim.frame().medium().data().to_vec());
+    record.frames.push(RecordFrame { slime, medium });
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -13,11 +20,29 @@ pub struct RecordFile {
     pub width: usize,
     pub height: usize,
     pub frames: Vec<RecordFrame>,
+    /// Missing from records made before it was stored
+    pub meta: Option<RecordMeta>,
+    /// Thumbnail made by the last save, and the number of frames it was made from
+    #[serde(skip)]
+    saved_thumbnail: Mutex<Option<(usize, Vec<u8>)>>,
+}
+
+/// How a record was made
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecordMeta {
+    #[serde(with = "toml_text")]
+    pub config: SlimeConfig,
+    /// Sim time between consecutive frames
+    pub dt: f32,
+    /// PNG of the trails over the whole record, made by `save` (see `render::thumbnail`)
+    pub thumbnail: Option<Vec<u8>>,
 }
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct RecordFrame {
     pub slime: Vec<SlimeParticle>,
+    /// Density of the medium, row-major with the record's width and height
+    pub medium: Option<Vec<f32>>,
 }
 
 impl RecordFile {
@@ -26,16 +51,417 @@ impl RecordFile {
             width,
             height,
             frames: vec![],
+            meta: None,
+            saved_thumbnail: Default::default(),
         }
     }
 
+    /// Read a record. Records from before metadata was stored, whose particles only had a
+    /// position, heading, origin and age, are converted as they're read
     pub fn load(path: &Path) -> Result<Self> {
-        let reader = BufReader::new(File::open(path)?);
-        Ok(bincode::deserialize_from(reader)?)
+        let reader = || -> Result<_> { Ok(BufReader::new(File::open(path)?)) };
+        // Older records might happen to parse, but leave bytes over
+        let mut current = reader()?;
+        let error = match bincode::deserialize_from::<_, Self>(&mut current) {
+            Ok(record) => {
+                if current.fill_buf()?.is_empty() {
+                    return Ok(record);
+                }
+                anyhow!("Unexpected data after the end of the record")
+            }
+            Err(e) => e.into(),
+        };
+
+        // If neither format fits, the current one's error is the more useful
+        let mut baseline = reader()?;
+        let frames: bincode::Result<(usize, usize, Vec<Vec<BaselineParticle>>)> =
+            bincode::deserialize_from(&mut baseline);
+        let (width, height, frames) = match frames {
+            Ok(frames) if baseline.fill_buf()?.is_empty() => frames,
+            _ => return Err(error.context(format!("Failed to read the record {}", path.display()))),
+        };
+        let center = Vector2::new(width as f32, height as f32) / 2.;
+        let frames = frames
+            .into_iter()
+            .map(|slime| RecordFrame {
+                slime: slime
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, p)| SlimeParticle {
+                        position: p.position,
+                        heading: p.heading,
+                        angular_velocity: 0.,
+                        handedness: 0.,
+                        state: AgentState::Exploring,
+                        trip_age: 0,
+                        origin: p.origin,
+                        age: p.age,
+                        // Particles were never added or removed, so they kept their places
+                        id: idx as u32,
+                        // Renderers used to color by three sectors of the origin
+                        group: spawn_group(p.origin, center, 3),
+                    })
+                    .collect(),
+                medium: None,
+            })
+            .collect();
+
+        Ok(Self {
+            width,
+            height,
+            frames,
+            meta: None,
+            saved_thumbnail: Default::default(),
+        })
+    }
+
+    /// Rough size in memory of the recorded frames
+    pub fn approx_bytes(&self) -> usize {
+        let frame_bytes = |f: &RecordFrame| {
+            std::mem::size_of::<RecordFrame>()
+                + f.slime.len() * std::mem::size_of::<SlimeParticle>()
+                + f.medium
+                    .as_ref()
+                    .map_or(0, |m| m.len() * std::mem::size_of::<f32>())
+        };
+        self.frames.iter().map(frame_bytes).sum()
     }
 
+    /// Written to a temporary file which then replaces `path`, so an interrupted save (or an
+    /// autosave cut short by a crash) never leaves a truncated record behind. The thumbnail in
+    /// the metadata is rendered from the frames, unless they're as many as at the last save
     pub fn save(&self, path: &Path) -> Result<()> {
-        let writer = BufWriter::new(File::create(path)?);
-        Ok(bincode::serialize_into(writer, self)?)
+        self.write(path, true)
+    }
+
+    /// Like `save`, but keeps the thumbnail of the last save rather than rendering a new one
+    /// every time. Before the first save, that's whichever thumbnail the record already has
+    pub fn autosave(&self, path: &Path) -> Result<()> {
+        self.write(path, false)
+    }
+
+    fn write(&self, path: &Path, render_thumbnail: bool) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let meta = match &self.meta {
+            Some(meta) => {
+                let mut saved = self.saved_thumbnail.lock().unwrap();
+                let png = match saved.as_ref() {
+                    Some((frames, png)) if *frames == self.frames.len() || !render_thumbnail => {
+                        Some(png.clone())
+                    }
+                    None if !render_thumbnail => meta.thumbnail.clone(),
+                    _ => {
+                        let png = thumbnail(self, THUMBNAIL_SIZE)?;
+                        *saved = Some((self.frames.len(), png.clone()));
+                        Some(png)
+                    }
+                };
+                Some(RecordMeta {
+                    thumbnail: png,
+                    ..meta.clone()
+                })
+            }
+            None => None,
+        };
+
+        // Serializes the same as the struct, without cloning the frames
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        bincode::serialize_into(&mut writer, &(self.width, self.height, &self.frames, meta))?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// What to do once a record outgrows its memory budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop recording, keeping the frames so far
+    Stop,
+    /// Drop every other frame, and from then on only record half as often
+    Thin,
+}
+
+impl Default for Overflow {
+    fn default() -> Self {
+        Overflow::Thin
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "stop" => Overflow::Stop,
+            "thin" => Overflow::Thin,
+            _ => bail!("Unknown overflow policy \"{}\"; expected stop or thin", s),
+        })
+    }
+}
+
+/// Keeps an in-memory record within a size limit, by deciding which of the sim's frames are
+/// recorded
+pub struct RecordBudget {
+    max_bytes: usize,
+    overflow: Overflow,
+    /// Only every Nth frame is recorded
+    stride: usize,
+    /// Frames offered so far
+    offered: usize,
+    stopped: bool,
+}
+
+impl RecordBudget {
+    pub fn new(max_mb: f32, overflow: Overflow) -> Self {
+        Self {
+            max_bytes: (max_mb.max(0.) * 1024. * 1024.) as usize,
+            overflow,
+            stride: 1,
+            offered: 0,
+            stopped: false,
+        }
+    }
+
+    /// Whether the sim's next frame should be recorded. Call once per frame
+    pub fn wants_frame(&mut self) -> bool {
+        let wanted = !self.stopped && self.offered % self.stride == 0;
+        self.offered += 1;
+        wanted
+    }
+
+    /// Apply the overflow policy if `record` has grown past the budget
+    pub fn enforce(&mut self, record: &mut RecordFile) {
+        if self.stopped || record.approx_bytes() <= self.max_bytes {
+            return;
+        }
+
+        match self.overflow {
+            Overflow::Stop => {
+                warn!(
+                    "Record is over budget; stopped after {} frames",
+                    record.frames.len()
+                );
+                self.stopped = true;
+            }
+            Overflow::Thin => {
+                let mut idx = 0;
+                record.frames.retain(|_| {
+                    idx += 1;
+                    idx % 2 == 1
+                });
+                // Frames are now twice as far apart
+                if let Some(meta) = &mut record.meta {
+                    meta.dt *= 2.;
+                }
+                self.stride *= 2;
+                warn!(
+                    "Record is over budget; keeping one frame in {}",
+                    self.stride
+                );
+            }
+        }
+    }
+}
+
+/// The config is stored as TOML text, so that records stay readable as parameters are added
+/// or regrouped
+mod toml_text {
+    use crate::sim::SlimeConfig;
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(config: &SlimeConfig, s: S) -> Result<S::Ok, S::Error> {
+        let text = toml::to_string(config).map_err(ser::Error::custom)?;
+        s.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SlimeConfig, D::Error> {
+        let text = String::deserialize(d)?;
+        SlimeConfig::from_toml(&text).map_err(de::Error::custom)
+    }
+}
+
+/// `SlimeParticle` in the first records
+#[derive(Deserialize)]
+struct BaselineParticle {
+    position: Vector2<f32>,
+    heading: Vector2<f32>,
+    origin: Vector2<f32>,
+    age: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("slime-{}-{}.slm", name, std::process::id()))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sim = SlimeSim::new(16, 16, 20, &mut rng);
+        let mut record = RecordFile::new(16, 16);
+        for step in 0..3 {
+            record_frame(&mut record, &sim, step == 1);
+            sim.step(&SlimeConfig::default(), 0.5, &mut rng);
+        }
+
+        let path = temp_path("round-trip");
+        record.save(&path).unwrap();
+        let loaded = RecordFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (16, 16));
+        assert_eq!(
+            bincode::serialize(&loaded.frames).unwrap(),
+            bincode::serialize(&record.frames).unwrap()
+        );
+    }
+
+    #[test]
+    fn loads_baseline_records() {
+        // Frames of particles, each a position, heading, origin and age, with nothing after
+        let particle = |x: f32, age: u32| {
+            let v = Vector2::new;
+            (v(x, 2.), v(1., 0.), v(x, 3.), age)
+        };
+        let frames = vec![
+            vec![particle(1., 0), particle(5., 0)],
+            vec![particle(2., 1), particle(6., 1)],
+        ];
+        let path = temp_path("baseline");
+        let file = BufWriter::new(File::create(&path).unwrap());
+        bincode::serialize_into(file, &(8usize, 8usize, frames)).unwrap();
+        let record = RecordFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((record.width, record.height), (8, 8));
+        assert!(record.meta.is_none());
+        assert_eq!(record.frames.len(), 2);
+        let p = &record.frames[1].slime[1];
+        assert_eq!(p.position, Vector2::new(6., 2.));
+        assert_eq!(p.heading, Vector2::new(1., 0.));
+        assert_eq!(p.origin, Vector2::new(6., 3.));
+        assert_eq!((p.age, p.id), (1, 1));
+        assert!(record.frames[1].medium.is_none());
+    }
+
+    #[test]
+    fn corrupt_records_report_the_current_format() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let sim = SlimeSim::new(16, 16, 20, &mut rng);
+        let mut record = RecordFile::new(16, 16);
+        record_frame(&mut record, &sim, false);
+
+        let path = temp_path("corrupt");
+        record.save(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.push(0);
+        std::fs::write(&path, &bytes).unwrap();
+        let err = RecordFile::load(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let message = format!("{:#}", err);
+        assert!(message.contains("Failed to read the record"), "{}", message);
+        assert!(message.contains("Unexpected data"), "{}", message);
+    }
+
+    #[test]
+    fn saves_replace_the_file_whole() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let sim = SlimeSim::new(16, 16, 20, &mut rng);
+        let mut record = RecordFile::new(16, 16);
+        record_frame(&mut record, &sim, false);
+
+        // Whatever was there before is replaced, through a temporary file which doesn't stay
+        let path = temp_path("replace");
+        std::fs::write(&path, b"not a record").unwrap();
+        record.autosave(&path).unwrap();
+        let loaded = RecordFile::load(&path).unwrap();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let leftover = Path::new(&tmp).exists();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.frames.len(), 1);
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn budgets_thin_or_stop_records() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let sim = SlimeSim::new(16, 16, 100, &mut rng);
+        let mut one_frame = RecordFile::new(16, 16);
+        record_frame(&mut one_frame, &sim, false);
+        // Room for four and a half frames
+        let max_mb = 4.5 * one_frame.approx_bytes() as f32 / (1024. * 1024.);
+
+        let record_ten = |overflow: Overflow| {
+            let mut budget = RecordBudget::new(max_mb, overflow);
+            let mut record = RecordFile::new(16, 16);
+            record.meta = Some(RecordMeta {
+                config: SlimeConfig::default(),
+                dt: 0.5,
+                thumbnail: None,
+            });
+            for _ in 0..10 {
+                if budget.wants_frame() {
+                    record_frame(&mut record, &sim, false);
+                    budget.enforce(&mut record);
+                }
+            }
+            record
+        };
+
+        // Thinned at the fifth frame and again at the fifth after, recorded every fourth frame
+        let thinned = record_ten(Overflow::Thin);
+        assert_eq!(thinned.frames.len(), 3);
+        assert_eq!(thinned.meta.unwrap().dt, 2.);
+
+        let stopped = record_ten(Overflow::Stop);
+        assert_eq!(stopped.frames.len(), 5);
+        assert_eq!(stopped.meta.unwrap().dt, 0.5);
+    }
+
+    #[test]
+    fn thumbnails_are_rendered_by_saves() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sim = SlimeSim::new(16, 16, 20, &mut rng);
+        let mut record = RecordFile::new(16, 16);
+        record.meta = Some(RecordMeta {
+            config: SlimeConfig::default(),
+            dt: 1.,
+            thumbnail: None,
+        });
+        let path = temp_path("thumbnail");
+        let saved_thumbnail = |record: &RecordFile, autosave: bool| {
+            if autosave {
+                record.autosave(&path).unwrap();
+            } else {
+                record.save(&path).unwrap();
+            }
+            RecordFile::load(&path).unwrap().meta.unwrap().thumbnail
+        };
+
+        record_frame(&mut record, &sim, false);
+        assert!(saved_thumbnail(&record, true).is_none());
+        let first = saved_thumbnail(&record, false).unwrap();
+        assert!(first.starts_with(b"\x89PNG"));
+
+        // Autosaves keep the last save's thumbnail, while saves catch up with new frames
+        for _ in 0..5 {
+            sim.step(&SlimeConfig::default(), 1., &mut rng);
+            record_frame(&mut record, &sim, false);
+        }
+        assert_eq!(saved_thumbnail(&record, true).unwrap(), first);
+        let second = saved_thumbnail(&record, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(second, first);
     }
 }
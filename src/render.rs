@@ -0,0 +1,50 @@
+//! Quick renders of whole records, for previews
+use crate::record::RecordFile;
+use anyhow::Result;
+use idek_basics::Array2D;
+
+/// Longest side of the thumbnails embedded in saved records
+pub const THUMBNAIL_SIZE: usize = 256;
+
+/// Everywhere the particles went over the whole record, at `width` by `height` and scaled to
+/// 0..1. Visits are counted logarithmically, so that faint paths still show next to busy ones
+pub fn trail_image(record: &RecordFile, width: usize, height: usize) -> Array2D<f32> {
+    let mut image = Array2D::new(width, height);
+    let sx = width as f32 / record.width.max(1) as f32;
+    let sy = height as f32 / record.height.max(1) as f32;
+    for part in record.frames.iter().flat_map(|frame| &frame.slime) {
+        let (x, y) = (part.position.x * sx, part.position.y * sy);
+        if x >= 0. && y >= 0. && (x as usize) < width && (y as usize) < height {
+            image[(x as usize, y as usize)] += 1.;
+        }
+    }
+
+    let max = image.data().iter().copied().fold(0., f32::max);
+    if max > 0. {
+        let norm = 1. / (1. + max).ln();
+        image
+            .data_mut()
+            .iter_mut()
+            .for_each(|v| *v = (1. + *v).ln() * norm);
+    }
+    image
+}
+
+/// Trail image of the record fitted within `size` by `size`, encoded as a grayscale PNG
+pub fn thumbnail(record: &RecordFile, size: usize) -> Result<Vec<u8>> {
+    let scale = size as f32 / record.width.max(record.height).max(1) as f32;
+    let width = ((record.width as f32 * scale).round() as usize).max(1);
+    let height = ((record.height as f32 * scale).round() as usize).max(1);
+    let pixels: Vec<u8> = trail_image(record, width, height)
+        .data()
+        .iter()
+        .map(|v| (v * 255.).round() as u8)
+        .collect();
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(png)
+}
@@ -1,55 +1,669 @@
+use anyhow::{anyhow, bail, Context, Result};
+use crate::expr::{ForceExpr, Modulation};
+use crate::forcing::Forcing;
+use crate::gpu::{ChannelRates, GpuDiffusion};
+use crate::image::{resample, resample_bilinear};
+use crate::noise::perlin3;
+use crate::spatial::SpatialHash;
 use idek_basics::Array2D;
 use nalgebra::{Rotation2, Vector1, Vector2};
 use rand::{distributions::Uniform, prelude::*};
-use std::f32::consts::TAU;
-use structopt::StructOpt;
+use std::collections::BTreeMap;
+use std::f32::consts::{PI, TAU};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use structopt::{clap::ArgMatches, StructOpt};
+use tracing::{info_span, warn};
 use serde::{Serialize, Deserialize};
 
-#[derive(Clone, Default, Debug, StructOpt)]
+// Each group is a table in config files, but the flags stay flat. The groups are described
+// with plain comments, since structopt would take doc comments as the help text
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+// Missing keys in config files take the same defaults as the flags
+#[serde(default, deny_unknown_fields)]
 pub struct SlimeConfig {
+    #[structopt(flatten)]
+    pub agent: AgentConfig,
+
+    #[structopt(flatten)]
+    pub medium: MediumConfig,
+
+    #[structopt(flatten)]
+    pub food: FoodConfig,
+
+    #[structopt(flatten)]
+    pub population: PopulationConfig,
+
+    #[structopt(flatten)]
+    pub perturb: PerturbConfig,
+
+    #[structopt(flatten)]
+    pub forcing: ForcingConfig,
+
+    #[structopt(flatten)]
+    pub modulation: ModulationConfig,
+
+    // Only set in config files, as [[zones]] tables
+    #[structopt(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<Zone>,
+}
+
+/// A region of the grid with parameters of its own, which override the rest of the config for
+/// the particles in it and for the decay and diffusion of the medium under it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Zone {
+    /// x, y, width and height of the rectangle covered, in cells
+    pub rect: Option<[f32; 4]>,
+    /// Level (0-255) of the zone map which marks this zone (see `SlimeSim::set_zone_map`)
+    pub index: Option<u8>,
+    /// Parameters by the names `SlimeConfig::set_param` takes, e.g. decay = 0.2
+    pub params: BTreeMap<String, f32>,
+}
+
+impl Zone {
+    /// Whether the zone covers `pos`, on a cell of the zone map at `level`
+    fn contains(&self, pos: Vector2<f32>, level: Option<u8>) -> bool {
+        let in_rect = self.rect.map_or(false, |[x, y, w, h]| {
+            (x..x + w).contains(&pos.x) && (y..y + h).contains(&pos.y)
+        });
+        in_rect || (self.index.is_some() && self.index == level)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// How particles sense, steer and move
+pub struct AgentConfig {
     /// Angle between adjacent sensors (radians)
     #[structopt(short = "s", long, default_value = "0.8")]
-    sensor_spread: f32,
+    pub sensor_spread: f32,
 
     /// Turn rate, radians/time
     #[structopt(short = "r", long, default_value = "1.8")]
-    turn_speed: f32,
+    pub turn_speed: f32,
+
+    /// Enables inertial steering with this angular damping rate. Turn speed then acts as an
+    /// angular acceleration (radians/time^2)
+    #[structopt(long)]
+    pub angular_damping: Option<f32>,
+
+    /// Turning bias used to break ties between sensors; -1 always turns right, 1 always left
+    #[structopt(long, default_value = "0.0")]
+    pub chirality: f32,
+
+    /// Per-particle variation of the turning bias
+    #[structopt(long, default_value = "0.0")]
+    pub chirality_spread: f32,
+
+    /// Slime movement speed
+    #[structopt(short = "m", long, default_value = "1.0")]
+    pub move_speed: f32,
+
+    /// Particles sensing more than this density switch from exploring to exploiting
+    #[structopt(long)]
+    pub exploit_threshold: Option<f32>,
+
+    /// Particles head back to their origin after this many steps away from it
+    #[structopt(long)]
+    pub homing_after: Option<u32>,
+
+    /// Chance per step of a particle heading back to its origin
+    #[structopt(long, default_value = "0.0")]
+    pub homing_probability: f32,
+
+    /// Particles which make it back to their origin respawn there, as new particles with a
+    /// random heading
+    #[structopt(long)]
+    pub respawn_at_origin: bool,
+
+    /// Particles straying further than this from their origin are steered back, harder the
+    /// further out they are
+    #[structopt(long)]
+    pub home_range: Option<f32>,
+
+    /// Respawn particles which leave their home range at their origin, instead of steering them
+    /// back
+    #[structopt(long)]
+    pub home_range_respawn: bool,
+
+    /// Per-state turn speeds (exploring, exploiting, homing), comma separated. Missing states
+    /// use `turn-speed`
+    #[structopt(long, use_delimiter = true)]
+    pub state_turn_speed: Vec<f32>,
+
+    /// Per-state move speeds (exploring, exploiting, homing), comma separated. Missing states
+    /// use `move-speed`
+    #[structopt(long, use_delimiter = true)]
+    pub state_move_speed: Vec<f32>,
+
+    /// Use the Jones (2010) motor stage: particles only move into unoccupied cells, and pick a
+    /// new random heading (without depositing) when the way is blocked
+    #[structopt(long)]
+    pub jones_motor: bool,
+
+    /// Enables wall following: particles near obstacles steer parallel to them at this rate, so
+    /// that trails hug stencils instead of jittering against them
+    #[structopt(long)]
+    pub wall_following: Option<f32>,
+
+    /// Distance within which obstacle cells count for wall following
+    #[structopt(long, default_value = "3.0")]
+    pub wall_range: f32,
+
+    /// Round particle positions, headings and angular velocities to fixed point with this many
+    /// fraction bits after every step, so that differences in float rounding between platforms
+    /// and optimization levels don't accumulate
+    #[structopt(long)]
+    pub fixed_point: Option<u32>,
 
+    /// Enables a directional trail: deposits also store the depositing particle's heading, which
+    /// decays like the medium, and particles line up with it at this rate. Gives laminar,
+    /// hair-like structures
+    #[structopt(long)]
+    pub heading_alignment: Option<f32>,
+
+    /// Particles passing within this distance of each other both deposit a burst and scatter in
+    /// random directions, which marks the junctions of the network
+    #[structopt(long)]
+    pub collision_radius: Option<f32>,
+
+    /// Deposit of a collision burst, as a multiple of the usual deposit
+    #[structopt(long, default_value = "5.0")]
+    pub collision_burst: f32,
+
+    /// Distance from the particle to its sensors
+    #[structopt(short = "u", long, alias = "sample-dist", default_value = "3.0")]
+    #[serde(alias = "sample_dist")]
+    pub sensor_distance: f32,
+
+    /// Random amount added to each sensor reading, uniform within plus or minus this
+    #[structopt(long, default_value = "0.0")]
+    pub sensor_noise: f32,
+
+    /// Random fraction by which each sensor reading is scaled up or down, uniform within plus
+    /// or minus this
+    #[structopt(long, default_value = "0.0")]
+    pub sensor_noise_relative: f32,
+
+    /// Distance by which sensors are pushed around by a noise field
+    #[structopt(long, default_value = "0.0")]
+    pub warp_strength: f32,
+
+    /// Spatial frequency of the sensor noise field (1/cells)
+    #[structopt(long, default_value = "0.02")]
+    pub warp_scale: f32,
+
+    /// How quickly the sensor noise field evolves over time
+    #[structopt(long, default_value = "0.01")]
+    pub warp_speed: f32,
+
+    /// How much the cost field slows particles down: speed / (1 + cost-speed * cost)
+    #[structopt(long, default_value = "1.0")]
+    pub cost_speed: f32,
+
+    /// How much the cost field speeds up turning: turn speed * (1 + cost-turn * cost)
+    #[structopt(long, default_value = "0.0")]
+    pub cost_turn: f32,
+
+    /// Constant force on particles along x (e.g. gravity)
+    #[structopt(long, default_value = "0.0")]
+    pub force_x: f32,
+
+    /// Constant force on particles along y
+    #[structopt(long, default_value = "0.0")]
+    pub force_y: f32,
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// Deposition, decay and diffusion of the chemical medium
+pub struct MediumConfig {
     /// Trail/slime decay rate
     #[structopt(short = "d", long, default_value = "0.05")]
-    decay: f32,
+    pub decay: f32,
+
+    /// Scale the local decay rate by the strength of the force field at each cell, so that windy
+    /// regions erase trails faster: decay * (1 + flow_decay * |force|). There is no fluid in the
+    /// sim, so the force on particles there (constant force, forcing pattern and force field)
+    /// stands in for the fluid speed
+    #[structopt(long)]
+    pub flow_decay: Option<f32>,
 
     /// Deposit rate for slime
     #[structopt(short = "e", long, default_value = "1.0")]
-    deposit_rate: f32,
+    pub deposit_rate: f32,
 
-    /// Slime movement speed
-    #[structopt(short = "m", long, default_value = "1.0")]
-    move_speed: f32,
+    /// Maximum slime density per cell, applied when depositing
+    #[structopt(long)]
+    pub max_density: Option<f32>,
 
-    /// Sample distance
-    #[structopt(short = "u", long, default_value = "3.0")]
-    sample_dist: f32,
+    /// Make `max-density` a soft cap; deposits shrink as the cell fills up instead of clamping
+    #[structopt(long)]
+    pub soft_cap: bool,
 
     /// Diffusion rate of the medium
     #[structopt(short = "i", long, default_value = "0.1")]
-    diffusion: f32,
+    pub diffusion: f32,
+
+    /// Diffusion rate along x only. Setting this or `diffusion-y` makes diffusion anisotropic
+    #[structopt(long)]
+    pub diffusion_x: Option<f32>,
+
+    /// Diffusion rate along y only. Setting this or `diffusion-x` makes diffusion anisotropic
+    #[structopt(long)]
+    pub diffusion_y: Option<f32>,
+
+    /// Symmetrize the medium under this many rotations about the center each step
+    #[structopt(long)]
+    pub symmetry: Option<usize>,
+
+    /// Also mirror the medium when symmetrizing
+    #[structopt(long)]
+    pub mirror: bool,
+
+    /// Number of chemical channels in the medium
+    #[structopt(long, default_value = "1")]
+    pub channels: usize,
+
+    /// Per-channel decay rates, comma separated. Missing channels use `decay`
+    #[structopt(long, use_delimiter = true)]
+    pub channel_decay: Vec<f32>,
+
+    /// Per-channel diffusion rates, comma separated. Missing channels use `diffusion`
+    #[structopt(long, use_delimiter = true)]
+    pub channel_diffusion: Vec<f32>,
+
+    /// How strongly particles follow each channel, comma separated. Defaults to just channel 0
+    #[structopt(long, use_delimiter = true, allow_hyphen_values = true)]
+    pub sense_weights: Vec<f32>,
+
+    /// How much particles deposit into each channel, comma separated. Defaults to just channel 0
+    #[structopt(long, use_delimiter = true, allow_hyphen_values = true)]
+    pub deposit_weights: Vec<f32>,
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// Food placed with `SlimeSim::set_food`
+pub struct FoodConfig {
+    /// How strongly particles are attracted to food, relative to the medium
+    #[structopt(long = "food-attraction", default_value = "1.0")]
+    #[serde(alias = "food_attraction")]
+    pub attraction: f32,
+
+    /// Rate at which particles eat the food in their cell
+    #[structopt(long = "food-consumption", default_value = "0.0")]
+    #[serde(alias = "food_consumption")]
+    pub consumption: f32,
+
+    /// Logistic regrowth rate of eaten food
+    #[structopt(long = "food-regrowth", default_value = "0.0")]
+    #[serde(alias = "food_regrowth")]
+    pub regrowth: f32,
+
+    /// Particles which find food head back to their origin
+    #[structopt(long)]
+    pub home_with_food: bool,
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// Births and deaths
+pub struct PopulationConfig {
+    /// Particles on cells denser than this may split in two
+    #[structopt(long)]
+    pub split_threshold: Option<f32>,
+
+    /// Probability per step of splitting on a dense cell
+    #[structopt(long, default_value = "0.01")]
+    pub split_probability: f32,
+
+    /// Maximum heading perturbation of split-off children (radians)
+    #[structopt(long, default_value = "0.5")]
+    pub split_spread: f32,
+
+    /// Number of groups particles are tagged with at spawn, by the sector around the center
+    /// that they spawn in
+    #[structopt(long, default_value = "3")]
+    pub groups: u32,
+
+    /// Population cap for splitting
+    #[structopt(long)]
+    pub max_particles: Option<usize>,
+
+    /// Keep the population near this size by adjusting birth and death rates each step
+    #[structopt(long)]
+    pub target_population: Option<usize>,
+
+    /// How aggressively the population controller corrects towards the target
+    #[structopt(long = "population-gain", default_value = "0.1")]
+    #[serde(alias = "population_gain")]
+    pub gain: f32,
+
+    /// Grow the population to this size over --grow-over steps, by budding new particles off
+    /// random existing ones
+    #[structopt(long)]
+    pub grow_to: Option<usize>,
+
+    /// Step by which the population reaches --grow-to
+    #[structopt(long, default_value = "5000")]
+    pub grow_over: usize,
+
+    /// Steps between batches of new particles while growing
+    #[structopt(long, default_value = "10")]
+    pub grow_every: usize,
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// Periodic disturbances to the sim
+pub struct PerturbConfig {
+    /// Apply the perturbation events below every this many steps
+    #[structopt(long = "perturb-every")]
+    #[serde(alias = "perturb_every")]
+    pub every: Option<usize>,
+
+    /// Radius of the region of the medium wiped by each perturbation
+    #[structopt(long = "perturb-wipe-radius", default_value = "0.0")]
+    #[serde(alias = "perturb_wipe_radius")]
+    pub wipe_radius: f32,
+
+    /// Fraction of particles given a random heading by each perturbation
+    #[structopt(long = "perturb-scramble", default_value = "0.0")]
+    #[serde(alias = "perturb_scramble")]
+    pub scramble: f32,
+
+    /// Peak density of the blob injected by each perturbation
+    #[structopt(long = "perturb-blob", default_value = "0.0")]
+    #[serde(alias = "perturb_blob")]
+    pub blob: f32,
+
+    /// Radius of the blob injected by each perturbation
+    #[structopt(long = "perturb-blob-radius", default_value = "10.0")]
+    #[serde(alias = "perturb_blob_radius")]
+    pub blob_radius: f32,
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// Built-in force patterns, on top of the constant force and any force field
+pub struct ForcingConfig {
+    /// Force pattern applied to particles: vortex-pair, jet, shear or stir
+    #[structopt(long = "forcing")]
+    pub pattern: Option<Forcing>,
+
+    /// Peak force of the pattern
+    #[structopt(long = "forcing-strength", default_value = "0.5")]
+    pub strength: f32,
+
+    /// Size of the pattern's vortex cores, jet, bands or eddies (cells)
+    #[structopt(long = "forcing-scale", default_value = "40.0")]
+    pub scale: f32,
+
+    /// How long it takes the stir pattern to change completely
+    #[structopt(long = "forcing-correlation-time", default_value = "50.0")]
+    pub correlation_time: f32,
+
+    /// Force along x as an expression of x, y, t, width and height, e.g. "sin(t*0.1)*x/width".
+    /// Adds to the pattern
+    #[structopt(long = "force-expr-x")]
+    pub expr_x: Option<ForceExpr>,
+
+    /// Force along y as an expression of x, y, t, width and height. Adds to the pattern
+    #[structopt(long = "force-expr-y")]
+    pub expr_y: Option<ForceExpr>,
+}
+
+impl ForcingConfig {
+    /// Force of the pattern and expressions (if any) at `pos` on a torus of the given size at
+    /// time `t`
+    pub fn force(&self, pos: Vector2<f32>, size: Vector2<f32>, t: f32) -> Vector2<f32> {
+        let pattern = self.pattern.map_or(Vector2::zeros(), |pattern| {
+            pattern.force(
+                pos,
+                size,
+                self.strength,
+                self.scale,
+                self.correlation_time,
+                t,
+            )
+        });
+        let expr = |e: &Option<ForceExpr>| {
+            e.as_ref()
+                .map_or(0., |e| e.eval(pos.x, pos.y, t, size.x, size.y))
+        };
+        pattern + Vector2::new(expr(&self.expr_x), expr(&self.expr_y))
+    }
+}
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+// Parameters following expressions of sim time
+pub struct ModulationConfig {
+    /// Drive a parameter with an expression of t and step, e.g. "decay=0.05+0.04*sin(t*0.1)".
+    /// Zones still override it
+    #[structopt(long)]
+    pub modulate: Vec<Modulation>,
+}
+
+/// Defaults are those of the flags, so they're only written down once
+macro_rules! default_from_flags {
+    ($($ty:ty),*) => {$(
+        impl Default for $ty {
+            fn default() -> Self {
+                Self::from_iter(["slime"])
+            }
+        }
+    )*};
+}
+
+default_from_flags!(
+    SlimeConfig,
+    AgentConfig,
+    MediumConfig,
+    FoodConfig,
+    PopulationConfig,
+    PerturbConfig,
+    ForcingConfig,
+    ModulationConfig
+);
+
+impl SlimeConfig {
+    /// Read a TOML config file, as written by `save`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::from_toml(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, toml::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Parse a config with a table per group. Older, flat configs are also accepted
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let value: toml::Value = toml::from_str(text)?;
+        let is_flat = value.as_table().map_or(false, |t| {
+            t.iter().any(|(k, v)| !v.is_table() && k != "zones")
+        });
+        if is_flat {
+            Self::from_flat(&value)
+        } else {
+            let config: Self = value.try_into()?;
+            config.check_zones()?;
+            Ok(config)
+        }
+    }
+
+    /// Check the parameter names of the zones now, as they're only applied once the sim runs
+    fn check_zones(&self) -> Result<()> {
+        for (k, zone) in self.zones.iter().enumerate() {
+            let mut cfg = self.clone();
+            for (name, &value) in &zone.params {
+                cfg.set_param(name, value)
+                    .with_context(|| format!("In zone {}", k + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flat configs used the flag names as keys, so they are read as if they were flags. This
+    /// also takes care of renamed parameters
+    fn from_flat(value: &toml::Value) -> Result<Self> {
+        let mut args = vec!["slime".to_string()];
+        for (key, value) in value.as_table().into_iter().flatten() {
+            let flag = format!("--{}", key.replace('_', "-"));
+            match value {
+                toml::Value::Boolean(true) => args.push(flag),
+                toml::Value::Boolean(false) => (),
+                toml::Value::Array(items) if items.is_empty() => (),
+                toml::Value::Array(items) => {
+                    let items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                    args.push(format!("{}={}", flag, items.join(",")));
+                }
+                other => args.push(format!("{}={}", flag, other)),
+            }
+        }
+        Self::from_iter_safe(args).map_err(|e| anyhow!(e.message))
+    }
+
+    /// Take the parameters which were given explicitly on the command line from `cli`, and the
+    /// rest from `self`. `matches` must be from the command line `cli` was parsed from
+    pub fn with_overrides(&self, cli: &SlimeConfig, matches: &ArgMatches) -> Result<Self> {
+        let mut merged = toml::Value::try_from(self)?;
+        let cli = toml::Value::try_from(cli)?;
+        let groups = cli.as_table().into_iter().flatten();
+        for (group, params) in groups {
+            for (key, value) in params.as_table().into_iter().flatten() {
+                // Flags are named after the fields in kebab-case, config keys in snake_case
+                if matches.occurrences_of(key.replace('_', "-")) > 0 {
+                    merged
+                        .get_mut(group)
+                        .and_then(toml::Value::as_table_mut)
+                        .context("Missing config group")?
+                        .insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Ok(merged.try_into()?)
+    }
+
+    /// The config with the modulated parameters set to their values at sim time `t` and the
+    /// given step
+    pub fn modulated(&self, t: f32, step: usize) -> Self {
+        let mut cfg = self.clone();
+        for m in &self.modulation.modulate {
+            // Names were checked when the modulation was parsed
+            let _ = cfg.set_param(&m.param, m.value(t, step));
+        }
+        cfg
+    }
+
+    /// Set a numeric parameter by name, e.g. `decay` or `turn-speed`. Optional parameters are
+    /// enabled by setting them.
+    pub fn set_param(&mut self, name: &str, value: f32) -> Result<()> {
+        match name.replace('-', "_").as_str() {
+            "sensor_spread" => self.agent.sensor_spread = value,
+            "turn_speed" => self.agent.turn_speed = value,
+            "chirality" => self.agent.chirality = value,
+            "homing_probability" => self.agent.homing_probability = value,
+            "home_range" => self.agent.home_range = Some(value),
+            "chirality_spread" => self.agent.chirality_spread = value,
+            "decay" => self.medium.decay = value,
+            "deposit_rate" => self.medium.deposit_rate = value,
+            "move_speed" => self.agent.move_speed = value,
+            "sensor_distance" | "sample_dist" => self.agent.sensor_distance = value,
+            "sensor_noise" => self.agent.sensor_noise = value,
+            "sensor_noise_relative" => self.agent.sensor_noise_relative = value,
+            "warp_strength" => self.agent.warp_strength = value,
+            "warp_scale" => self.agent.warp_scale = value,
+            "warp_speed" => self.agent.warp_speed = value,
+            "diffusion" => self.medium.diffusion = value,
+            "food_attraction" => self.food.attraction = value,
+            "food_consumption" => self.food.consumption = value,
+            "food_regrowth" => self.food.regrowth = value,
+            "split_probability" => self.population.split_probability = value,
+            "split_spread" => self.population.split_spread = value,
+            "population_gain" => self.population.gain = value,
+            "perturb_wipe_radius" => self.perturb.wipe_radius = value,
+            "perturb_scramble" => self.perturb.scramble = value,
+            "perturb_blob" => self.perturb.blob = value,
+            "perturb_blob_radius" => self.perturb.blob_radius = value,
+            "cost_speed" => self.agent.cost_speed = value,
+            "cost_turn" => self.agent.cost_turn = value,
+            "force_x" => self.agent.force_x = value,
+            "force_y" => self.agent.force_y = value,
+            "angular_damping" => self.agent.angular_damping = Some(value),
+            "flow_decay" => self.medium.flow_decay = Some(value),
+            "max_density" => self.medium.max_density = Some(value),
+            "exploit_threshold" => self.agent.exploit_threshold = Some(value),
+            "heading_alignment" => self.agent.heading_alignment = Some(value),
+            "collision_radius" => self.agent.collision_radius = Some(value),
+            "collision_burst" => self.agent.collision_burst = value,
+            "wall_following" => self.agent.wall_following = Some(value),
+            "wall_range" => self.agent.wall_range = value,
+            "fixed_point" => self.agent.fixed_point = Some(value as u32),
+            "diffusion_x" => self.medium.diffusion_x = Some(value),
+            "diffusion_y" => self.medium.diffusion_y = Some(value),
+            "split_threshold" => self.population.split_threshold = Some(value),
+            "forcing_strength" => self.forcing.strength = value,
+            "forcing_scale" => self.forcing.scale = value,
+            "forcing_correlation_time" => self.forcing.correlation_time = value,
+            _ => bail!("Unknown parameter \"{}\"", name),
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct SlimeParticle {
     pub position: Vector2<f32>,
     pub heading: Vector2<f32>,
+    /// Only used with the inertial steering model (radians/time)
+    pub angular_velocity: f32,
+    /// Individual turning bias in -1..1, scaled by `chirality-spread`
+    pub handedness: f32,
+    pub state: AgentState,
+    /// Steps since the particle last left its origin
+    pub trip_age: u32,
     pub origin: Vector2<f32>,
     pub age: u32,
+    /// Unique for the lifetime of the particle, so renderers can pair particles across frames
+    /// when the population changes
+    pub id: u32,
+    /// Cohort given by the spawn region, which renderers color consistently. Children inherit
+    /// their parent's
+    pub group: u32,
+}
+
+/// What a particle is currently up to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AgentState {
+    /// Wandering around following the medium
+    Exploring,
+    /// Sitting on a dense trail
+    Exploiting,
+    /// Heading back to its origin
+    Homing,
 }
 
 #[derive(Clone)]
 pub struct SlimeData {
-    pub medium: Array2D<f32>,
+    /// One grid per chemical channel. There is always at least one.
+    pub channels: Vec<Array2D<f32>>,
     pub slime: Vec<SlimeParticle>,
 }
 
+impl SlimeData {
+    /// The primary channel, which is the one shown by the renderers
+    pub fn medium(&self) -> &Array2D<f32> {
+        &self.channels[0]
+    }
+}
+
 pub struct SlimeSim {
     /// The buffer to be presented to the user and read by the sim
     front: SlimeData,
@@ -57,27 +671,88 @@ pub struct SlimeSim {
     back: SlimeData,
     /// Slime factory
     factory: SlimeFactory,
+    /// Per-cell force applied to particles, in addition to the constant force in the config
+    force_field: Option<Array2D<Vector2<f32>>>,
+    /// Per-cell multiplier on move speed and deposit rate
+    temperature: Option<Array2D<f32>>,
+    /// Per-cell cost of moving through, which slows particles down and/or makes them turn faster
+    cost: Option<Array2D<f32>>,
+    /// Cells which particles, slime and sensors cannot pass through
+    obstacles: Option<Array2D<bool>>,
+    /// Level of each cell, which selects the zone of the config with the same index
+    zone_map: Option<Array2D<u8>>,
+    /// Food which attracts particles, and which they consume
+    food: Option<FoodField>,
+    /// Sim time at which each cell was last deposited into, or minus infinity
+    last_deposit: Array2D<f32>,
+    /// Sum of the headings deposited into each cell, present with `heading-alignment`
+    direction: Option<Array2D<Vector2<f32>>>,
+    /// Positions of the front buffer's particles, for neighbor queries
+    spatial: SpatialHash,
+    /// Compute pass for the decay and diffusion of the medium, if it runs on the GPU
+    gpu: Option<GpuDiffusion>,
+    /// Number of steps taken so far
+    steps: usize,
+    /// Simulation time so far
+    time: f32,
+    /// Largest population seen, to notice it collapsing
+    peak_population: usize,
+    /// Problems which have been warned about already, so that they're only reported once
+    warned_nan: bool,
+    warned_collapse: bool,
+    warned_gpu: bool,
+}
+
+struct FoodField {
+    amount: Array2D<f32>,
+    /// Carrying capacity of each cell for regrowth
+    capacity: Array2D<f32>,
 }
 
+/// Steps between checks for non-finite densities and population collapse
+const HEALTH_CHECK_INTERVAL: usize = 100;
+
+/// Size of the cells of the spatial index. Queries are fastest for radii of about this size
+const SPATIAL_CELL_SIZE: f32 = 8.;
+
 fn unit_circ(a: f32) -> Vector2<f32> {
     Vector2::new(a.cos(), a.sin())
 }
 
 impl SlimeSim {
     pub fn new(width: usize, height: usize, n_particles: usize, mut rng: impl Rng) -> Self {
-        let factory = SlimeFactory::new(width, height);
+        let mut factory = SlimeFactory::new(width, height);
 
         let slime = (0..n_particles).map(|_| factory.slime(&mut rng)).collect();
 
         let front = SlimeData {
             slime,
-            medium: Array2D::new(width, height),
+            channels: vec![Array2D::new(width, height)],
         };
 
+        let mut spatial = SpatialHash::new(width, height, SPATIAL_CELL_SIZE);
+        spatial.rebuild(front.slime.iter().map(|p| p.position));
+
         Self {
             back: front.clone(),
             front,
             factory,
+            force_field: None,
+            temperature: None,
+            cost: None,
+            obstacles: None,
+            zone_map: None,
+            food: None,
+            last_deposit: Array2D::from_array(width, vec![f32::NEG_INFINITY; width * height]),
+            direction: None,
+            spatial,
+            gpu: None,
+            steps: 0,
+            time: 0.,
+            peak_population: n_particles,
+            warned_nan: false,
+            warned_collapse: false,
+            warned_gpu: false,
         }
     }
 
@@ -85,52 +760,302 @@ impl SlimeSim {
         &self.front
     }
 
-    pub fn step(&mut self, cfg: &SlimeConfig, dt: f32, mut rng: impl Rng) {
-        // Diffusion and decay
-        for y in 0..self.front.medium.height() {
-            for x in 0..self.front.medium.width() {
-                let mut sum = 0.;
-                let mut n_parts = 0;
-                for i in -1..=1 {
-                    for j in -1..=1 {
-                        if let Some(v) =
-                            sample_array_isize(&self.front.medium, j + x as isize, i + y as isize)
-                        {
-                            sum += v;
-                            n_parts += 1;
-                        }
-                    }
-                }
+    /// The state before and after the last step, as (previous, current), for drawing each
+    /// particle's motion over the step. Particles mostly keep their index across a step, but
+    /// respawned ones get new ids and new ones are added, so check ids match before pairing
+    /// them up. Both are the same before the first step, and after `set_particles` or `resize`
+    pub fn frames(&self) -> (&SlimeData, &SlimeData) {
+        (&self.back, &self.front)
+    }
+
+    /// Number of steps taken so far
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Simulation time so far
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// A new particle at `position` heading along `heading`, tagged as if it had spawned there.
+    /// It only joins the sim through `set_particles`
+    pub fn new_particle(
+        &mut self,
+        position: Vector2<f32>,
+        heading: Vector2<f32>,
+        rng: impl Rng,
+    ) -> SlimeParticle {
+        SlimeParticle {
+            position,
+            origin: position,
+            heading,
+            group: spawn_group(position, self.factory.center, self.factory.groups),
+            ..self.factory.slime(rng)
+        }
+    }
+
+    /// Replace the population, e.g. with a starting arrangement loaded from a file (see the init
+    /// module). Ids are reassigned, so that they stay unique
+    pub fn set_particles(&mut self, mut slime: Vec<SlimeParticle>) {
+        for part in &mut slime {
+            part.id = self.factory.next_id();
+        }
+        self.spatial.rebuild(slime.iter().map(|p| p.position));
+        self.peak_population = slime.len();
+        self.back.slime = slime.clone();
+        self.front.slime = slime;
+    }
+
+    /// Resize the grid in place, resampling the medium and every per-cell field bilinearly (the
+    /// obstacles by nearest neighbor), and scaling the particles' positions to match. Parameters
+    /// measured in cells, like move speed and sensor distance, are left as they are
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let (old_w, old_h) = (self.front.medium().width(), self.front.medium().height());
+        let scale = Vector2::new(width as f32 / old_w as f32, height as f32 / old_h as f32);
+
+        for channel in &mut self.front.channels {
+            *channel = resample_bilinear(channel, width, height);
+        }
+        for part in &mut self.front.slime {
+            part.position = part.position.component_mul(&scale);
+            part.origin = part.origin.component_mul(&scale);
+        }
+        self.back = self.front.clone();
+
+        let fields = [&mut self.temperature, &mut self.cost];
+        for field in fields.into_iter().flatten() {
+            *field = resample_bilinear(field, width, height);
+        }
+        if let Some(force) = &mut self.force_field {
+            *force = resample_bilinear(force, width, height);
+        }
+        if let Some(obstacles) = &mut self.obstacles {
+            *obstacles = resample(obstacles, width, height);
+        }
+        if let Some(gpu) = &mut self.gpu {
+            gpu.obstacles_changed();
+        }
+        if let Some(zone_map) = &mut self.zone_map {
+            *zone_map = resample(zone_map, width, height);
+        }
+        self.last_deposit = resample(&self.last_deposit, width, height);
+        if let Some(direction) = &mut self.direction {
+            *direction = resample_bilinear(direction, width, height);
+        }
+        if let Some(food) = &mut self.food {
+            food.amount = resample_bilinear(&food.amount, width, height);
+            food.capacity = resample_bilinear(&food.capacity, width, height);
+        }
+
+        let factory = SlimeFactory::new(width, height);
+        self.factory = SlimeFactory {
+            groups: self.factory.groups,
+            next_id: self.factory.next_id,
+            ..factory
+        };
+        self.spatial = SpatialHash::new(width, height, SPATIAL_CELL_SIZE);
+        self.spatial
+            .rebuild(self.front.slime.iter().map(|p| p.position));
+    }
+
+    /// Particles of the current frame within `radius` of `pos`, measured the short way around
+    /// the edges
+    pub fn particles_within(
+        &self,
+        pos: Vector2<f32>,
+        radius: f32,
+    ) -> impl Iterator<Item = &SlimeParticle> + '_ {
+        self.spatial
+            .within(pos, radius)
+            .map(move |idx| &self.front.slime[idx])
+    }
+
+    /// Set the per-cell force field. Must be the same size as the medium.
+    pub fn set_force_field(&mut self, force_field: Option<Array2D<Vector2<f32>>>) {
+        self.force_field = force_field;
+    }
+
+    /// Set the temperature field, which scales local move speed and deposit rate. Must be the
+    /// same size as the medium.
+    pub fn set_temperature(&mut self, temperature: Option<Array2D<f32>>) {
+        self.temperature = temperature;
+    }
+
+    /// Set the cost field, which scales local move speed and turn rate through `cost-speed` and
+    /// `cost-turn`. Must be the same size as the medium.
+    pub fn set_cost(&mut self, cost: Option<Array2D<f32>>) {
+        self.cost = cost;
+    }
+
+    /// Set the obstacle mask. Must be the same size as the medium.
+    pub fn set_obstacles(&mut self, obstacles: Option<Array2D<bool>>) {
+        self.obstacles = obstacles;
+        if let Some(gpu) = &mut self.gpu {
+            gpu.obstacles_changed();
+        }
+    }
+
+    /// Decay and diffuse the medium on the GPU from now on (see the gpu module). Zones and
+    /// flow decay still need the CPU, which takes over the steps using them. Needs the gpu
+    /// feature
+    pub fn use_gpu(&mut self) -> Result<()> {
+        self.gpu = Some(GpuDiffusion::new()?);
+        Ok(())
+    }
+
+    /// Set the zone map, whose levels pick out the config's zones by their `index`. Must be the
+    /// same size as the medium.
+    pub fn set_zone_map(&mut self, zone_map: Option<Array2D<u8>>) {
+        self.zone_map = zone_map;
+    }
 
-                let avg = sum / n_parts as f32;
+    /// Set the food field, which also becomes the carrying capacity for regrowth. Must be the
+    /// same size as the medium.
+    pub fn set_food(&mut self, food: Option<Array2D<f32>>) {
+        self.food = food.map(|amount| FoodField {
+            capacity: amount.clone(),
+            amount,
+        });
+    }
+
+    /// Food remaining in each cell, if there is a food field
+    pub fn food(&self) -> Option<&Array2D<f32>> {
+        self.food.as_ref().map(|food| &food.amount)
+    }
+
+    /// Sim time at which each cell was last deposited into, or minus infinity if it never was
+    pub fn last_deposit(&self) -> &Array2D<f32> {
+        &self.last_deposit
+    }
 
-                let pos = (x, y);
-                let center = self.front.medium[pos];
+    /// How recently each cell was deposited into, from 1 for the last step down towards 0 for
+    /// cells untouched for much longer than `fade` (sim time). For coloring trails by age
+    pub fn freshness(&self, fade: f32) -> Array2D<f32> {
+        let fade = fade.max(1e-6);
+        let data = self
+            .last_deposit
+            .data()
+            .iter()
+            .map(|&last| (-(self.time - last) / fade).exp())
+            .collect();
+        Array2D::from_array(self.last_deposit.width(), data)
+    }
 
-                let diffuse = mix(center, avg, cfg.diffusion);
+    pub fn step(&mut self, cfg: &SlimeConfig, dt: f32, mut rng: impl Rng) {
+        let _span = info_span!("step").entered();
+        let modulated;
+        let cfg = if cfg.modulation.modulate.is_empty() {
+            cfg
+        } else {
+            modulated = cfg.modulated(self.time, self.steps);
+            &modulated
+        };
+        let const_force = Vector2::new(cfg.agent.force_x, cfg.agent.force_y);
 
-                let decayed = (1. - cfg.decay) * diffuse;
+        // Keep the channel count in sync with the config
+        let (width, height) = (self.front.medium().width(), self.front.medium().height());
+        let size = Vector2::new(width as f32, height as f32);
+        for data in [&mut self.front, &mut self.back] {
+            data.channels
+                .resize_with(cfg.medium.channels.max(1), || Array2D::new(width, height));
+        }
 
-                self.back.medium[pos] = decayed;
+        // Retag everyone when the number of groups changes
+        if cfg.population.groups.max(1) != self.factory.groups {
+            self.factory.groups = cfg.population.groups.max(1);
+            let factory = &self.factory;
+            for part in self.front.slime.iter_mut().chain(&mut self.back.slime) {
+                part.group = spawn_group(part.origin, factory.center, factory.groups);
             }
         }
 
-        // Some premature optimization
-        let left_sensor_rot = Rotation2::from_scaled_axis(Vector1::new(cfg.sensor_spread) * dt);
-        let right_sensor_rot = left_sensor_rot.inverse();
+        if let Some(every) = cfg.perturb.every {
+            if every > 0 && self.steps > 0 && self.steps % every == 0 {
+                let _span = info_span!("perturb").entered();
+                self.perturb(cfg, &mut rng);
+            }
+        }
 
-        let left_turn_rate = Rotation2::from_scaled_axis(Vector1::new(cfg.turn_speed) * dt);
-        let right_turn_rate = left_turn_rate.inverse();
+        let zones = ZoneConfigs::new(cfg);
+        info_span!("diffuse").in_scope(|| self.diffuse(cfg, &zones, const_force));
+        info_span!("regrow_food").in_scope(|| self.regrow_food(cfg, dt));
 
         let unit_rot = Rotation2::identity();
 
-        // Step particle motion
-        for (b, f) in self.back.slime.iter_mut().zip(&self.front.slime) {
+        // The directional trail decays into the buffer this step's deposits go to
+        if cfg.agent.heading_alignment.is_none() {
+            self.direction = None;
+        }
+        let mut next_direction = match &self.direction {
+            Some(direction) => {
+                let keep = (1. - cfg.medium.decay).max(0.);
+                let data = direction.data().iter().map(|d| d * keep).collect();
+                Some(Array2D::from_array(width, data))
+            }
+            None => cfg
+                .agent
+                .heading_alignment
+                .map(|_| Array2D::from_array(width, vec![Vector2::zeros(); width * height])),
+        };
+
+        // Number of particles in each cell, for the Jones motor stage
+        let mut occupancy = cfg.agent.jones_motor.then(|| {
+            let mut occ: Array2D<u32> = Array2D::new(width, height);
+            for part in &self.front.slime {
+                if let Some(pos) = sample_array_vect(&occ, part.position) {
+                    occ[pos] += 1;
+                }
+            }
+            occ
+        });
+
+        // Step particle motion: sensing, steering, moving and depositing
+        let particles_span = info_span!("particles").entered();
+        self.back.slime.clear();
+        let mut children = vec![];
+        for f in &self.front.slime {
+            let cfg = zones.at(f.position, self.zone_map.as_ref());
+            let left_sensor_rot =
+                Rotation2::from_scaled_axis(Vector1::new(cfg.agent.sensor_spread) * dt);
+            let right_sensor_rot = left_sensor_rot.inverse();
+
             // Sample the grid
             let [left, center, right] = [left_sensor_rot, unit_rot, right_sensor_rot]
-                .map(|r| f.position + r * f.heading * cfg.sample_dist)
-                .map(|p| sample_array_vect(&self.back.medium, p))
-                .map(|p| p.map(|p| self.front.medium[p]));
+                .map(|r| f.position + r * f.heading * cfg.agent.sensor_distance)
+                .map(|p| {
+                    if cfg.agent.warp_strength != 0. {
+                        warp_sensor(p, &cfg.agent, self.time)
+                    } else {
+                        p
+                    }
+                })
+                .map(|p| {
+                    let reading = sample_array_vect(self.front.medium(), p).map(|idx| {
+                        let channels = self.front.channels.iter().enumerate();
+                        let trail = channels
+                            .map(|(k, ch)| channel_weight(&cfg.medium.sense_weights, k) * ch[idx])
+                            .sum::<f32>();
+                        let food = self.food.as_ref().map_or(0., |food| food.amount[idx]);
+                        trail + cfg.food.attraction * food
+                    });
+                    // Sensors can't see through walls
+                    match &self.obstacles {
+                        Some(obs) if occluded(obs, f.position, p) => reading.map(|_| 0.),
+                        _ => reading,
+                    }
+                })
+                .map(|reading| {
+                    let (absolute, relative) =
+                        (cfg.agent.sensor_noise, cfg.agent.sensor_noise_relative);
+                    if absolute == 0. && relative == 0. {
+                        return reading;
+                    }
+                    reading.map(|v| {
+                        let scale = 1. + relative * rng.gen_range(-1.0..=1.0);
+                        v * scale + absolute * rng.gen_range(-1.0..=1.0)
+                    })
+                });
 
             // Decide which way to go
             let lc = left.partial_cmp(&center);
@@ -138,62 +1063,788 @@ impl SlimeSim {
 
             use std::cmp::Ordering as Odr;
 
-            let rotation = match (lc, cr) {
-                (Some(Odr::Greater), Some(Odr::Greater)) => left_turn_rate,
-                (Some(Odr::Less), Some(Odr::Less)) => right_turn_rate,
-                (Some(Odr::Less), Some(Odr::Greater)) => unit_rot,
-                /*(Odr::Greater, Odr::Less) =>
-                *[left_turn_rate, unit_rot, right_turn_rate]
-                .choose(&mut rng)
-                .unwrap(),*/
-                _ => unit_rot,
+            let chirality =
+                (cfg.agent.chirality + cfg.agent.chirality_spread * f.handedness).clamp(-1., 1.);
+
+            let turn = match (lc, cr) {
+                (Some(Odr::Greater), Some(Odr::Greater)) => 1.,
+                (Some(Odr::Less), Some(Odr::Less)) => -1.,
+                (Some(Odr::Less), Some(Odr::Greater)) => 0.,
+                // Ties are broken by the particle's handedness
+                (Some(Odr::Greater), Some(Odr::Less)) | (Some(Odr::Equal), Some(Odr::Equal))
+                    if chirality != 0. =>
+                {
+                    if rng.gen_bool(((1. + chirality) / 2.) as f64) {
+                        1.
+                    } else {
+                        -1.
+                    }
+                }
+                _ => 0.,
+            };
+
+            // Behavioral state transitions
+            // The grid is a torus, so home lies whichever way round is shortest
+            let to_origin = Vector2::new(
+                wrap_delta(f.origin.x - f.position.x, size.x),
+                wrap_delta(f.origin.y - f.position.y, size.y),
+            );
+            let away_too_long = cfg.agent.homing_after.map_or(false, |n| f.trip_age >= n);
+            let homesick = cfg.agent.homing_probability > 0.
+                && rng.gen_bool(cfg.agent.homing_probability.clamp(0., 1.) as f64);
+            let found_food = cfg.food.home_with_food
+                && self.food.as_ref().map_or(false, |food| {
+                    sample_array_vect(&food.amount, f.position)
+                        .map_or(false, |p| food.amount[p] > 0.)
+                });
+            let dense = cfg
+                .agent
+                .exploit_threshold
+                .zip(center)
+                .map_or(false, |(threshold, c)| c > threshold);
+            let state = match f.state {
+                AgentState::Homing if to_origin.norm() < cfg.agent.sensor_distance => {
+                    AgentState::Exploring
+                }
+                AgentState::Homing => AgentState::Homing,
+                _ if away_too_long || homesick || found_food => AgentState::Homing,
+                _ if dense => AgentState::Exploiting,
+                _ => AgentState::Exploring,
+            };
+            let arrived = f.state == AgentState::Homing && state != AgentState::Homing;
+            let trip_age = match state {
+                AgentState::Homing => f.trip_age,
+                _ if f.state == AgentState::Homing => 0,
+                _ => f.trip_age + 1,
+            };
+
+            // Turn which points the particle along `d`
+            let turn_towards = |d: Vector2<f32>| {
+                let cross = f.heading.x * d.y - f.heading.y * d.x;
+                (cross / d.norm()).clamp(-1., 1.)
+            };
+
+            // Homing particles ignore the medium and turn towards their origin
+            let turn = match state {
+                AgentState::Homing if to_origin.norm() > 0. => turn_towards(to_origin),
+                _ => turn,
+            };
+
+            // Past the edge of the home range the pull back grows until, at twice the range, it
+            // overrides the medium entirely
+            let home_offset = -to_origin;
+            let strayed = cfg
+                .agent
+                .home_range
+                .filter(|&range| range > 0. && home_offset.norm() > range);
+            let turn = match strayed {
+                Some(range) if !cfg.agent.home_range_respawn => {
+                    let pull = ((home_offset.norm() - range) / range).clamp(0., 1.);
+                    mix(turn, turn_towards(-home_offset), pull)
+                }
+                _ => turn,
             };
 
+            // Costly terrain is slow going, and particles wriggle out of it
+            let cost = self
+                .cost
+                .as_ref()
+                .and_then(|c| sample_array_vect(c, f.position).map(|p| c[p]))
+                .unwrap_or(0.);
+
+            let turn_speed = state_param(&cfg.agent.state_turn_speed, state, cfg.agent.turn_speed)
+                * (1. + cfg.agent.cost_turn * cost);
+            let move_speed = state_param(&cfg.agent.state_move_speed, state, cfg.agent.move_speed)
+                / (1. + cfg.agent.cost_speed * cost).max(1e-3);
+
             // Integrate rotation
-            let heading = rotation * f.heading;
+            let (heading, angular_velocity) = match cfg.agent.angular_damping {
+                // Steering accelerates the turn instead of setting it directly
+                Some(damping) => {
+                    let angular_velocity =
+                        (f.angular_velocity + turn * turn_speed * dt) * (1. - damping * dt).max(0.);
+                    let rotation = Rotation2::new(angular_velocity * dt);
+                    (rotation * f.heading, angular_velocity)
+                }
+                None => (Rotation2::new(turn * turn_speed * dt) * f.heading, 0.),
+            };
 
-            // Integrate position
-            let position = f.position + heading * cfg.move_speed * dt;
+            // Followers of the directional trail line up with the headings stored in it
+            let heading = match (&self.direction, cfg.agent.heading_alignment) {
+                (Some(direction), Some(rate)) if state != AgentState::Homing => {
+                    let stored = sample_array_vect(direction, f.position)
+                        .map_or(Vector2::zeros(), |p| direction[p]);
+                    let aligned = heading + stored * (rate * dt);
+                    if aligned.norm() > 0. {
+                        aligned.normalize()
+                    } else {
+                        heading
+                    }
+                }
+                _ => heading,
+            };
+
+            // Collisions scatter both particles
+            let collided = cfg.agent.collision_radius.map_or(false, |radius| {
+                self.particles_within(f.position, radius)
+                    .any(|other| other.id != f.id)
+            });
+            let heading = if collided {
+                unit_circ(rng.gen_range(0.0..TAU))
+            } else {
+                heading
+            };
+
+            // Near walls, steer along them rather than into them
+            let heading = match (&self.obstacles, cfg.agent.wall_following) {
+                (Some(obstacles), Some(rate)) => {
+                    match wall_tangent(obstacles, f.position, cfg.agent.wall_range, heading) {
+                        Some(tangent) => (heading + tangent * (rate * dt)).normalize(),
+                        None => heading,
+                    }
+                }
+                _ => heading,
+            };
+
+            // External forces
+            let mut force = const_force + cfg.forcing.force(f.position, size, self.time);
+            if let Some(field) = &self.force_field {
+                if let Some(pos) = sample_array_vect(field, f.position) {
+                    force += field[pos];
+                }
+            }
+
+            // Hot regions are frantic, cold regions sluggish
+            let temperature = self
+                .temperature
+                .as_ref()
+                .and_then(|t| sample_array_vect(t, f.position).map(|p| t[p]))
+                .unwrap_or(1.);
+
+            // Integrate position, wrapping around the edges
+            let position = f.position + (heading * move_speed * temperature + force) * dt;
+            let position = wrap_position(position, width, height);
+
+            // Walls (and occupied cells with the Jones motor stage) turn particles around
+            // instead of letting them through
+            let stuck = blocked(&self.obstacles, position)
+                || occupancy
+                    .as_mut()
+                    .map_or(false, |occ| !try_occupy(occ, f.position, position));
+
+            let (position, heading) = if stuck {
+                (f.position, unit_circ(rng.gen_range(0.0..TAU)))
+            } else {
+                (position, heading)
+            };
 
             // Happy birthday!
             let age = f.age + 1;
 
-            // Drop some slime (or create a new particle if out of bounds)
-            if let Some(pos) = sample_array_vect(&self.back.medium, position) {
-                self.back.medium[pos] += cfg.deposit_rate * dt;
-                *b = SlimeParticle {
+            // Drop some slime (or create a new particle if lost to NaN or stuck in a wall)
+            if let Some(pos) = sample_array_vect(&self.back.channels[0], position)
+                .filter(|_| !blocked(&self.obstacles, f.position))
+            {
+                let deposit = if stuck && cfg.agent.jones_motor {
+                    0.
+                } else if collided {
+                    cfg.medium.deposit_rate * temperature * dt * cfg.agent.collision_burst
+                } else {
+                    cfg.medium.deposit_rate * temperature * dt
+                };
+                if let Some(food) = &mut self.food {
+                    let cell = &mut food.amount[pos];
+                    *cell -= cell.min(cfg.food.consumption * dt);
+                }
+
+                if deposit > 0. {
+                    self.last_deposit[pos] = self.time + dt;
+                }
+                if let Some(direction) = &mut next_direction {
+                    direction[pos] += heading * deposit;
+                }
+                for (k, channel) in self.back.channels.iter_mut().enumerate() {
+                    let deposit = deposit * channel_weight(&cfg.medium.deposit_weights, k);
+                    let cell = &mut channel[pos];
+                    *cell = match cfg.medium.max_density {
+                        Some(max) if cfg.medium.soft_cap => {
+                            *cell + deposit * (1. - *cell / max).max(0.)
+                        }
+                        Some(max) => (*cell + deposit).min(max),
+                        None => *cell + deposit,
+                    };
+                }
+                let particle = SlimeParticle {
                     origin: f.origin,
                     position,
                     heading,
+                    angular_velocity,
+                    handedness: f.handedness,
+                    state,
+                    trip_age,
                     age,
+                    id: f.id,
+                    group: f.group,
+                };
+                let respawn = (arrived && cfg.agent.respawn_at_origin)
+                    || (strayed.is_some() && cfg.agent.home_range_respawn);
+                let particle = if respawn {
+                    SlimeParticle {
+                        id: self.factory.next_id(),
+                        position: f.origin,
+                        heading: unit_circ(rng.gen_range(0.0..TAU)),
+                        angular_velocity: 0.,
+                        state: AgentState::Exploring,
+                        trip_age: 0,
+                        age: 0,
+                        ..particle
+                    }
+                } else {
+                    particle
                 };
+                self.back.slime.push(particle);
+
+                // Successful trails recruit more agents
+                let density = self.front.medium()[pos];
+                if cfg
+                    .population
+                    .split_threshold
+                    .map_or(false, |t| density > t)
+                    && rng.gen_bool(cfg.population.split_probability.clamp(0., 1.) as f64)
+                {
+                    let spread = rng.gen_range(-1.0..=1.0) * cfg.population.split_spread;
+                    children.push(SlimeParticle {
+                        id: self.factory.next_id(),
+                        heading: Rotation2::new(spread) * heading,
+                        angular_velocity: 0.,
+                        state: AgentState::Exploring,
+                        trip_age: 0,
+                        age: 0,
+                        ..particle
+                    });
+                }
             } else {
-                *b = self.factory.slime(&mut rng);
+                self.back.slime.push(self.factory.slime(&mut rng));
             }
         }
 
-        std::mem::swap(&mut self.front, &mut self.back);
-    }
+        self.direction = next_direction;
+        drop(particles_span);
+        let population_span = info_span!("population").entered();
+
+        // Children only join while there's room
+        let room = cfg.population.max_particles.map_or(children.len(), |max| {
+            max.saturating_sub(self.back.slime.len())
+        });
+        children.truncate(room);
+        self.back.slime.extend(children);
+
+        // Population homeostasis; birth and death rates follow the distance from the target
+        if let Some(target) = cfg.population.target_population {
+            let n = self.back.slime.len();
+            let error = (target as f32 - n as f32) / target.max(1) as f32;
+
+            let death = (-error * cfg.population.gain).clamp(0., 1.);
+            if death > 0. {
+                self.back.slime.retain(|_| !rng.gen_bool(death as f64));
+            }
+
+            let birth = (error * cfg.population.gain).clamp(0., 1.);
+            let n_births = (n.max(1) as f32 * birth).ceil() as usize;
+            for _ in 0..n_births {
+                self.back.slime.push(self.factory.slime(&mut rng));
+            }
+        }
+
+        // Scheduled growth, in batches which keep the population on a straight line to the goal
+        if let Some(goal) = cfg.population.grow_to {
+            let every = cfg.population.grow_every.max(1);
+            let n = self.back.slime.len();
+            if self.steps % every == 0 && n < goal {
+                let remaining = cfg.population.grow_over.saturating_sub(self.steps);
+                let batch = match remaining {
+                    r if r <= every => goal - n,
+                    r => ((goal - n) * every + r - 1) / r,
+                };
+                for _ in 0..batch {
+                    let bud = match self.back.slime.len() {
+                        0 => self.factory.slime(&mut rng),
+                        len => SlimeParticle {
+                            id: self.factory.next_id(),
+                            heading: unit_circ(rng.gen_range(0.0..TAU)),
+                            angular_velocity: 0.,
+                            state: AgentState::Exploring,
+                            trip_age: 0,
+                            age: 0,
+                            ..self.back.slime[rng.gen_range(0..len)]
+                        },
+                    };
+                    self.back.slime.push(bud);
+                }
+            }
+        }
+
+        drop(population_span);
+
+        // Mandala mode
+        if let Some(folds) = cfg.medium.symmetry.filter(|&n| n > 1) {
+            let _span = info_span!("symmetrize").entered();
+            for channel in &mut self.back.channels {
+                symmetrize(channel, folds, cfg.medium.mirror);
+            }
+        }
+
+        if let Some(bits) = cfg.agent.fixed_point {
+            let (width, height) = (self.back.medium().width(), self.back.medium().height());
+            for part in &mut self.back.slime {
+                part.position =
+                    wrap_position(part.position.map(|v| quantize(v, bits)), width, height);
+                part.heading = part.heading.map(|v| quantize(v, bits));
+                part.angular_velocity = quantize(part.angular_velocity, bits);
+            }
+        }
+
+        std::mem::swap(&mut self.front, &mut self.back);
+        info_span!("spatial").in_scope(|| {
+            self.spatial
+                .rebuild(self.front.slime.iter().map(|p| p.position))
+        });
+        self.steps += 1;
+        self.time += dt;
+
+        if self.steps % HEALTH_CHECK_INTERVAL == 0 {
+            self.check_health();
+        }
+    }
+
+    /// Warn about non-finite densities and the population dying off, once each
+    fn check_health(&mut self) {
+        let n = self.front.slime.len();
+        self.peak_population = self.peak_population.max(n);
+        if !self.warned_collapse && n * 10 < self.peak_population {
+            warn!(
+                "Population collapsed to {} particles at step {}, from a peak of {}",
+                n, self.steps, self.peak_population
+            );
+            self.warned_collapse = true;
+        }
+
+        let non_finite = self
+            .front
+            .channels
+            .iter()
+            .any(|ch| ch.data().iter().any(|v| !v.is_finite()));
+        if !self.warned_nan && non_finite {
+            warn!("Non-finite densities in the medium at step {}", self.steps);
+            self.warned_nan = true;
+        }
+    }
+
+    /// Scheduled disturbances, applied to the front buffer
+    fn perturb(&mut self, cfg: &SlimeConfig, mut rng: impl Rng) {
+        let (width, height) = (self.front.medium().width(), self.front.medium().height());
+        let mut random_point = || {
+            Vector2::new(
+                rng.gen_range(0.0..width as f32),
+                rng.gen_range(0.0..height as f32),
+            )
+        };
+
+        // Wipe a region of the medium
+        if cfg.perturb.wipe_radius > 0. {
+            let center = random_point();
+            for (pos, _) in disc(width, height, center, cfg.perturb.wipe_radius) {
+                self.front.channels.iter_mut().for_each(|ch| ch[pos] = 0.);
+            }
+        }
+
+        // Inject a blob of slime, densest at the center
+        if cfg.perturb.blob > 0. {
+            let center = random_point();
+            let radius = cfg.perturb.blob_radius;
+            for (pos, dist) in disc(width, height, center, radius) {
+                self.front.channels[0][pos] += cfg.perturb.blob * (1. - dist / radius);
+            }
+        }
+
+        // Scramble some headings
+        if cfg.perturb.scramble > 0. {
+            let p = cfg.perturb.scramble.clamp(0., 1.) as f64;
+            for part in &mut self.front.slime {
+                if rng.gen_bool(p) {
+                    part.heading = unit_circ(rng.gen_range(0.0..TAU));
+                }
+            }
+        }
+    }
+
+    /// Logistic regrowth of the food towards its capacity
+    fn regrow_food(&mut self, cfg: &SlimeConfig, dt: f32) {
+        if let Some(food) = &mut self.food {
+            let cells = food.amount.data_mut().iter_mut().zip(food.capacity.data());
+            for (amount, &capacity) in cells {
+                if capacity > 0. {
+                    // Leave a little seed so that fully eaten cells can grow back
+                    let seed = amount.max(capacity * 0.01);
+                    *amount += cfg.food.regrowth * seed * (1. - *amount / capacity) * dt;
+                }
+            }
+        }
+    }
+
+    /// Diffusion and decay of each channel, from front to back
+    fn diffuse(&mut self, cfg: &SlimeConfig, zones: &ZoneConfigs, const_force: Vector2<f32>) {
+        // The compute pass only takes rates which are the same all over the grid
+        if let Some(gpu) = &mut self.gpu {
+            if cfg.zones.is_empty() && cfg.medium.flow_decay.is_none() {
+                let rates: Vec<ChannelRates> = (0..self.front.channels.len())
+                    .map(|k| channel_rates(&cfg.medium, k))
+                    .collect();
+                let anisotropic =
+                    cfg.medium.diffusion_x.is_some() || cfg.medium.diffusion_y.is_some();
+                let result = gpu.diffuse(
+                    &self.front.channels,
+                    &mut self.back.channels,
+                    &rates,
+                    anisotropic,
+                    self.obstacles.as_ref(),
+                );
+                match result {
+                    Ok(()) => return,
+                    Err(e) => {
+                        warn!("GPU diffusion failed, using the CPU from now on: {:#}", e);
+                        self.gpu = None;
+                    }
+                }
+            } else if !self.warned_gpu {
+                warn!("Zones and flow decay aren't supported on the GPU; diffusing on the CPU");
+                self.warned_gpu = true;
+            }
+        }
+
+        let medium = self.front.medium();
+        let size = Vector2::new(medium.width() as f32, medium.height() as f32);
+
+        let channels = self.front.channels.iter().zip(&mut self.back.channels);
+        for (k, (front, back)) in channels.enumerate() {
+            for y in 0..front.height() {
+                for x in 0..front.width() {
+                    let pos = (x, y);
+                    let center = front[pos];
+                    let cell = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+
+                    let here = zones.at(cell, self.zone_map.as_ref());
+                    let anisotropic =
+                        here.medium.diffusion_x.is_some() || here.medium.diffusion_y.is_some();
+                    let rates = channel_rates(&here.medium, k);
+
+                    let diffuse = if anisotropic {
+                        let horiz = neighborhood_avg(front, x, y, -1..=1, 0..=0);
+                        let vert = neighborhood_avg(front, x, y, 0..=0, -1..=1);
+                        center
+                            + rates.diffusion_x * (horiz - center)
+                            + rates.diffusion_y * (vert - center)
+                    } else {
+                        let avg = neighborhood_avg(front, x, y, -1..=1, -1..=1);
+                        mix(center, avg, rates.diffusion)
+                    };
+
+                    let decay = match here.medium.flow_decay {
+                        Some(strength) => {
+                            let mut flow = const_force + cfg.forcing.force(cell, size, self.time);
+                            if let Some(field) = &self.force_field {
+                                flow += field[pos];
+                            }
+                            rates.decay * (1. + strength * flow.norm())
+                        }
+                        None => rates.decay,
+                    };
+
+                    let decayed = (1. - decay).max(0.) * diffuse;
+
+                    back[pos] = match &self.obstacles {
+                        Some(obs) if obs[pos] => 0.,
+                        _ => decayed,
+                    };
+                }
+            }
+        }
+    }
 }
 
-fn sample_array_isize<T: Copy>(arr: &Array2D<T>, x: isize, y: isize) -> Option<T> {
-    let bounds = |x: isize, w: usize| {
-        (x >= 0 && x < w as isize) //
-            .then(|| x as usize)
-    };
+/// The config in effect at each position: that of the first zone covering it, or else the base
+/// config. Zone configs are the base config with the zone's parameters set
+struct ZoneConfigs<'a> {
+    base: &'a SlimeConfig,
+    zones: Vec<(&'a Zone, SlimeConfig)>,
+}
 
-    let pos = (bounds(x, arr.width())?, bounds(y, arr.height())?);
+impl<'a> ZoneConfigs<'a> {
+    fn new(base: &'a SlimeConfig) -> Self {
+        let zones = base
+            .zones
+            .iter()
+            .map(|zone| {
+                let mut cfg = SlimeConfig {
+                    zones: vec![],
+                    ..base.clone()
+                };
+                for (name, &value) in &zone.params {
+                    // Names were checked when the config was loaded
+                    let _ = cfg.set_param(name, value);
+                }
+                (zone, cfg)
+            })
+            .collect();
+        Self { base, zones }
+    }
 
-    Some(arr[pos])
+    fn at(&self, pos: Vector2<f32>, zone_map: Option<&Array2D<u8>>) -> &SlimeConfig {
+        if self.zones.is_empty() {
+            return self.base;
+        }
+        let level = zone_map.and_then(|map| sample_array_vect(map, pos).map(|idx| map[idx]));
+        self.zones
+            .iter()
+            .find(|(zone, _)| zone.contains(pos, level))
+            .map_or(self.base, |(_, cfg)| cfg)
+    }
 }
 
+/// Cells of a `width` by `height` grid within `radius` of `center`, along with their distance
+/// from it
+fn disc(
+    width: usize,
+    height: usize,
+    center: Vector2<f32>,
+    radius: f32,
+) -> impl Iterator<Item = ((usize, usize), f32)> {
+    let x0 = (center.x - radius).floor().max(0.) as usize;
+    let y0 = (center.y - radius).floor().max(0.) as usize;
+    let x1 = ((center.x + radius).ceil().max(0.) as usize).min(width);
+    let y1 = ((center.y + radius).ceil().max(0.) as usize).min(height);
+
+    (y0..y1)
+        .flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+        .map(move |(x, y)| {
+            let cell = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+            ((x, y), (cell - center).norm())
+        })
+        .filter(move |&(_, dist)| dist <= radius)
+}
+
+/// Average each cell with its images under `folds`-fold rotation (and reflection, if `mirror` is
+/// set) about the center of the grid
+fn symmetrize(arr: &mut Array2D<f32>, folds: usize, mirror: bool) {
+    let src = arr.clone();
+    let center = Vector2::new(arr.width() as f32, arr.height() as f32) / 2.;
+    let rotations: Vec<Rotation2<f32>> = (0..folds)
+        .map(|k| Rotation2::new(TAU * k as f32 / folds as f32))
+        .collect();
+
+    for y in 0..arr.height() {
+        for x in 0..arr.width() {
+            let rel = Vector2::new(x as f32 + 0.5, y as f32 + 0.5) - center;
+            let flipped = Vector2::new(rel.x, -rel.y);
+            let images = if mirror {
+                &[rel, flipped][..]
+            } else {
+                &[rel][..]
+            };
+
+            let mut sum = 0.;
+            let mut n_parts = 0;
+            for rot in &rotations {
+                for &image in images {
+                    // Images which leave the grid are skipped rather than wrapped, which would
+                    // break the symmetry
+                    let p = center + rot * image;
+                    let inside = (0. ..arr.width() as f32).contains(&p.x)
+                        && (0. ..arr.height() as f32).contains(&p.y);
+                    if let Some(pos) = sample_array_vect(&src, p).filter(|_| inside) {
+                        sum += src[pos];
+                        n_parts += 1;
+                    }
+                }
+            }
+
+            arr[(x, y)] = sum / n_parts as f32;
+        }
+    }
+}
+
+/// `p` pushed around by the time-evolving noise field which warps the sensors
+fn warp_sensor(p: Vector2<f32>, agent: &AgentConfig, time: f32) -> Vector2<f32> {
+    let (x, y) = (p.x * agent.warp_scale, p.y * agent.warp_scale);
+    let t = time * agent.warp_speed;
+    p + Vector2::new(perlin3(x, y, t, 0), perlin3(x, y, t, 1)) * agent.warp_strength
+}
+
+/// Per-state override of a steering parameter
+fn state_param(overrides: &[f32], state: AgentState, default: f32) -> f32 {
+    overrides.get(state as usize).copied().unwrap_or(default)
+}
+
+/// Decay and diffusion rates of channel `k`. Channels missing from the per-channel lists use
+/// the shared rates, and missing directional rates the channel's own
+fn channel_rates(medium: &MediumConfig, k: usize) -> ChannelRates {
+    let diffusion = medium
+        .channel_diffusion
+        .get(k)
+        .copied()
+        .unwrap_or(medium.diffusion);
+    ChannelRates {
+        diffusion,
+        diffusion_x: medium.diffusion_x.unwrap_or(diffusion),
+        diffusion_y: medium.diffusion_y.unwrap_or(diffusion),
+        decay: medium.channel_decay.get(k).copied().unwrap_or(medium.decay),
+    }
+}
+
+/// Weight of channel `k`, where channels missing from `weights` default to only using channel 0
+fn channel_weight(weights: &[f32], k: usize) -> f32 {
+    weights
+        .get(k)
+        .copied()
+        .unwrap_or(if k == 0 { 1. } else { 0. })
+}
+
+/// Whether `v` lies in an obstacle cell
+fn blocked(obstacles: &Option<Array2D<bool>>, v: Vector2<f32>) -> bool {
+    obstacles.as_ref().map_or(false, |obs| {
+        sample_array_vect(obs, v).map_or(false, |pos| obs[pos])
+    })
+}
+
+/// Unit direction along the obstacle cells within `range` of `pos`, on the side `heading` points
+/// to. Nearer cells count more. `None` away from walls
+fn wall_tangent(
+    obstacles: &Array2D<bool>,
+    pos: Vector2<f32>,
+    range: f32,
+    heading: Vector2<f32>,
+) -> Option<Vector2<f32>> {
+    let r = range.ceil() as isize;
+    let mut toward = Vector2::zeros();
+    for dy in -r..=r {
+        for dx in -r..=r {
+            let offset = Vector2::new(dx as f32, dy as f32);
+            let dist = offset.norm();
+            if dist == 0. || dist > range {
+                continue;
+            }
+            if sample_array_vect(obstacles, pos + offset).map_or(false, |p| obstacles[p]) {
+                toward += offset / (dist * dist);
+            }
+        }
+    }
+
+    if toward.norm_squared() == 0. {
+        return None;
+    }
+    let tangent = Vector2::new(-toward.y, toward.x).normalize();
+    Some(if tangent.dot(&heading) < 0. {
+        -tangent
+    } else {
+        tangent
+    })
+}
+
+/// Move a particle from `from` to `to` in the occupancy grid, unless `to` is already taken by
+/// another particle. Returns whether the move is allowed.
+fn try_occupy(occ: &mut Array2D<u32>, from: Vector2<f32>, to: Vector2<f32>) -> bool {
+    match (sample_array_vect(occ, from), sample_array_vect(occ, to)) {
+        (Some(a), Some(b)) if a == b => true,
+        (_, Some(b)) if occ[b] > 0 => false,
+        (a, b) => {
+            if let Some(a) = a {
+                occ[a] = occ[a].saturating_sub(1);
+            }
+            if let Some(b) = b {
+                occ[b] += 1;
+            }
+            true
+        }
+    }
+}
+
+/// Whether the straight line from `a` to `b` crosses an obstacle cell
+fn occluded(obstacles: &Array2D<bool>, a: Vector2<f32>, b: Vector2<f32>) -> bool {
+    let n_steps = (b - a).norm().ceil().max(1.) as usize;
+    (1..=n_steps).any(|i| {
+        let p = a + (b - a) * (i as f32 / n_steps as f32);
+        sample_array_vect(obstacles, p).map_or(false, |pos| obstacles[pos])
+    })
+}
+
+/// Average of the cells within the given offsets of (x, y), skipping those out of bounds
+fn neighborhood_avg(
+    arr: &Array2D<f32>,
+    x: usize,
+    y: usize,
+    dx: RangeInclusive<isize>,
+    dy: RangeInclusive<isize>,
+) -> f32 {
+    let mut sum = 0.;
+    let mut n_parts = 0;
+    for i in dy {
+        for j in dx.clone() {
+            sum += sample_array_isize(arr, j + x as isize, i + y as isize);
+            n_parts += 1;
+        }
+    }
+
+    sum / n_parts as f32
+}
+
+/// The grid is a torus, so coordinates past one edge wrap around to the opposite one
+fn sample_array_isize<T: Copy>(arr: &Array2D<T>, x: isize, y: isize) -> T {
+    let wrap = |x: isize, w: usize| x.rem_euclid(w as isize) as usize;
+    arr[(wrap(x, arr.width()), wrap(y, arr.height()))]
+}
+
+/// Cell containing `v`, or None if it isn't finite
 fn sample_array_vect<T>(arr: &Array2D<T>, v: Vector2<f32>) -> Option<(usize, usize)> {
-    let bounds = |x: f32, w: usize| {
-        (x.is_finite() && x >= 0. && x < w as f32) //
-            .then(|| x as usize)
+    let wrap = |x: f32, w: usize| {
+        x.is_finite()
+            .then(|| (x.floor() as isize).rem_euclid(w as isize) as usize)
+    };
+
+    Some((wrap(v.x, arr.width())?, wrap(v.y, arr.height())?))
+}
+
+/// `v` rounded to the nearest multiple of 2^-bits
+fn quantize(v: f32, bits: u32) -> f32 {
+    let scale = (1u32 << bits.min(24)) as f32;
+    (v * scale).round() / scale
+}
+
+/// The equivalent of `v` within 0..width and 0..height. Non-finite positions stay non-finite
+fn wrap_position(v: Vector2<f32>, width: usize, height: usize) -> Vector2<f32> {
+    let wrap = |x: f32, len: usize| {
+        let x = x.rem_euclid(len as f32);
+        // Tiny negative values round up to a whole length
+        if x >= len as f32 {
+            0.
+        } else {
+            x
+        }
     };
+    Vector2::new(wrap(v.x, width), wrap(v.y, height))
+}
 
-    Some((bounds(v.x, arr.width())?, bounds(v.y, arr.height())?))
+/// Shortest equivalent of the offset `d` on a loop of length `len`, such as either axis of the
+/// grid
+pub fn wrap_delta(d: f32, len: f32) -> f32 {
+    d - (d / len).round() * len
+}
+
+/// Group of a particle spawned at `origin`: the index of its sector out of `groups` around
+/// `center`, counting anticlockwise from the -x axis
+pub fn spawn_group(origin: Vector2<f32>, center: Vector2<f32>, groups: u32) -> u32 {
+    let d = origin - center;
+    let angle = d.y.atan2(d.x) + PI;
+    ((angle / TAU * groups as f32) as u32).min(groups.max(1) - 1)
 }
 
 // Overengineered bullshit
@@ -201,6 +1852,10 @@ struct SlimeFactory {
     x: Uniform<f32>,
     y: Uniform<f32>,
     angle: Uniform<f32>,
+    handedness: Uniform<f32>,
+    center: Vector2<f32>,
+    groups: u32,
+    next_id: u32,
 }
 
 impl SlimeFactory {
@@ -208,17 +1863,37 @@ impl SlimeFactory {
         let x = Uniform::new(0.0, width as f32);
         let y = Uniform::new(0.0, height as f32);
         let angle = Uniform::new(0., TAU);
-        Self { x, y, angle }
+        let handedness = Uniform::new_inclusive(-1., 1.);
+        Self {
+            x,
+            y,
+            angle,
+            handedness,
+            center: Vector2::new(width as f32, height as f32) / 2.,
+            groups: 3,
+            next_id: 0,
+        }
+    }
+
+    pub fn next_id(&mut self) -> u32 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
     }
 
-    pub fn slime(&self, mut rng: impl Rng) -> SlimeParticle {
+    pub fn slime(&mut self, mut rng: impl Rng) -> SlimeParticle {
         let origin = Vector2::new(self.x.sample(&mut rng), self.y.sample(&mut rng));
         SlimeParticle {
+            id: self.next_id(),
             position: origin,
             origin,
             //position: Vector2::new(200., 200.), //Vector2::new(self.x.sample(&mut rng), self.y.sample(&mut rng)),
             heading: unit_circ(self.angle.sample(&mut rng)),
+            angular_velocity: 0.,
+            handedness: self.handedness.sample(&mut rng),
+            state: AgentState::Exploring,
+            trip_age: 0,
             age: 0,
+            group: spawn_group(origin, self.center, self.groups),
         }
     }
 }
@@ -227,3 +1902,1009 @@ fn mix(a: f32, b: f32, t: f32) -> f32 {
     (1. - t) * a + t * b
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let file = "[agent]\nturn_speed = 1.0\nmove_speed = 3.0\n";
+        let file = SlimeConfig::from_toml(file).unwrap();
+        let matches = SlimeConfig::clap().get_matches_from(["slime", "--turn-speed", "2.5"]);
+        let cli = SlimeConfig::from_clap(&matches);
+        let merged = file.with_overrides(&cli, &matches).unwrap();
+        assert_eq!(merged.agent.turn_speed, 2.5);
+        assert_eq!(merged.agent.move_speed, 3.0);
+    }
+
+    /// 4x3 grid holding the index of each cell
+    fn indexed() -> Array2D<usize> {
+        Array2D::from_array(4, (0..12).collect())
+    }
+
+    #[test]
+    fn integer_samples_wrap() {
+        let arr = indexed();
+        for (x, wrapped) in [
+            (-1, 3),
+            (-4, 0),
+            (-5, 3),
+            (4, 0),
+            (5, 1),
+            (4002, 2),
+            (-4002, 2),
+        ] {
+            assert_eq!(sample_array_isize(&arr, x, 0), wrapped, "x = {}", x);
+        }
+        for (y, wrapped) in [(-1, 2), (3, 0), (3001, 1), (-3001, 2)] {
+            assert_eq!(sample_array_isize(&arr, 0, y), wrapped * 4, "y = {}", y);
+        }
+        for x in -50..50 {
+            for y in -50..50 {
+                let cell = x.rem_euclid(4) + 4 * y.rem_euclid(3);
+                assert_eq!(sample_array_isize(&arr, x, y), cell as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn float_samples_wrap() {
+        let arr = indexed();
+        let cell = |x: f32, y: f32| sample_array_vect(&arr, Vector2::new(x, y));
+        let cases = [
+            (-0.5, 3),
+            (-1e-8, 3),
+            (-4., 0),
+            (-4.5, 3),
+            (3.999, 3),
+            (4., 0),
+            (5.5, 1),
+            (4003.5, 3),
+            (-4003.5, 0),
+        ];
+        for (x, wrapped) in cases {
+            assert_eq!(cell(x, 0.), Some((wrapped, 0)), "x = {}", x);
+        }
+        assert_eq!(cell(0., -0.5), Some((0, 2)));
+        assert_eq!(cell(0., 3.), Some((0, 0)));
+
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(cell(bad, 0.), None);
+            assert_eq!(cell(0., bad), None);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let (x, y) = (rng.gen_range(-1e4..1e4), rng.gen_range(-1e4..1e4));
+            let (cx, cy) = cell(x, y).unwrap();
+            assert_eq!(cx as isize, (x.floor() as isize).rem_euclid(4));
+            assert_eq!(cy as isize, (y.floor() as isize).rem_euclid(3));
+        }
+    }
+
+    #[test]
+    fn positions_wrap() {
+        let wrap = |x: f32| wrap_position(Vector2::new(x, 0.), 4, 3).x;
+        let cases = [
+            (-0.5, 3.5),
+            (-4., 0.),
+            (0., 0.),
+            (4., 0.),
+            (5.5, 1.5),
+            (4003.5, 3.5),
+            (-4003.5, 0.5),
+        ];
+        for (x, wrapped) in cases {
+            assert_eq!(wrap(x), wrapped, "x = {}", x);
+        }
+        assert_eq!(wrap_position(Vector2::new(0., -0.5), 4, 3).y, 2.5);
+        assert_eq!(wrap_position(Vector2::new(0., 3.), 4, 3).y, 0.);
+
+        // Small enough to round to a whole length
+        let tiny = wrap(-1e-8);
+        assert!((0. ..4.).contains(&tiny), "{}", tiny);
+
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert!(!wrap(bad).is_finite());
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let x: f32 = rng.gen_range(-1e4..1e4);
+            let wrapped = wrap(x);
+            assert!((0. ..4.).contains(&wrapped), "{} wrapped to {}", x, wrapped);
+            let turns = (x - wrapped) / 4.;
+            assert!(
+                (turns - turns.round()).abs() < 1e-3,
+                "{} wrapped to {}",
+                x,
+                wrapped
+            );
+        }
+    }
+
+    /// 16x16 sim without particles, with the medium (every channel) at `density` everywhere
+    fn empty_sim(density: f32) -> SlimeSim {
+        let mut sim = SlimeSim::new(16, 16, 0, StdRng::seed_from_u64(0));
+        for data in [&mut sim.front, &mut sim.back] {
+            for ch in &mut data.channels {
+                ch.data_mut().iter_mut().for_each(|v| *v = density);
+            }
+        }
+        sim
+    }
+
+    fn config(flags: &[&str]) -> SlimeConfig {
+        SlimeConfig::from_iter(std::iter::once("slime").chain(flags.iter().copied()))
+    }
+
+    #[test]
+    fn flow_decay_is_faster_under_stronger_forcing() {
+        let mut sim = empty_sim(1.);
+        // Force of 2 on the left half, none on the right
+        let field = (0..16 * 16)
+            .map(|i| Vector2::new(if i % 16 < 8 { 2. } else { 0. }, 0.))
+            .collect();
+        sim.set_force_field(Some(Array2D::from_array(16, field)));
+
+        let cfg = config(&["--decay", "0.1", "--diffusion", "0", "--flow-decay", "1"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let medium = sim.frame().medium();
+        assert!((medium[(12, 4)] - 0.9).abs() < 1e-6, "{}", medium[(12, 4)]);
+        assert!((medium[(3, 4)] - 0.7).abs() < 1e-6, "{}", medium[(3, 4)]);
+
+        // Without flow decay, the force field makes no difference
+        let mut plain = empty_sim(1.);
+        plain.set_force_field(sim.force_field.clone());
+        let cfg = config(&["--decay", "0.1", "--diffusion", "0"]);
+        plain.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let medium = plain.frame().medium();
+        assert_eq!(medium[(3, 4)], medium[(12, 4)]);
+    }
+
+    #[test]
+    fn expressions_round_trip_through_config_files() {
+        let text = "[forcing]\nexpr_x = \"sin(t) * x / width\"\n\n\
+                    [modulation]\nmodulate = [\"decay = 0.05 + 0.01 * t\"]\n";
+        let cfg = SlimeConfig::from_toml(text).unwrap();
+        let modulated = cfg.modulated(2., 4);
+        assert!((modulated.medium.decay - 0.07).abs() < 1e-6);
+
+        let saved = toml::to_string_pretty(&cfg).unwrap();
+        let loaded = SlimeConfig::from_toml(&saved).unwrap();
+        assert_eq!(
+            loaded.forcing.expr_x.unwrap().to_string(),
+            "sin(t) * x / width"
+        );
+        assert_eq!(
+            loaded.modulation.modulate[0].to_string(),
+            "decay=0.05 + 0.01 * t"
+        );
+
+        let typo = "[modulation]\nmodulate = [\"decy = 0.1\"]\n";
+        assert!(SlimeConfig::from_toml(typo).is_err());
+    }
+
+    #[test]
+    fn step_applies_modulations_and_force_expressions() {
+        let mut sim = empty_sim(1.);
+        let cfg = config(&["--diffusion", "0", "--modulate", "decay=0.1*(step+1)"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        assert!((sim.frame().medium()[(3, 3)] - 0.9).abs() < 1e-6);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        assert!((sim.frame().medium()[(3, 3)] - 0.72).abs() < 1e-6);
+
+        let mut sim = SlimeSim::new(16, 16, 1, StdRng::seed_from_u64(0));
+        let start = sim.frame().slime[0].position;
+        let cfg = config(&[
+            "--move-speed",
+            "0",
+            "--force-expr-x",
+            "2",
+            "--force-expr-y",
+            "t-1",
+        ]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let moved = sim.frame().slime[0].position;
+        assert!((wrap_delta(moved.x - start.x, 16.) - 2.).abs() < 1e-4);
+        assert!((wrap_delta(moved.y - start.y, 16.) + 1.).abs() < 1e-4);
+    }
+
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn gpu_diffusion_matches_the_cpu() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sims = [empty_sim(0.), empty_sim(0.)];
+        if let Err(e) = sims[1].use_gpu() {
+            eprintln!("Skipping, as there's no GPU: {:#}", e);
+            return;
+        }
+        let medium: Vec<f32> = (0..16 * 16).map(|_| rng.gen_range(0.0..1.0)).collect();
+        let walls: Vec<bool> = (0..16 * 16).map(|i| i % 37 == 0).collect();
+        for sim in &mut sims {
+            for ch in &mut sim.front.channels {
+                ch.data_mut().copy_from_slice(&medium);
+            }
+            sim.set_obstacles(Some(Array2D::from_array(16, walls.clone())));
+        }
+
+        let flags = [
+            &["--diffusion", "0.3", "--decay", "0.1"][..],
+            &[
+                "--diffusion-x",
+                "0.5",
+                "--channels",
+                "2",
+                "--channel-decay",
+                "0.1,0.4",
+            ][..],
+        ];
+        for flags in flags {
+            let cfg = config(flags);
+            for sim in &mut sims {
+                sim.step(&cfg, 0.5, StdRng::seed_from_u64(0));
+            }
+            let [cpu, gpu] = &sims;
+            assert!(gpu.gpu.is_some(), "GPU diffusion fell back to the CPU");
+            for (a, b) in cpu.frame().channels.iter().zip(&gpu.frame().channels) {
+                for (a, b) in a.data().iter().zip(b.data()) {
+                    assert!((a - b).abs() < 1e-5, "{} on the CPU, {} on the GPU", a, b);
+                }
+            }
+        }
+    }
+
+    /// Replace the particles of `sim` with ones at the given positions, heading the given ways
+    fn place(sim: &mut SlimeSim, particles: &[([f32; 2], [f32; 2])]) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let slime: Vec<SlimeParticle> = particles
+            .iter()
+            .map(|&([x, y], [hx, hy])| {
+                sim.new_particle(Vector2::new(x, y), Vector2::new(hx, hy), &mut rng)
+            })
+            .collect();
+        sim.set_particles(slime);
+    }
+
+    #[test]
+    fn forces_push_particles() {
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        let field = vec![Vector2::new(0., 1.); 16 * 16];
+        sim.set_force_field(Some(Array2D::from_array(16, field)));
+
+        let cfg = config(&["--move-speed", "0", "--force-x", "0.5"]);
+        sim.step(&cfg, 2., StdRng::seed_from_u64(0));
+        assert_eq!(sim.frame().slime[0].position, Vector2::new(9.5, 10.5));
+    }
+
+    #[test]
+    fn temperature_scales_speed_and_deposits() {
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        sim.set_temperature(Some(Array2D::from_array(16, vec![2.; 16 * 16])));
+
+        sim.step(&config(&[]), 1., StdRng::seed_from_u64(0));
+        assert_eq!(sim.frame().slime[0].position, Vector2::new(10.5, 8.5));
+        assert_eq!(sim.frame().medium()[(10, 8)], 2.);
+    }
+
+    #[test]
+    fn anisotropic_diffusion_spreads_along_one_axis() {
+        let mut sim = empty_sim(0.);
+        sim.front.channels[0][(8, 8)] = 1.;
+
+        let cfg = config(&["--decay", "0", "--diffusion", "0", "--diffusion-x", "0.3"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let medium = sim.frame().medium();
+        assert!((medium[(8, 8)] - 0.8).abs() < 1e-6);
+        assert!((medium[(7, 8)] - 0.1).abs() < 1e-6);
+        assert!((medium[(9, 8)] - 0.1).abs() < 1e-6);
+        assert_eq!(medium[(8, 7)], 0.);
+        assert_eq!(medium[(8, 9)], 0.);
+    }
+
+    #[test]
+    fn inertial_steering_builds_up_and_damps() {
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+
+        // The sensors all read the same, so a full left-handed bias turns left every step
+        let cfg = config(&[
+            "--move-speed",
+            "0",
+            "--deposit-rate",
+            "0",
+            "--chirality",
+            "1",
+            "--turn-speed",
+            "1",
+            "--angular-damping",
+            "0.5",
+        ]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        assert!((sim.frame().slime[0].angular_velocity - 0.5).abs() < 1e-6);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let p = sim.frame().slime[0];
+        assert!((p.angular_velocity - 0.75).abs() < 1e-6);
+        let angle = p.heading.y.atan2(p.heading.x);
+        assert!((angle - 1.25).abs() < 1e-5, "{}", angle);
+    }
+
+    #[test]
+    fn density_caps_limit_every_channel() {
+        let deposit = |soft: bool| {
+            let mut sim = empty_sim(0.);
+            for data in [&mut sim.front, &mut sim.back] {
+                data.channels = vec![Array2D::from_array(16, vec![1.5; 16 * 16]); 2];
+            }
+            place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+            let mut flags = vec![
+                "--move-speed",
+                "0",
+                "--decay",
+                "0",
+                "--diffusion",
+                "0",
+                "--channels",
+                "2",
+                "--deposit-weights",
+                "1,1",
+                "--max-density",
+                "2",
+            ];
+            if soft {
+                flags.push("--soft-cap");
+            }
+            sim.step(&config(&flags), 1., StdRng::seed_from_u64(0));
+            sim.frame()
+                .channels
+                .iter()
+                .map(|ch| ch[(8, 8)])
+                .collect::<Vec<f32>>()
+        };
+
+        assert_eq!(deposit(false), [2., 2.]);
+        // Soft caps shrink deposits by how full the cell already is
+        let soft = deposit(true);
+        assert!(soft.iter().all(|v| (v - 1.75).abs() < 1e-6), "{:?}", soft);
+    }
+
+    #[test]
+    fn chirality_breaks_ties() {
+        let angle_after = |flags: &[&str], handedness: f32| {
+            let mut sim = empty_sim(0.);
+            place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+            sim.front.slime[0].handedness = handedness;
+            let mut all = vec![
+                "--move-speed",
+                "0",
+                "--deposit-rate",
+                "0",
+                "--turn-speed",
+                "1",
+            ];
+            all.extend(flags);
+            sim.step(&config(&all), 1., StdRng::seed_from_u64(0));
+            let heading = sim.frame().slime[0].heading;
+            heading.y.atan2(heading.x)
+        };
+
+        assert!((angle_after(&["--chirality", "1"], 0.) - 1.).abs() < 1e-6);
+        assert!((angle_after(&["--chirality=-1"], 0.) + 1.).abs() < 1e-6);
+        assert_eq!(angle_after(&[], 1.), 0.);
+        // Each particle's own bias comes in through the spread
+        assert!((angle_after(&["--chirality-spread", "1"], -1.) + 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn walls_block_sensors() {
+        // A wall along x = 5
+        let walls: Vec<bool> = (0..16 * 16).map(|i| i % 16 == 5).collect();
+        let walls = Array2D::from_array(16, walls);
+        let v = Vector2::new;
+        assert!(occluded(&walls, v(2.5, 2.5), v(8.5, 2.5)));
+        assert!(occluded(&walls, v(2.5, 2.5), v(5.5, 9.5)));
+        assert!(!occluded(&walls, v(2.5, 2.5), v(4.9, 9.5)));
+        assert!(!occluded(&walls, v(6.5, 2.5), v(12.5, 14.5)));
+
+        // The medium grows along y, so a particle heading along x turns left, unless it can't
+        // see past the wall ahead of it
+        let turn = |obstacles: Option<Array2D<bool>>| {
+            let mut sim = empty_sim(0.);
+            for y in 0..16 {
+                for x in 0..16 {
+                    sim.front.channels[0][(x, y)] = y as f32;
+                }
+            }
+            place(&mut sim, &[([3.5, 8.5], [1., 0.])]);
+            sim.set_obstacles(obstacles);
+            let cfg = config(&["--move-speed", "0", "--deposit-rate", "0"]);
+            sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+            sim.frame().slime[0].heading.y
+        };
+        assert!(turn(None) > 0.);
+        assert_eq!(turn(Some(walls)), 0.);
+    }
+
+    #[test]
+    fn jones_motor_stage_blocks_occupied_cells() {
+        let mut sim = empty_sim(0.);
+        // The first particle runs into the second, which only moves out of the way after it
+        place(&mut sim, &[([8.5, 8.5], [1., 0.]), ([9.5, 8.5], [0., 1.])]);
+
+        let cfg = config(&["--jones-motor", "--decay", "0", "--diffusion", "0"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let slime = &sim.frame().slime;
+        assert_eq!(slime[0].position, Vector2::new(8.5, 8.5));
+        assert_eq!(slime[1].position, Vector2::new(9.5, 9.5));
+        let medium = sim.frame().medium();
+        assert_eq!(medium[(8, 8)], 0., "Blocked particles don't deposit");
+        assert_eq!(medium[(9, 9)], 1.);
+    }
+
+    #[test]
+    fn channels_are_followed_and_deposited_by_weight() {
+        // Channel 0 falls along y and channel 1 rises
+        let heading_y = |sense_weights: &str| {
+            let mut sim = empty_sim(0.);
+            for data in [&mut sim.front, &mut sim.back] {
+                data.channels.resize_with(2, || Array2D::new(16, 16));
+            }
+            for y in 0..16 {
+                for x in 0..16 {
+                    sim.front.channels[0][(x, y)] = -(y as f32);
+                    sim.front.channels[1][(x, y)] = y as f32;
+                }
+            }
+            place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+            let weights = format!("--sense-weights={}", sense_weights);
+            let cfg = config(&["--channels", "2", "--move-speed", "0", weights.as_str()]);
+            sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+            sim.frame().slime[0].heading.y
+        };
+        assert!(heading_y("0,1") > 0.);
+        assert!(heading_y("1,0") < 0.);
+
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        let cfg = config(&[
+            "--channels",
+            "2",
+            "--deposit-weights",
+            "1,0.5",
+            "--move-speed",
+            "0",
+            "--decay",
+            "0",
+            "--diffusion",
+            "0",
+        ]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let deposits: Vec<f32> = sim.frame().channels.iter().map(|ch| ch[(8, 8)]).collect();
+        assert_eq!(deposits, [1., 0.5]);
+    }
+
+    #[test]
+    fn agents_switch_states() {
+        let step = |sim: &mut SlimeSim, flags: &[&str]| {
+            let mut all = vec!["--move-speed", "0", "--deposit-rate", "0"];
+            all.extend(flags);
+            sim.step(&config(&all), 1., StdRng::seed_from_u64(0));
+            sim.frame().slime[0]
+        };
+
+        // Dense trails hold particles
+        let mut sim = empty_sim(1.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        let p = step(&mut sim, &["--exploit-threshold", "0.5"]);
+        assert_eq!(p.state, AgentState::Exploiting);
+        let p = step(&mut sim, &["--exploit-threshold", "5"]);
+        assert_eq!(p.state, AgentState::Exploring);
+
+        // Particles away for too long head home, until they're within sensor range of it
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        sim.front.slime[0].origin = Vector2::new(2.5, 8.5);
+        let flags = ["--homing-after", "2"];
+        for trip_age in 1..=2 {
+            let p = step(&mut sim, &flags);
+            assert_eq!((p.state, p.trip_age), (AgentState::Exploring, trip_age));
+        }
+        assert_eq!(step(&mut sim, &flags).state, AgentState::Homing);
+        sim.front.slime[0].position = Vector2::new(4.5, 8.5);
+        let p = step(&mut sim, &flags);
+        assert_eq!((p.state, p.trip_age), (AgentState::Exploring, 0));
+
+        // Home lies the short way round, across the edge of the grid
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([13.5, 8.5], [0., 1.])]);
+        sim.front.slime[0].origin = Vector2::new(2.5, 8.5);
+        sim.front.slime[0].state = AgentState::Homing;
+        let p = step(&mut sim, &[]);
+        assert_eq!(p.state, AgentState::Homing);
+        assert!(p.heading.x > 0., "{:?}", p.heading);
+    }
+
+    #[test]
+    fn food_is_eaten_and_grows_back() {
+        let mut sim = empty_sim(0.);
+        sim.set_food(Some(Array2D::from_array(16, vec![1.; 16 * 16])));
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+
+        let cfg = config(&[
+            "--move-speed",
+            "0",
+            "--food-consumption",
+            "0.5",
+            "--food-regrowth",
+            "1",
+        ]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let food = sim.food().unwrap();
+        assert_eq!(food[(8, 8)], 0.5);
+        assert_eq!(food[(3, 3)], 1.);
+
+        // Logistic regrowth towards the capacity, once the particle is gone
+        sim.set_particles(vec![]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        assert!((sim.food().unwrap()[(8, 8)] - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dense_trails_split_particles_up_to_the_cap() {
+        let split = |threshold: &str, max: &str| {
+            let mut sim = empty_sim(1.);
+            let particles = [2.5, 6.5, 10.5, 14.5].map(|x| ([x, 2.5], [1., 0.]));
+            place(&mut sim, &particles);
+            let cfg = config(&[
+                "--split-threshold",
+                threshold,
+                "--split-probability",
+                "1",
+                "--max-particles",
+                max,
+            ]);
+            sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+            sim.frame().slime.clone()
+        };
+
+        let slime = split("0.5", "100");
+        assert_eq!(slime.len(), 8);
+        let mut ids: Vec<u32> = slime.iter().map(|p| p.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+        assert_eq!(split("0.5", "6").len(), 6);
+        // Nothing splits on thin trails
+        assert_eq!(split("2", "100").len(), 4);
+    }
+
+    #[test]
+    fn population_is_steered_to_the_target() {
+        let population_after = |target: &str| {
+            let mut sim = SlimeSim::new(16, 16, 10, StdRng::seed_from_u64(0));
+            let cfg = config(&["--target-population", target, "--population-gain", "1"]);
+            sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+            sim.frame().slime.len()
+        };
+        assert_eq!(population_after("20"), 15);
+        assert_eq!(population_after("10"), 10);
+        assert!(population_after("5") < 10);
+    }
+
+    #[test]
+    fn perturbations_wipe_inject_and_scramble() {
+        let cfg = |flags: &[&str]| {
+            let mut all = vec![
+                "--perturb-every",
+                "1",
+                "--decay",
+                "0",
+                "--diffusion",
+                "0",
+                "--move-speed",
+                "0",
+                "--deposit-rate",
+                "0",
+            ];
+            all.extend(flags);
+            config(&all)
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // Perturbations start with the second step
+        let mut sim = empty_sim(1.);
+        let wipe = cfg(&["--perturb-wipe-radius", "100"]);
+        sim.step(&wipe, 1., &mut rng);
+        assert!(sim.frame().medium().data().iter().all(|&v| v == 1.));
+        sim.step(&wipe, 1., &mut rng);
+        assert!(sim.frame().medium().data().iter().all(|&v| v == 0.));
+
+        let mut sim = empty_sim(0.);
+        let blob = cfg(&["--perturb-blob", "1", "--perturb-blob-radius", "4"]);
+        sim.step(&blob, 1., &mut rng);
+        sim.step(&blob, 1., &mut rng);
+        let data = sim.frame().medium().data();
+        let covered = data.iter().filter(|&&v| v > 0.).count();
+        assert!((1..=81).contains(&covered), "{}", covered);
+        assert!(data.iter().all(|&v| v <= 1.));
+
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.]); 20]);
+        let scramble = cfg(&["--perturb-scramble", "1"]);
+        sim.step(&scramble, 1., &mut rng);
+        let straight = Vector2::new(1., 0.);
+        assert!(sim.frame().slime.iter().all(|p| p.heading == straight));
+        sim.step(&scramble, 1., &mut rng);
+        assert!(sim.frame().slime.iter().all(|p| p.heading != straight));
+    }
+
+    #[test]
+    fn symmetrized_media_repeat_around_the_center() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let data: Vec<f32> = (0..16 * 16).map(|_| rng.gen_range(0.0..1.0)).collect();
+
+        let mut arr = Array2D::from_array(16, data.clone());
+        symmetrize(&mut arr, 4, false);
+        for y in 0..16 {
+            for x in 0..16 {
+                assert!((arr[(x, y)] - arr[(15 - y, x)]).abs() < 1e-5);
+            }
+        }
+
+        let mut arr = Array2D::from_array(16, data);
+        symmetrize(&mut arr, 4, true);
+        for y in 0..16 {
+            for x in 0..16 {
+                assert!((arr[(x, y)] - arr[(x, 15 - y)]).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn sensors_warp_smoothly_over_time() {
+        let flags = [
+            "--warp-strength",
+            "2",
+            "--warp-scale",
+            "0.1",
+            "--warp-speed",
+            "0.5",
+        ];
+        let agent = config(&flags).agent;
+        let p = Vector2::new(5.3, 7.1);
+        let warped = warp_sensor(p, &agent, 1.);
+        assert_ne!(warped, p);
+        assert!((warped - p).norm() < 4.);
+
+        // Nearby sensors are pushed alike, and the field moves on over time
+        let nudge = Vector2::new(0.1, 0.);
+        let near = warp_sensor(p + nudge, &agent, 1.);
+        assert!(((near - p - nudge) - (warped - p)).norm() < 0.2);
+        assert_ne!(warp_sensor(p, &agent, 50.), warped);
+
+        assert_eq!(warp_sensor(p, &config(&[]).agent, 1.), p);
+    }
+
+    #[test]
+    fn groups_follow_spawn_sectors() {
+        let center = Vector2::new(8., 8.);
+        let v = Vector2::new;
+        // Counting anticlockwise from the -x axis
+        let offsets = [v(-8., -1.), v(1., -8.), v(8., 1.), v(-1., 8.)];
+        for (group, offset) in offsets.iter().enumerate() {
+            assert_eq!(spawn_group(center + offset, center, 4), group as u32);
+        }
+
+        // Changing the number of groups retags everyone, and children take their parent's
+        let mut sim = empty_sim(1.);
+        place(&mut sim, &[([0.5, 7.5], [1., 0.]), ([15.5, 8.5], [1., 0.])]);
+        let cfg = config(&[
+            "--groups",
+            "2",
+            "--split-threshold",
+            "0.5",
+            "--split-probability",
+            "1",
+        ]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let groups: Vec<u32> = sim.frame().slime.iter().map(|p| p.group).collect();
+        assert_eq!(groups, [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn homesick_particles_head_home_and_respawn() {
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        sim.front.slime[0].origin = Vector2::new(8.5, 2.5);
+        let cfg = config(&[
+            "--move-speed",
+            "0",
+            "--homing-probability",
+            "1",
+            "--respawn-at-origin",
+        ]);
+
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let p = sim.frame().slime[0];
+        assert_eq!(p.state, AgentState::Homing);
+        assert!(
+            p.heading.y < 0.,
+            "Should turn towards home, {:?}",
+            p.heading
+        );
+
+        sim.front.slime[0].position = Vector2::new(8.5, 4.5);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let respawned = sim.frame().slime[0];
+        assert_eq!(respawned.position, Vector2::new(8.5, 2.5));
+        assert_ne!(respawned.id, p.id);
+        assert_eq!((respawned.state, respawned.age), (AgentState::Exploring, 0));
+    }
+
+    #[test]
+    fn home_range_pulls_back_or_respawns() {
+        let step = |range: &str, respawn: bool| {
+            let mut sim = empty_sim(0.);
+            place(&mut sim, &[([8.5, 8.5], [0., 1.])]);
+            sim.front.slime[0].origin = Vector2::new(2.5, 8.5);
+            let mut flags = vec!["--move-speed", "0", "--home-range", range];
+            if respawn {
+                flags.push("--home-range-respawn");
+            }
+            sim.step(&config(&flags), 1., StdRng::seed_from_u64(0));
+            sim.frame().slime[0]
+        };
+
+        // Twice the range out, the pull back overrides the medium entirely
+        assert!(step("3", false).heading.x < 0.);
+        assert_eq!(step("3", true).position, Vector2::new(2.5, 8.5));
+        // Within range, nothing happens
+        assert_eq!(step("10", false).heading, Vector2::new(0., 1.));
+        assert_eq!(step("10", true).position, Vector2::new(8.5, 8.5));
+    }
+
+    #[test]
+    fn costly_terrain_slows_particles_and_speeds_turns() {
+        let step = |flags: &[&str]| {
+            let mut sim = empty_sim(0.);
+            place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+            sim.set_cost(Some(Array2D::from_array(16, vec![1.; 16 * 16])));
+            let mut all = vec!["--turn-speed", "1", "--deposit-rate", "0"];
+            all.extend(flags);
+            sim.step(&config(&all), 1., StdRng::seed_from_u64(0));
+            sim.frame().slime[0]
+        };
+
+        assert_eq!(step(&[]).position, Vector2::new(9., 8.5));
+        assert_eq!(
+            step(&["--cost-speed", "0"]).position,
+            Vector2::new(9.5, 8.5)
+        );
+        let p = step(&["--chirality", "1", "--cost-turn", "1"]);
+        assert!((p.heading.y.atan2(p.heading.x) - 2.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn populations_grow_on_schedule() {
+        let sizes = |every: &str| {
+            let mut sim = SlimeSim::new(16, 16, 10, StdRng::seed_from_u64(0));
+            let cfg = config(&[
+                "--grow-to",
+                "20",
+                "--grow-over",
+                "10",
+                "--grow-every",
+                every,
+            ]);
+            let mut rng = StdRng::seed_from_u64(0);
+            (0..12)
+                .map(|_| {
+                    sim.step(&cfg, 1., &mut rng);
+                    sim.frame().slime.len()
+                })
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(sizes("1"), [11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 20, 20]);
+        assert_eq!(sizes("5"), [15, 15, 15, 15, 15, 20, 20, 20, 20, 20, 20, 20]);
+    }
+
+    #[test]
+    fn resizing_scales_particles_and_resamples_the_medium() {
+        let mut sim = empty_sim(0.5);
+        place(&mut sim, &[([4., 6.], [1., 0.])]);
+        sim.set_temperature(Some(Array2D::from_array(16, vec![2.; 16 * 16])));
+
+        sim.resize(32, 8);
+        let p = sim.frame().slime[0];
+        assert_eq!(p.position, Vector2::new(8., 3.));
+        assert_eq!(p.origin, Vector2::new(8., 3.));
+        let medium = sim.frame().medium();
+        assert_eq!((medium.width(), medium.height()), (32, 8));
+        assert!(medium.data().iter().all(|&v| (v - 0.5).abs() < 1e-6));
+
+        // Fields are resampled along with the medium
+        let cfg = config(&["--decay", "0", "--diffusion", "0"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let moved = sim.frame().slime[0].position;
+        assert!((moved - Vector2::new(10., 3.)).norm() < 1e-5, "{:?}", moved);
+        assert!((sim.frame().medium()[(10, 3)] - 2.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sensor_noise_breaks_up_ties() {
+        let turned = |density: f32, flags: &[&str]| {
+            let mut sim = empty_sim(density);
+            place(&mut sim, &[([8.5, 8.5], [1., 0.]); 50]);
+            let mut all = vec!["--move-speed", "0", "--deposit-rate", "0"];
+            all.extend(flags);
+            sim.step(&config(&all), 1., StdRng::seed_from_u64(0));
+            sim.frame()
+                .slime
+                .iter()
+                .filter(|p| p.heading.y != 0.)
+                .count()
+        };
+
+        assert_eq!(turned(1., &[]), 0);
+        assert!(turned(1., &["--sensor-noise", "0.5"]) > 0);
+        // Relative noise scales with the reading, so there's none on an empty medium
+        assert_eq!(turned(0., &["--sensor-noise-relative", "0.5"]), 0);
+        assert!(turned(1., &["--sensor-noise-relative", "0.5"]) > 0);
+    }
+
+    #[test]
+    fn zones_override_the_config_where_they_cover() {
+        let cfg = SlimeConfig::from_toml(
+            r#"
+            [medium]
+            decay = 0.0
+            diffusion = 0.0
+
+            [[zones]]
+            rect = [0.0, 0.0, 8.0, 16.0]
+            params = { decay = 0.5 }
+
+            [[zones]]
+            index = 3
+            params = { move_speed = 2.0 }
+            "#,
+        )
+        .unwrap();
+        let mut sim = empty_sim(1.);
+        place(&mut sim, &[([12.5, 8.5], [1., 0.]), ([2.5, 8.5], [1., 0.])]);
+        sim.set_zone_map(Some(Array2D::from_array(16, vec![3; 16 * 16])));
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+
+        // The rectangle comes first, so the zone map only counts on the right half
+        let medium = sim.frame().medium();
+        assert_eq!(medium[(3, 3)], 0.5);
+        assert_eq!(medium[(12, 3)], 1.);
+        let slime = &sim.frame().slime;
+        assert_eq!(slime[0].position, Vector2::new(14.5, 8.5));
+        assert_eq!(slime[1].position, Vector2::new(3.5, 8.5));
+
+        let unknown = "[[zones]]\nindex = 1\nparams = { decay_rate = 0.5 }\n";
+        let message = format!("{:#}", SlimeConfig::from_toml(unknown).unwrap_err());
+        assert!(message.contains("In zone 1"), "{}", message);
+    }
+
+    #[test]
+    fn freshness_fades_after_the_last_deposit() {
+        let mut sim = empty_sim(0.);
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        let cfg = config(&["--move-speed", "0"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        assert_eq!(sim.last_deposit()[(8, 8)], 1.);
+        assert_eq!(sim.freshness(2.)[(8, 8)], 1.);
+        assert_eq!(sim.freshness(2.)[(3, 3)], 0.);
+
+        sim.set_particles(vec![]);
+        for _ in 0..2 {
+            sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        }
+        assert_eq!(sim.last_deposit()[(8, 8)], 1.);
+        assert!((sim.freshness(2.)[(8, 8)] - (-1f32).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn directional_trails_line_particles_up() {
+        let cfg = config(&["--move-speed", "0", "--heading-alignment", "10"]);
+        let mut sim = empty_sim(0.);
+        // One particle lays a trail heading along y, and the next follows it from across
+        place(&mut sim, &[([8.5, 8.5], [0., 1.])]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        place(&mut sim, &[([8.5, 8.5], [1., 0.])]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let heading = sim.frame().slime[0].heading;
+        assert!(heading.y > 0.99, "{:?}", heading);
+
+        sim.step(&config(&[]), 1., StdRng::seed_from_u64(0));
+        assert!(sim.direction.is_none());
+    }
+
+    #[test]
+    fn collisions_scatter_and_burst() {
+        let mut sim = empty_sim(0.);
+        let straight = [1., 0.];
+        place(
+            &mut sim,
+            &[
+                ([8.5, 8.5], straight),
+                ([9., 8.5], straight),
+                ([2.5, 2.5], straight),
+            ],
+        );
+        let cfg = config(&[
+            "--move-speed",
+            "0",
+            "--collision-radius",
+            "1",
+            "--decay",
+            "0",
+            "--diffusion",
+            "0",
+        ]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+
+        let slime = &sim.frame().slime;
+        let straight = Vector2::from(straight);
+        assert!(slime[..2].iter().all(|p| p.heading != straight));
+        assert_eq!(slime[2].heading, straight);
+        let medium = sim.frame().medium();
+        assert_eq!(medium[(8, 8)], 5.);
+        assert_eq!(medium[(9, 8)], 5.);
+        assert_eq!(medium[(2, 2)], 1.);
+    }
+
+    #[test]
+    fn particles_follow_walls() {
+        // A wall along x = 10
+        let walls: Vec<bool> = (0..16 * 16).map(|i| i % 16 == 10).collect();
+        let walls = Array2D::from_array(16, walls);
+        let v = Vector2::new;
+        let tangent = wall_tangent(&walls, v(8.5, 8.5), 3., v(1., 0.2)).unwrap();
+        assert!((tangent - v(0., 1.)).norm() < 1e-6, "{:?}", tangent);
+        let tangent = wall_tangent(&walls, v(8.5, 8.5), 3., v(1., -0.2)).unwrap();
+        assert!((tangent - v(0., -1.)).norm() < 1e-6, "{:?}", tangent);
+        assert!(wall_tangent(&walls, v(2.5, 8.5), 3., v(1., 0.)).is_none());
+
+        let mut sim = empty_sim(0.);
+        let heading = v(1., 0.2).normalize();
+        place(&mut sim, &[([8.5, 8.5], [heading.x, heading.y])]);
+        sim.set_obstacles(Some(walls));
+        let cfg = config(&["--move-speed", "0", "--wall-following", "10"]);
+        sim.step(&cfg, 1., StdRng::seed_from_u64(0));
+        let heading = sim.frame().slime[0].heading;
+        assert!(heading.y > 0.99, "{:?}", heading);
+    }
+
+    #[test]
+    fn fixed_point_rounds_particles() {
+        assert_eq!(quantize(0.3, 4), 0.3125);
+        assert_eq!(quantize(-0.3, 4), -0.3125);
+        assert_eq!(quantize(0.75, 2), 0.75);
+
+        let mut sim = SlimeSim::new(16, 16, 50, StdRng::seed_from_u64(0));
+        sim.step(
+            &config(&["--fixed-point", "8"]),
+            1.,
+            StdRng::seed_from_u64(0),
+        );
+        let on_grid = |v: f32| (v * 256.).fract() == 0.;
+        for p in &sim.frame().slime {
+            assert!(
+                on_grid(p.position.x) && on_grid(p.position.y),
+                "{:?}",
+                p.position
+            );
+            assert!(
+                on_grid(p.heading.x) && on_grid(p.heading.y),
+                "{:?}",
+                p.heading
+            );
+            assert!(p.position.x < 16. && p.position.y < 16.);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Uniform grid over particle positions, for finding the neighbors of a point without scanning
+//! every particle. Like the sim, the grid wraps around its edges
+use crate::sim::wrap_delta;
+use nalgebra::Vector2;
+
+pub struct SpatialHash {
+    /// Size of the wrapped domain
+    size: Vector2<f32>,
+    /// Width and height of each cell
+    cell_size: Vector2<f32>,
+    cols: usize,
+    rows: usize,
+    /// Offset into `entries` of the first particle of each cell, plus one past the last cell
+    starts: Vec<usize>,
+    /// Indices of the particles, sorted by cell
+    entries: Vec<usize>,
+    /// Positions of the indexed particles, by index
+    positions: Vec<Vector2<f32>>,
+}
+
+impl SpatialHash {
+    /// An empty index over a `width` by `height` torus, with cells of about `cell_size` square
+    pub fn new(width: usize, height: usize, cell_size: f32) -> Self {
+        let count = |len: usize| ((len as f32 / cell_size).floor() as usize).max(1);
+        let (cols, rows) = (count(width), count(height));
+        Self {
+            size: Vector2::new(width as f32, height as f32),
+            // Stretched slightly so that the cells exactly cover the domain
+            cell_size: Vector2::new(width as f32 / cols as f32, height as f32 / rows as f32),
+            cols,
+            rows,
+            starts: vec![0; cols * rows + 1],
+            entries: vec![],
+            positions: vec![],
+        }
+    }
+
+    /// Replace the indexed particles. Their indices are their order in `positions`
+    pub fn rebuild(&mut self, positions: impl IntoIterator<Item = Vector2<f32>>) {
+        self.positions.clear();
+        self.positions.extend(positions);
+
+        // Counting sort by cell. Non-finite positions aren't in any cell
+        let cells: Vec<Option<usize>> = self.positions.iter().map(|&p| self.cell(p)).collect();
+        self.starts.iter_mut().for_each(|s| *s = 0);
+        for cell in cells.iter().flatten() {
+            self.starts[cell + 1] += 1;
+        }
+        let mut total = 0;
+        for start in &mut self.starts {
+            total += *start;
+            *start = total;
+        }
+
+        let mut next = self.starts.clone();
+        self.entries.clear();
+        self.entries.resize(total, 0);
+        for (idx, cell) in cells.iter().enumerate() {
+            if let Some(cell) = *cell {
+                self.entries[next[cell]] = idx;
+                next[cell] += 1;
+            }
+        }
+    }
+
+    /// Indices of the particles within `radius` of `pos`, measured the short way around the
+    /// edges
+    pub fn within(&self, pos: Vector2<f32>, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let reach = |cell_size: f32| (radius / cell_size).ceil().max(0.) as usize;
+        let center = self.cell_coords(pos);
+        let (cx, cy) = center.unwrap_or_default();
+
+        let xs = cell_span(cx, reach(self.cell_size.x), self.cols);
+        let ys = cell_span(cy, reach(self.cell_size.y), self.rows);
+        ys.flat_map(move |y| xs.clone().map(move |x| y * self.cols + x))
+            .filter(move |_| center.is_some())
+            .flat_map(move |cell| &self.entries[self.starts[cell]..self.starts[cell + 1]])
+            .copied()
+            .filter(move |&idx| {
+                let d = self.positions[idx] - pos;
+                let d = Vector2::new(wrap_delta(d.x, self.size.x), wrap_delta(d.y, self.size.y));
+                d.norm_squared() <= radius * radius
+            })
+    }
+
+    fn cell_coords(&self, p: Vector2<f32>) -> Option<(usize, usize)> {
+        let wrap = |x: f32, cell_size: f32, n: usize| {
+            x.is_finite()
+                .then(|| ((x / cell_size).floor() as isize).rem_euclid(n as isize) as usize)
+        };
+        Some((
+            wrap(p.x, self.cell_size.x, self.cols)?,
+            wrap(p.y, self.cell_size.y, self.rows)?,
+        ))
+    }
+
+    fn cell(&self, p: Vector2<f32>) -> Option<usize> {
+        self.cell_coords(p).map(|(x, y)| y * self.cols + x)
+    }
+}
+
+/// Cells from `c - reach` to `c + reach` on a loop of `n` cells, each visited once
+fn cell_span(c: usize, reach: usize, n: usize) -> impl Iterator<Item = usize> + Clone {
+    let (start, len) = if 2 * reach + 1 >= n {
+        (0, n)
+    } else {
+        (c + n - reach, 2 * reach + 1)
+    };
+    (0..len).map(move |i| (start + i) % n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Indices of `positions` within `radius` of `pos`, by checking every one
+    fn brute_force(
+        positions: &[Vector2<f32>],
+        size: Vector2<f32>,
+        pos: Vector2<f32>,
+        radius: f32,
+    ) -> Vec<usize> {
+        (0..positions.len())
+            .filter(|&idx| {
+                let d = positions[idx] - pos;
+                let d = Vector2::new(wrap_delta(d.x, size.x), wrap_delta(d.y, size.y));
+                d.norm_squared() <= radius * radius
+            })
+            .collect()
+    }
+
+    #[test]
+    fn within_matches_brute_force() {
+        let (width, height) = (50, 30);
+        let size = Vector2::new(width as f32, height as f32);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut positions: Vec<Vector2<f32>> = (0..500)
+            .map(|_| Vector2::new(rng.gen_range(0.0..size.x), rng.gen_range(0.0..size.y)))
+            .collect();
+        // On and either side of the seams
+        let below = |len: f32| len - len * f32::EPSILON;
+        for x in [0., below(size.x), size.x / 2.] {
+            for y in [0., below(size.y), size.y / 2.] {
+                positions.push(Vector2::new(x, y));
+            }
+        }
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            positions.push(Vector2::new(bad, 1.));
+            positions.push(Vector2::new(1., bad));
+        }
+
+        let mut index = SpatialHash::new(width, height, 8.);
+        index.rebuild(positions.iter().copied());
+
+        let mut queries: Vec<Vector2<f32>> = positions[500..].to_vec();
+        queries.extend(
+            (0..100).map(|_| Vector2::new(rng.gen_range(0.0..size.x), rng.gen_range(0.0..size.y))),
+        );
+        // Radii of less than a cell, several cells, and more than the whole grid
+        for radius in [0., 0.5, 3., 8., 13., 17., 40., 100.] {
+            for &pos in &queries {
+                let mut found: Vec<usize> = index.within(pos, radius).collect();
+                found.sort_unstable();
+                let expected = brute_force(&positions, size, pos, radius);
+                assert_eq!(found, expected, "within {} of {:?}", radius, pos);
+            }
+        }
+
+        // Everything finite is within reach of a big enough radius
+        let all = index.within(Vector2::new(1., 1.), 100.).count();
+        assert_eq!(all, positions.len() - 6);
+    }
+}
@@ -0,0 +1,243 @@
+//! Short seeded runs checked against the checked-in renders and recorded particles in
+//! `tests/golden`, so that changes to the step pipeline can't silently alter its output.
+//!
+//! Run with `SLIME_BLESS=1` to write the golden files, or to rewrite them after an intended
+//! change. A missing golden file fails its test.
+use rand::{rngs::StdRng, SeedableRng};
+use slime::{
+    image::load_grayscale,
+    record::{record_frame, RecordFile},
+    sim::*,
+};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 64;
+const N_PARTICLES: usize = 300;
+const STEPS: usize = 60;
+const DT: f32 = 0.5;
+
+/// Largest per-pixel difference (in 0..1) which still counts as a match
+const PIXEL_TOLERANCE: f32 = 2. / 255.;
+
+/// Fraction of pixels allowed to differ by more than the tolerance
+const MAX_BAD_PIXELS: f32 = 0.005;
+
+/// Recorded frames whose particles are compared, every this many
+const CHECKED_EVERY: usize = 15;
+
+/// Largest distance (in cells) from the golden position which still counts as a match. Math
+/// functions round differently between platforms, which moves particles slightly
+const POSITION_TOLERANCE: f32 = 1e-2;
+
+/// Fraction of particles allowed to be further than the tolerance from their golden position,
+/// for those sent another way by a near tie between their sensors
+const MAX_BAD_PARTICLES: f32 = 0.02;
+
+#[test]
+fn default_config() {
+    check("default", &[]);
+}
+
+#[test]
+fn mandala() {
+    check("mandala", &["--symmetry", "6", "--mirror"]);
+}
+
+#[test]
+fn forcing_and_channels() {
+    check(
+        "forcing",
+        &[
+            "--forcing",
+            "vortex-pair",
+            "--channels",
+            "2",
+            "--deposit-weights",
+            "1,0.5",
+        ],
+    );
+}
+
+#[test]
+fn homing_and_splitting() {
+    check(
+        "homing",
+        &[
+            "--homing-after",
+            "20",
+            "--split-threshold",
+            "0.5",
+            "--max-particles",
+            "400",
+        ],
+    );
+}
+
+/// Run the sim with the given flags, and compare the final medium and the recorded particles
+/// with the golden files of `name`
+fn check(name: &str, flags: &[&str]) {
+    let cfg = SlimeConfig::from_iter(std::iter::once("slime").chain(flags.iter().copied()));
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut sim = SlimeSim::new(WIDTH, HEIGHT, N_PARTICLES, &mut rng);
+    let mut record = RecordFile::new(WIDTH, HEIGHT);
+    for _ in 0..STEPS {
+        record_frame(&mut record, &sim, false);
+        sim.step(&cfg, DT, &mut rng);
+    }
+
+    let pixels: Vec<u8> = sim
+        .frame()
+        .medium()
+        .data()
+        .iter()
+        .map(|v| (v.clamp(0., 1.) * 255.).round() as u8)
+        .collect();
+    let particles = recorded_particles(&record);
+
+    let image_path = golden_path(name, "png");
+    let particles_path = golden_path(name, "particles");
+    let bless = std::env::var_os("SLIME_BLESS").is_some();
+    if bless {
+        std::fs::create_dir_all(image_path.parent().unwrap()).unwrap();
+        write_gray_png(&image_path, &pixels);
+        let text: String = particles
+            .iter()
+            .map(|p| format!("{} {} {} {}\n", p.frame, p.id, p.x, p.y))
+            .collect();
+        std::fs::write(&particles_path, text).unwrap();
+        eprintln!("Wrote golden files for {}", name);
+        return;
+    }
+    assert!(
+        image_path.exists() && particles_path.exists(),
+        "Missing golden files for {}; run with SLIME_BLESS=1 to write them",
+        name
+    );
+
+    let golden = load_grayscale(&image_path).unwrap();
+    assert_eq!(
+        (golden.width(), golden.height()),
+        (WIDTH, HEIGHT),
+        "Golden render of {} is the wrong size",
+        name
+    );
+    let bad = golden
+        .data()
+        .iter()
+        .zip(&pixels)
+        .filter(|&(&g, &p)| (g - p as f32 / 255.).abs() > PIXEL_TOLERANCE)
+        .count();
+    let bad_fraction = bad as f32 / pixels.len() as f32;
+    assert!(
+        bad_fraction <= MAX_BAD_PIXELS,
+        "Render of {} differs from the golden one in {} pixels ({:.2}%); rerun with \
+         SLIME_BLESS=1 if this is intended",
+        name,
+        bad,
+        bad_fraction * 100.
+    );
+
+    let golden = load_particles(&particles_path);
+    assert_eq!(
+        golden.len(),
+        particles.len(),
+        "Record of {} has a different number of particles from the golden one; rerun with \
+         SLIME_BLESS=1 if this is intended",
+        name
+    );
+    for (g, p) in golden.iter().zip(&particles) {
+        assert_eq!(
+            (g.frame, g.id),
+            (p.frame, p.id),
+            "Record of {} has different particles from the golden one; rerun with \
+             SLIME_BLESS=1 if this is intended",
+            name
+        );
+    }
+    let bad = golden
+        .iter()
+        .zip(&particles)
+        .filter(|(g, p)| {
+            let dx = wrap_delta(g.x - p.x, WIDTH as f32);
+            let dy = wrap_delta(g.y - p.y, HEIGHT as f32);
+            // Also catches particles which went NaN
+            !(dx.hypot(dy) <= POSITION_TOLERANCE)
+        })
+        .count();
+    let bad_fraction = bad as f32 / particles.len().max(1) as f32;
+    assert!(
+        bad_fraction <= MAX_BAD_PARTICLES,
+        "{} recorded particles of {} ({:.2}%) are away from their golden positions; rerun \
+         with SLIME_BLESS=1 if this is intended",
+        bad,
+        name,
+        bad_fraction * 100.
+    );
+}
+
+/// A particle of one of the checked frames of a record
+struct Particle {
+    frame: usize,
+    id: u32,
+    x: f32,
+    y: f32,
+}
+
+fn recorded_particles(record: &RecordFile) -> Vec<Particle> {
+    let frames = record.frames.iter().enumerate().step_by(CHECKED_EVERY);
+    frames
+        .flat_map(|(frame, f)| {
+            f.slime.iter().map(move |p| Particle {
+                frame,
+                id: p.id,
+                x: p.position.x,
+                y: p.position.y,
+            })
+        })
+        .collect()
+}
+
+/// Particles written by blessing, one per line as frame, id, x and y
+fn load_particles(path: &Path) -> Vec<Particle> {
+    let text = std::fs::read_to_string(path).unwrap();
+    text.lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [frame, id, x, y] => Particle {
+                    frame: frame.parse().unwrap(),
+                    id: id.parse().unwrap(),
+                    x: x.parse().unwrap(),
+                    y: y.parse().unwrap(),
+                },
+                _ => panic!("Bad line in {}: {}", path.display(), line),
+            }
+        })
+        .collect()
+}
+
+fn golden_path(name: &str, ext: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.{}", name, ext))
+}
+
+fn write_gray_png(path: &Path, pixels: &[u8]) {
+    let writer = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(writer, WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .unwrap()
+        .write_image_data(pixels)
+        .unwrap();
+}